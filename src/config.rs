@@ -1,10 +1,23 @@
-use crate::llm::LlmConfig;
+use crate::{
+    llm::{LlmApi, LlmConfig},
+    tool::{tool_names, ToolsConfig},
+};
+use schemars::JsonSchema;
 use serde::Deserialize;
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+use toml::{Table, Value};
 
 /// A program configuration error.
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
+    #[error("malformed environment override '{0}'")]
+    EnvOverride(String),
+    #[error("invalid config field '{0}': {1}")]
+    InvalidField(&'static str, &'static str),
     #[error("failed to perform io")]
     SerdeJson(
         #[from]
@@ -20,11 +33,53 @@ pub enum Error {
 }
 
 /// A program configuration.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 pub struct Config {
+    /// Query shorthands by the first query word, called without LLM.
+    #[serde(default)]
+    pub aliases: HashMap<String, Alias>,
+    /// The number of top-ranked tools tried in turn by double pass derive (no ranking if unset).
+    pub candidate_tools: Option<usize>,
+    #[serde(default)]
     pub double_pass_derive: bool,
+    /// Names of tools available for queries (all if absent, none if empty).
+    pub enabled_tools: Option<Vec<String>>,
+    /// Directories filesystem tools must stay within (unrestricted if empty).
+    #[serde(default)]
+    pub filesystem_roots: Vec<PathBuf>,
     pub llm: LlmConfig,
+    /// The maximum number of outputs a single tool call may produce.
+    pub max_outputs: Option<usize>,
+    #[serde(default = "default_num_derive_tries")]
     pub num_derive_tries: u32,
+    /// Whether to retry derivation with a hint if a derived call produces no output.
+    #[serde(default)]
+    pub retry_on_empty: bool,
+    /// The number of tools most similar to a query to offer to LLM (all if unset).
+    pub tool_preselect_k: Option<usize>,
+    /// The maximum execution time of a single tool call.
+    pub tool_timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub tools: ToolsConfig,
+    /// Whether to warn about tool outputs not matching their schemas (slow, for debugging).
+    #[serde(default)]
+    pub validate_tool_outputs: bool,
+}
+
+/// A tool call template for queries starting with an alias name.
+///
+/// String params may contain captures of the query text after the alias name: `{1}`, `{2}`
+/// and so on for whitespace-separated words, `{*}` for the whole text. Literal braces are
+/// escaped as `{{` and `}}`. An alias doesn't match if any capture is missing.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct Alias {
+    #[serde(default)]
+    pub params: serde_json::Map<String, serde_json::Value>,
+    pub tool: String,
+}
+
+fn default_num_derive_tries() -> u32 {
+    3
 }
 
 impl Config {
@@ -33,26 +88,356 @@ impl Config {
 
     /// Searches for the configuration file in common directories and returns its path if found.
     pub fn find_common_path() -> Option<PathBuf> {
-        #[cfg(unix)]
-        let paths = vec![".", "$HOME/.config", "/etc"];
+        Self::common_paths(|name| std::env::var_os(name))
+            .into_iter()
+            .find(|path| path.exists())
+    }
 
-        #[cfg(windows)]
-        let paths = vec![".", "$USERPROFILE", "$SystemDrive"];
+    /// Lists configuration file paths probed in order using a given environment lookup.
+    pub fn common_paths(var: impl Fn(&str) -> Option<OsString>) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
 
-        for path in paths {
-            let filename = PathBuf::from(path).join(Self::COMMON_FILE_BASENAME);
-            let filename = shellexpand::path::env(&filename).unwrap();
-            if filename.exists() {
-                return Some(filename.to_path_buf());
+        #[cfg(unix)]
+        {
+            if let Some(config_home) = var("XDG_CONFIG_HOME").filter(|v| !v.is_empty()) {
+                paths.push(PathBuf::from(config_home).join("korah"));
+            }
+            paths.push(PathBuf::from("."));
+            if let Some(home) = var("HOME") {
+                let home = PathBuf::from(home);
+                paths.push(home.join(".config"));
+                #[cfg(target_os = "macos")]
+                paths.push(home.join("Library/Application Support/korah"));
             }
+            paths.push(PathBuf::from("/etc"));
         }
 
-        None
+        #[cfg(windows)]
+        {
+            paths.push(PathBuf::from("."));
+            paths.extend(var("USERPROFILE").map(PathBuf::from));
+            paths.extend(var("SystemDrive").map(PathBuf::from));
+        }
+
+        paths
+            .into_iter()
+            .map(|p| p.join(Self::COMMON_FILE_BASENAME))
+            .collect()
     }
 
-    /// Reads program configuration from a file.
+    /// A prefix of environment variables overriding configuration values.
+    ///
+    /// Nested keys are separated with double underscores, e.g. `KORAH_LLM__OLLAMA__BASE_URL`
+    /// overrides `base_url` in the `[llm.ollama]` table.
+    pub const ENV_OVERRIDE_PREFIX: &str = "KORAH_";
+
+    /// Reads program configuration from a file applying environment overrides.
     pub fn read(path: &Path) -> Result<Self, Error> {
         let s = std::fs::read_to_string(path)?;
-        toml::from_str(&s).map_err(Into::into)
+        let table: Table = toml::from_str(&s)?;
+        let config = Self::from_table(table, std::env::vars().collect())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Deserializes a configuration from a table applying given environment overrides.
+    ///
+    /// New values can't be coerced to the types of replaced ones, so a failure is blamed on
+    /// an override without which the configuration deserializes.
+    fn from_table(table: Table, vars: Vec<(String, String)>) -> Result<Self, Error> {
+        let mut overridden = table.clone();
+        apply_env_overrides(&mut overridden, vars.clone())?;
+        let err = match overridden.try_into() {
+            Ok(config) => return Ok(config),
+            Err(err) => err,
+        };
+
+        let names = vars
+            .iter()
+            .map(|(name, _)| name)
+            .filter(|name| name.starts_with(Self::ENV_OVERRIDE_PREFIX));
+        for name in names {
+            let mut table = table.clone();
+            let other_vars = vars.iter().filter(|(n, _)| n != name).cloned();
+            apply_env_overrides(&mut table, other_vars)?;
+            if table.try_into::<Self>().is_ok() {
+                return Err(Error::EnvOverride(name.clone()));
+            }
+        }
+        Err(err.into())
+    }
+
+    /// Checks the configuration for logically invalid values.
+    pub fn validate(&self) -> Result<(), Error> {
+        use LlmApi::*;
+        let apis = std::iter::once(&self.llm.api).chain(&self.llm.fallback_apis);
+        for api in apis {
+            let (field, has_section) = match api {
+                Anthropic => ("llm.anthropic", self.llm.anthropic.is_some()),
+                Gemini => ("llm.gemini", self.llm.gemini.is_some()),
+                Mock => ("llm.mock", self.llm.mock.is_some()),
+                Ollama => ("llm.ollama", self.llm.ollama.is_some()),
+                OpenAi => ("llm.open_ai", self.llm.open_ai.is_some()),
+            };
+            if !has_section {
+                return Err(Error::InvalidField(
+                    field,
+                    "missing section for the chosen api",
+                ));
+            }
+        }
+
+        let tool_names = tool_names();
+        if self
+            .aliases
+            .values()
+            .any(|a| !tool_names.contains(&a.tool.as_str()))
+        {
+            return Err(Error::InvalidField("aliases", "unknown tool"));
+        }
+
+        if let Some(enabled_tools) = &self.enabled_tools {
+            if enabled_tools
+                .iter()
+                .any(|t| !tool_names.contains(&t.as_str()))
+            {
+                return Err(Error::InvalidField("enabled_tools", "unknown tool"));
+            }
+        }
+
+        if self.tool_preselect_k.is_some() && self.llm.embedding.is_none() {
+            return Err(Error::InvalidField(
+                "llm.embedding",
+                "missing section for tool_preselect_k",
+            ));
+        }
+
+        if self.num_derive_tries == 0 {
+            return Err(Error::InvalidField(
+                "num_derive_tries",
+                "must be at least 1",
+            ));
+        }
+
+        if !self.llm.query_fmt.contains("{query}") {
+            return Err(Error::InvalidField(
+                "llm.query_fmt",
+                "missing {query} placeholder",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Applies overrides from prefixed variables to existing tables ignoring unknown ones.
+///
+/// The values are coerced to the types of the values they replace. New values are parsed as
+/// TOML literals falling back to plain strings.
+fn apply_env_overrides(
+    table: &mut Table,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<(), Error> {
+    'vars: for (name, value) in vars {
+        let Some(path) = name.strip_prefix(Config::ENV_OVERRIDE_PREFIX) else {
+            continue;
+        };
+        let mut keys: Vec<_> = path.split("__").map(str::to_lowercase).collect();
+        let key = keys.pop().unwrap();
+
+        let mut parent = &mut *table;
+        for k in keys {
+            let Some(Value::Table(t)) = parent.get_mut(&k) else {
+                continue 'vars;
+            };
+            parent = t;
+        }
+
+        let value = match parent.get(&key) {
+            None => parse_toml_literal(&value).unwrap_or(Value::String(value)),
+            Some(Value::Table(_)) => continue,
+            Some(Value::String(_)) => Value::String(value),
+            Some(Value::Integer(_)) => match value.parse() {
+                Ok(v) => Value::Integer(v),
+                Err(_) => return Err(Error::EnvOverride(name)),
+            },
+            Some(Value::Float(_)) => match value.parse() {
+                Ok(v) => Value::Float(v),
+                Err(_) => return Err(Error::EnvOverride(name)),
+            },
+            Some(Value::Boolean(_)) => match value.parse() {
+                Ok(v) => Value::Boolean(v),
+                Err(_) => return Err(Error::EnvOverride(name)),
+            },
+            Some(old) => match parse_toml_literal(&value) {
+                Some(v) if v.same_type(old) => v,
+                _ => return Err(Error::EnvOverride(name)),
+            },
+        };
+        parent.insert(key, value);
+    }
+    Ok(())
+}
+
+fn parse_toml_literal(s: &str) -> Option<Value> {
+    toml::from_str::<Table>(&format!("v = {s}"))
+        .ok()
+        .and_then(|mut t| t.remove("v"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Config {
+        toml::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_validate() {
+        let valid = "double_pass_derive = false\nnum_derive_tries = 3\n\
+            [llm]\napi = \"ollama\"\n\
+            [llm.ollama]\nbase_url = \"http://localhost:11434\"\nmodel = \"qwen2.5\"\n";
+        assert!(parse(valid).validate().is_ok());
+
+        let err = parse(&valid.replace("api = \"ollama\"", "api = \"open_ai\""))
+            .validate()
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidField("llm.open_ai", _)));
+
+        let err = parse(&valid.replace(
+            "api = \"ollama\"",
+            "api = \"ollama\"\nfallback_apis = [\"anthropic\"]",
+        ))
+        .validate()
+        .unwrap_err();
+        assert!(matches!(err, Error::InvalidField("llm.anthropic", _)));
+
+        let err = parse(&valid.replace("num_derive_tries = 3", "num_derive_tries = 0"))
+            .validate()
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidField("num_derive_tries", _)));
+
+        let err = parse(&valid.replace(
+            "api = \"ollama\"",
+            "api = \"ollama\"\nquery_fmt = \"{context}\"",
+        ))
+        .validate()
+        .unwrap_err();
+        assert!(matches!(err, Error::InvalidField("llm.query_fmt", _)));
+
+        let config = parse(&format!(
+            "enabled_tools = [\"find_files\", \"find_file\"]\n{valid}"
+        ));
+        let err = config.validate().unwrap_err();
+        assert!(matches!(err, Error::InvalidField("enabled_tools", _)));
+        let config = parse(&format!("enabled_tools = [\"run_command\"]\n{valid}"));
+        assert!(config.validate().is_ok());
+
+        let config = parse(&format!("{valid}[aliases.du]\ntool = \"disk_usag\"\n"));
+        let err = config.validate().unwrap_err();
+        assert!(matches!(err, Error::InvalidField("aliases", _)));
+    }
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    #[test]
+    fn test_common_paths() {
+        let var = |name: &str| match name {
+            "HOME" => Some("/home/john".into()),
+            "XDG_CONFIG_HOME" => Some("/xdg".into()),
+            _ => None,
+        };
+        assert_eq!(
+            Config::common_paths(var),
+            [
+                "/xdg/korah/korah.toml",
+                "./korah.toml",
+                "/home/john/.config/korah.toml",
+                "/etc/korah.toml"
+            ]
+            .map(PathBuf::from)
+        );
+
+        let paths = Config::common_paths(|_| None);
+        assert_eq!(
+            paths,
+            ["./korah.toml", "/etc/korah.toml"].map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = parse("[llm]\napi = \"ollama\"\n[llm.ollama]\nbase_url = \"http://localhost:11434\"\nmodel = \"qwen2.5\"\n");
+        assert!(!config.double_pass_derive);
+        assert_eq!(config.num_derive_tries, 3);
+        assert!(!config.retry_on_empty);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema = serde_json::to_value(schemars::schema_for!(Config)).unwrap();
+        assert_eq!(schema["required"], serde_json::json!(["llm"]));
+        let ollama = &schema["definitions"]["OllamaConfig"]["properties"];
+        // Flattened retry options and request options are top-level keys of a provider table.
+        assert!(ollama.get("max_retries").is_some());
+        assert!(ollama.get("temperature").is_some());
+    }
+
+    #[test]
+    fn test_apply_env_overrides() {
+        let mut table: Table = toml::from_str(
+            "num_derive_tries = 3\n[llm]\napi = \"ollama\"\n\
+            [llm.ollama]\nbase_url = \"http://localhost:11434\"\n",
+        )
+        .unwrap();
+
+        let vars = [
+            ("KORAH_LLM__OLLAMA__BASE_URL", "http://ci:11434"),
+            ("KORAH_NUM_DERIVE_TRIES", "5"),
+            ("KORAH_LLM__CONTEXT_FIELDS", r#"["os_name"]"#),
+            ("KORAH_FOO__BAR", "ignored"),
+            ("PATH", "/usr/bin"),
+        ];
+        let vars = vars.map(|(k, v)| (k.to_owned(), v.to_owned()));
+        apply_env_overrides(&mut table, vars).unwrap();
+        assert_eq!(table["num_derive_tries"].as_integer(), Some(5));
+        assert_eq!(
+            table["llm"]["ollama"]["base_url"].as_str(),
+            Some("http://ci:11434")
+        );
+        assert_eq!(table["llm"]["context_fields"][0].as_str(), Some("os_name"));
+        assert!(!table.contains_key("foo"));
+
+        let vars = [("KORAH_NUM_DERIVE_TRIES".to_owned(), "many".to_owned())];
+        let err = apply_env_overrides(&mut table, vars).unwrap_err();
+        assert!(matches!(err, Error::EnvOverride(name) if name == "KORAH_NUM_DERIVE_TRIES"));
+    }
+
+    #[test]
+    fn test_new_key_env_override() {
+        let table: Table = toml::from_str(
+            "[llm]
+api = \"ollama\"
+\
+            [llm.ollama]
+base_url = \"http://localhost:11434\"
+model = \"qwen2.5\"
+",
+        )
+        .unwrap();
+        let vars = |value: &str| vec![("KORAH_MAX_OUTPUTS".to_owned(), value.to_owned())];
+
+        let config = Config::from_table(table.clone(), vars("7")).unwrap();
+        assert_eq!(config.max_outputs, Some(7));
+
+        let err = Config::from_table(table.clone(), vars("abc")).unwrap_err();
+        assert!(matches!(err, Error::EnvOverride(name) if name == "KORAH_MAX_OUTPUTS"));
+
+        // A malformed table isn't blamed on the overrides.
+        let mut table = table;
+        table.remove("llm");
+        let err = Config::from_table(table, vars("abc")).unwrap_err();
+        assert!(matches!(err, Error::TomlDe(_)));
     }
 }
@@ -0,0 +1,1108 @@
+//! Natural language queries processed by deriving and executing tool calls.
+//!
+//! Korah can be embedded into other programs:
+//!
+//! ```no_run
+//! use korah::{config::Config, run_query};
+//! use std::{
+//!     path::Path,
+//!     sync::{atomic::AtomicBool, Arc},
+//! };
+//!
+//! let config = Config::read(Path::new("korah.toml")).unwrap();
+//! let cancel = Arc::new(AtomicBool::new(false));
+//! for output in run_query(&config, "find videos on the desktop", cancel).unwrap() {
+//!     println!("{}", output.get());
+//! }
+//! ```
+
+pub mod config;
+pub mod llm;
+pub mod output;
+pub mod tool;
+pub mod util;
+
+pub use crate::{
+    llm::create_llm_client,
+    tool::{create_tools, BoxOutputIter},
+};
+
+use crate::{
+    config::Config,
+    llm::{
+        embedding::{create_embedding_client, preselect_tools},
+        ContentCallback, Context, LlmClient, ToolCall,
+    },
+    tool::{deadline_cancel, CallReport, DynTool, DynTools, ToolMeta},
+    util::fmt::ErrorChainDisplay,
+};
+use either::Either;
+use log::{debug, info, log_enabled, warn};
+use schemars::{schema_for, JsonSchema};
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use std::{
+    fmt::{self, Debug, Formatter},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// A query processing error.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("processing cancelled")]
+    Cancelled,
+    #[error("failed to read config")]
+    Config(
+        #[from]
+        #[source]
+        crate::config::Error,
+    ),
+    #[error("failed to derive tool call")]
+    DeriveToolCall,
+    #[error("{0} tool call(s) failed")]
+    FailedCalls(usize),
+    #[error("failed to parse tool calls")]
+    MalformedCalls(#[source] serde_json::Error),
+    #[error("llm error")]
+    Llm(
+        #[from]
+        #[source]
+        crate::llm::Error,
+    ),
+    #[error("no query given as an argument or via stdin")]
+    MissingQuery,
+    #[error("no tools enabled")]
+    NoTools,
+    #[error("failed to perform io")]
+    SerdeJson(
+        #[from]
+        #[source]
+        std::io::Error,
+    ),
+    #[error("failed to deserialize toml")]
+    TomlDe(
+        #[from]
+        #[source]
+        toml::de::Error,
+    ),
+    #[error("processing timed out")]
+    TimedOut,
+    #[error("tool call timed out")]
+    ToolTimedOut,
+    #[error("tool error")]
+    Tool(
+        #[from]
+        #[source]
+        crate::tool::Error,
+    ),
+    #[error("unknown tool '{0}'")]
+    UnknownTool(String),
+}
+
+impl Error {
+    /// A stable machine-readable code of the error variant.
+    pub fn code(&self) -> &'static str {
+        use Error::*;
+        match self {
+            Cancelled => "cancelled",
+            Config(_) => "config",
+            DeriveToolCall => "derive_tool_call",
+            FailedCalls(_) => "failed_calls",
+            MalformedCalls(_) => "malformed_calls",
+            Llm(_) => "llm",
+            MissingQuery => "missing_query",
+            NoTools => "no_tools",
+            SerdeJson(_) => "io",
+            TomlDe(_) => "toml_de",
+            TimedOut => "timed_out",
+            ToolTimedOut => "tool_timed_out",
+            Tool(_) => "tool",
+            UnknownTool(_) => "unknown_tool",
+        }
+    }
+}
+
+/// Query processing options.
+#[derive(Clone, Default)]
+pub struct QueryOptions {
+    /// Asks whether a derived tool call is to be executed (nothing is executed if declined).
+    pub confirm: Option<fn(&ToolCall) -> bool>,
+    /// Return the derived tool calls instead of calling the tools.
+    pub derive_only: bool,
+    /// Overrides `double_pass_derive` of the config if set.
+    pub double_pass_derive: Option<bool>,
+    /// Send the raw query without context.
+    pub no_context: bool,
+    /// Receives assistant content pieces during derivation (Ollama and OpenAI clients only).
+    pub on_content: Option<ContentCallback>,
+    /// Params injected into a derived tool call if the tool supports them and they are unset.
+    pub param_defaults: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Debug for QueryOptions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QueryOptions")
+            .field("confirm", &self.confirm)
+            .field("derive_only", &self.derive_only)
+            .field("double_pass_derive", &self.double_pass_derive)
+            .field("no_context", &self.no_context)
+            .field("on_content", &self.on_content.as_ref().map(|_| ".."))
+            .field("param_defaults", &self.param_defaults)
+            .finish()
+    }
+}
+
+/// Outputs of executed tool calls.
+pub struct ToolOutputs {
+    /// Chained outputs of the calls which succeeded.
+    pub outputs: BoxOutputIter,
+    /// The number of calls which failed (the failures are logged).
+    pub num_failures: usize,
+    /// Set once a call exceeds the configured tool timeout, final after consuming the outputs.
+    pub tool_timed_out: Arc<AtomicBool>,
+    /// Facts gathered by the calls besides their outputs, final after consuming the outputs.
+    pub report: Arc<CallReport>,
+}
+
+impl ToolOutputs {
+    fn empty() -> Self {
+        Self {
+            outputs: Box::new(std::iter::empty()),
+            num_failures: 0,
+            tool_timed_out: Default::default(),
+            report: Default::default(),
+        }
+    }
+}
+
+macro_rules! check_confirm {
+    ($options: expr, $call: expr) => {
+        if let Some(confirm) = $options.confirm {
+            if !confirm($call) {
+                info!("tool call declined");
+                return Ok(Either::Left(ToolOutputs::empty()));
+            }
+        }
+    };
+}
+
+macro_rules! check_cancel {
+    ($cancel: expr) => {
+        if $cancel.load(Ordering::SeqCst) {
+            return Ok(Either::Left(ToolOutputs::empty()));
+        }
+    };
+}
+
+/// Unwraps derived calls, malformed ones are logged and count as invalid calls of the try.
+macro_rules! check_malformed {
+    ($result: expr, $has_invalid_calls: ident, $last_call_err: ident) => {
+        match $result {
+            Err(crate::llm::Error::MalformedToolCall(err)) => {
+                warn!("derived call is malformed: {}", ErrorChainDisplay(&err));
+                $has_invalid_calls = true;
+                $last_call_err = Some(crate::llm::Error::MalformedToolCall(err).into());
+                continue;
+            }
+            result => result?,
+        }
+    };
+}
+
+/// Derives tool calls from a given query and calls the tools getting a joint output iterator.
+///
+/// The calls are executed in sequence concatenating their outputs. All of them are validated
+/// before executing any, and once executed they are never retried, so a failed call is only
+/// counted in the result unless all of them fail (the last error is returned then). Returns
+/// the derived tool calls instead if `derive_only` option is set.
+pub fn derive_and_call_tool(
+    config: &Config,
+    query: &str,
+    options: &QueryOptions,
+    tools: &DynTools,
+    cancel: Arc<AtomicBool>,
+) -> Result<Either<ToolOutputs, Vec<ToolCall>>, Error> {
+    if tools.is_empty() {
+        return Err(Error::NoTools);
+    }
+
+    let mut contextualized_query = if options.no_context {
+        query.to_owned()
+    } else {
+        Context::new().contextualize(&config.llm, query.to_owned())?
+    };
+    debug!("contextualized query '{contextualized_query}'");
+
+    let tools_meta: Vec<_> = tools.values().map(|t| t.meta()).collect();
+    let tools_meta = preselect_tools_meta(config, tools_meta, query);
+    let mut llm = create_llm_client(&config.llm)?;
+    if let Some(on_content) = &options.on_content {
+        llm.set_content_callback(on_content.clone());
+    }
+
+    let double_pass_derive = options
+        .double_pass_derive
+        .unwrap_or(config.double_pass_derive);
+    debug!(
+        "deriving in {} pass mode",
+        if double_pass_derive {
+            "double"
+        } else {
+            "single"
+        }
+    );
+
+    let mut num_call_failures = 0;
+    let mut last_call_err = None;
+    let mut has_empty_outputs = false;
+    let mut num_failures = 0;
+    let tool_timed_out = Arc::new(AtomicBool::new(false));
+    let report = Arc::new(CallReport::default());
+
+    let outputs: BoxOutputIter = 'a: {
+        for _ in 0..config.num_derive_tries {
+            check_cancel!(cancel);
+
+            // Ranked candidate tools are tried in turn, otherwise a single derivation is made.
+            let candidates = match (double_pass_derive, config.candidate_tools) {
+                (true, Some(num_candidates)) => {
+                    let mut names = rank_tools(llm.as_ref(), &tools_meta, query)?;
+                    names.truncate(num_candidates);
+                    if names.is_empty() {
+                        warn!("no candidate tools derived");
+                        continue;
+                    }
+                    names.into_iter().map(Some).collect()
+                }
+                _ => vec![None],
+            };
+
+            let mut has_invalid_calls = false;
+            'candidates: for candidate in candidates {
+                check_cancel!(cancel);
+
+                let mut calls = if let Some(name) = &candidate {
+                    let mut tools_meta = tools_meta.clone();
+                    tools_meta.retain(|t| &t.name == name);
+                    if tools_meta.is_empty() {
+                        warn!("unknown candidate tool '{name}'");
+                        continue;
+                    }
+                    let call = llm.derive_tool_call(tools_meta, contextualized_query.clone());
+                    match check_malformed!(call, has_invalid_calls, last_call_err) {
+                        Some(call) => vec![call],
+                        None => {
+                            warn!("no tool call params derived for candidate tool '{name}'");
+                            continue;
+                        }
+                    }
+                } else if double_pass_derive {
+                    let tools_stripped_meta: Vec<_> = tools_meta
+                        .iter()
+                        .cloned()
+                        .map(ToolMeta::strip_params)
+                        .collect();
+                    let call = llm.derive_tool_call(tools_stripped_meta, query.to_owned());
+                    let Some(mut call) = check_malformed!(call, has_invalid_calls, last_call_err)
+                    else {
+                        warn!("no tool name derived");
+                        continue;
+                    };
+
+                    correct_tool_name(&mut call, tools);
+                    let mut tools_meta = tools_meta.clone();
+                    tools_meta.retain(|t| t.name == call.tool);
+                    if tools_meta.is_empty() {
+                        warn!("unknown derived tool '{}'", call.tool);
+                        continue;
+                    }
+
+                    check_cancel!(cancel);
+
+                    let call = llm.derive_tool_call(tools_meta, contextualized_query.clone());
+                    match check_malformed!(call, has_invalid_calls, last_call_err) {
+                        Some(call) => vec![call],
+                        None => {
+                            warn!("no tool call params derived");
+                            continue;
+                        }
+                    }
+                } else {
+                    let calls =
+                        llm.derive_tool_calls(tools_meta.clone(), contextualized_query.clone());
+                    let calls = check_malformed!(calls, has_invalid_calls, last_call_err);
+                    if calls.is_empty() {
+                        warn!("no tool calls derived");
+                        continue;
+                    }
+                    calls
+                };
+
+                for call in &mut calls {
+                    correct_tool_name(call, tools);
+                }
+                let defaults = &options.param_defaults;
+                if let Err(err) = calls
+                    .iter_mut()
+                    .try_for_each(|call| apply_param_defaults(tools, call, defaults))
+                {
+                    warn!("derived call is malformed: {}", ErrorChainDisplay(&err));
+                    has_invalid_calls = true;
+                    last_call_err = Some(err);
+                    continue;
+                }
+
+                if options.derive_only {
+                    return Ok(Either::Right(calls));
+                }
+
+                if log_enabled!(log::Level::Info) {
+                    for call in &calls {
+                        let json = serde_json::to_string(call).unwrap();
+                        info!("derived call {json}");
+                    }
+                }
+
+                // All the calls are checked before executing any of them.
+                if let Some(call) = calls.iter().find(|c| !tools.contains_key(&c.tool.as_str())) {
+                    warn!("unknown derived tool '{}'", call.tool);
+                    continue;
+                }
+
+                // Only calls which haven't been executed yet can be rederived.
+                for call in &calls {
+                    if let Err(err) = tools[&call.tool.as_str()].validate(&call.params) {
+                        warn!("derived call is invalid: {}", ErrorChainDisplay(&err));
+                        has_invalid_calls = true;
+                        last_call_err = Some(err.into());
+                        continue 'candidates;
+                    }
+                }
+
+                for call in &calls {
+                    check_confirm!(options, call);
+                }
+
+                let calls_json = serde_json::to_string(&calls).unwrap();
+                let mut outputs = Vec::with_capacity(calls.len());
+                let mut last_err = None;
+                for call in calls {
+                    let tool = &tools[&call.tool.as_str()];
+                    let result = call_with_timeout(
+                        config,
+                        tool.as_ref(),
+                        call.params,
+                        &cancel,
+                        &tool_timed_out,
+                        &report,
+                    );
+                    match result {
+                        Ok(it) => outputs.push(it),
+                        Err(err) => {
+                            warn!("derived call failed: {}", ErrorChainDisplay(&err));
+                            num_failures += 1;
+                            last_err = Some(err);
+                        }
+                    }
+                }
+                // Report the actual cause if no call succeeded.
+                if let (true, Some(err)) = (outputs.is_empty(), last_err) {
+                    return Err(err.into());
+                }
+                let mut outputs = outputs.into_iter().flatten().peekable();
+                // Peeking keeps the first output in the iterator.
+                if num_failures > 0 || outputs.peek().is_some() {
+                    break 'a Box::new(outputs);
+                }
+                info!("derived calls produced no output");
+                if config.retry_on_empty {
+                    append_empty_output_hint(&mut contextualized_query, &calls_json);
+                }
+                has_empty_outputs = true;
+            }
+
+            if has_empty_outputs && !config.retry_on_empty {
+                break 'a Box::new(std::iter::empty());
+            }
+            if has_invalid_calls {
+                num_call_failures += 1;
+            }
+        }
+
+        // An empty output is a success if no retry produced anything better.
+        if has_empty_outputs {
+            break 'a Box::new(std::iter::empty());
+        }
+
+        // Report the actual cause if the derivation succeeded every time.
+        if num_call_failures == config.num_derive_tries {
+            if let Some(err) = last_call_err {
+                return Err(err);
+            }
+        }
+        return Err(Error::DeriveToolCall);
+    };
+
+    Ok(Either::Left(ToolOutputs {
+        outputs,
+        num_failures,
+        tool_timed_out,
+        report,
+    }))
+}
+
+/// Narrows tools down to the most similar to a given query if configured.
+///
+/// Falls back to all the tools if the embedding API fails.
+fn preselect_tools_meta(config: &Config, tools_meta: Vec<ToolMeta>, query: &str) -> Vec<ToolMeta> {
+    let (Some(k), Some(embedding_config)) = (config.tool_preselect_k, &config.llm.embedding) else {
+        return tools_meta;
+    };
+    if tools_meta.len() <= k {
+        return tools_meta;
+    }
+
+    let client = create_embedding_client(embedding_config);
+    match preselect_tools(client.as_ref(), &tools_meta, query, k) {
+        Ok(selected) => {
+            let names: Vec<_> = selected.iter().map(|t| t.name.as_str()).collect();
+            debug!("preselected tools {names:?}");
+            selected
+        }
+        Err(err) => {
+            warn!("failed to preselect tools: {}", ErrorChainDisplay(&err));
+            tools_meta
+        }
+    }
+}
+
+/// Asks LLM to rank the tools by their relevance to a given query.
+fn rank_tools(
+    llm: &dyn LlmClient,
+    tools_meta: &[ToolMeta],
+    query: &str,
+) -> Result<Vec<String>, Error> {
+    #[derive(Deserialize, JsonSchema)]
+    struct RankToolsParams {
+        #[schemars(description = "Tool names ordered by relevance, most relevant first.")]
+        tools: Vec<String>,
+    }
+
+    let tools_desc: Vec<_> = tools_meta
+        .iter()
+        .map(|t| match &t.description {
+            Some(desc) => format!("{} ({desc})", t.name),
+            None => t.name.clone(),
+        })
+        .collect();
+    let meta = ToolMeta {
+        name: "rank_tools".to_owned(),
+        description: Some(format!(
+            "Ranks the following tools by their relevance to the query: {}.",
+            tools_desc.join(", ")
+        )),
+        destructive: false,
+        params_schema: schema_for!(RankToolsParams),
+        output_schema: schema_for!(()),
+    };
+
+    let Some(call) = llm.derive_tool_call(vec![meta], query.to_owned())? else {
+        return Ok(Vec::new());
+    };
+    match serde_json::from_str::<RankToolsParams>(call.params.get()) {
+        Ok(params) => Ok(params.tools),
+        Err(err) => {
+            warn!("failed to parse ranked tools: {}", ErrorChainDisplay(&err));
+            Ok(Vec::new())
+        }
+    }
+}
+
+/// The maximum edit distance at which an unknown derived tool name is corrected.
+const MAX_TOOL_NAME_DISTANCE: usize = 2;
+
+/// Appends a hint about a derived call (or calls) with no output to a given query.
+fn append_empty_output_hint(query: &mut String, call_json: &str) {
+    query.push_str(&format!(
+        "\n\nThe previous attempt {call_json} produced no output, try a different call."
+    ));
+}
+
+/// Replaces an unknown tool name of a given call with a single close known one if any.
+fn correct_tool_name(call: &mut ToolCall, tools: &DynTools) {
+    if tools.contains_key(&call.tool.as_str()) {
+        return;
+    }
+
+    let mut matches = tools
+        .keys()
+        .filter(|name| levenshtein(&call.tool, name) <= MAX_TOOL_NAME_DISTANCE);
+    if let (Some(name), None) = (matches.next(), matches.next()) {
+        info!("corrected derived tool name '{}' to '{name}'", call.tool);
+        call.tool = (*name).to_owned();
+    }
+}
+
+/// Computes the Levenshtein distance between two strings.
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<_> = b.chars().collect();
+    let mut row: Vec<_> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let sub = diag + usize::from(ca != *cb);
+            diag = row[j + 1];
+            row[j + 1] = sub.min(row[j] + 1).min(diag + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Injects default params into a call of a known tool if the tool supports them.
+pub fn apply_param_defaults(
+    tools: &DynTools,
+    call: &mut ToolCall,
+    defaults: &serde_json::Map<String, serde_json::Value>,
+) -> Result<(), Error> {
+    match tools.get(&call.tool.as_str()) {
+        Some(tool) => merge_param_defaults(call, &tool.meta(), defaults),
+        None => Ok(()),
+    }
+}
+
+/// Injects default params supported by the tool unless they are already set.
+fn merge_param_defaults(
+    call: &mut ToolCall,
+    meta: &ToolMeta,
+    defaults: &serde_json::Map<String, serde_json::Value>,
+) -> Result<(), Error> {
+    let Some(params_object) = &meta.params_schema.schema.object else {
+        return Ok(());
+    };
+
+    let mut defaults = defaults
+        .iter()
+        .filter(|(k, _)| params_object.properties.contains_key(*k))
+        .peekable();
+    if defaults.peek().is_none() {
+        return Ok(());
+    }
+
+    let mut params: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(call.params.get()).map_err(crate::tool::Error::from)?;
+    for (k, v) in defaults {
+        if params.get(k).is_none_or(serde_json::Value::is_null) {
+            params.insert(k.clone(), v.clone());
+        }
+    }
+
+    let params = serde_json::to_string(&params).map_err(crate::tool::Error::from)?;
+    call.params = RawValue::from_string(params).map_err(crate::tool::Error::from)?;
+    Ok(())
+}
+
+/// Calls a tool bounded by the configured tool timeout.
+///
+/// The timeout is counted from the first output request, so chained calls aren't charged
+/// for consuming the outputs of the preceding ones. The `timed_out` flag is set on timeout.
+fn call_with_timeout(
+    config: &Config,
+    tool: &dyn DynTool,
+    params: Box<RawValue>,
+    cancel: &Arc<AtomicBool>,
+    timed_out: &Arc<AtomicBool>,
+    report: &Arc<CallReport>,
+) -> Result<BoxOutputIter, crate::tool::Error> {
+    let Some(secs) = config.tool_timeout_secs else {
+        return tool.call(params, cancel.clone(), report.clone());
+    };
+
+    let started = Arc::new(AtomicBool::new(false));
+    let timeout = Duration::from_secs(secs);
+    let call_cancel = deadline_cancel(cancel.clone(), timeout, started.clone(), timed_out.clone());
+    let mut outputs = tool.call(params, call_cancel, report.clone())?;
+    Ok(Box::new(std::iter::from_fn(move || {
+        started.store(true, Ordering::SeqCst);
+        outputs.next()
+    })))
+}
+
+/// Calls a tool explicitly specified by a given tool call.
+pub fn call_tool(
+    config: &Config,
+    tools: &DynTools,
+    call: ToolCall,
+    cancel: &Arc<AtomicBool>,
+) -> Result<ToolOutputs, Error> {
+    let Some(tool) = tools.get(&call.tool.as_str()) else {
+        return Err(Error::UnknownTool(call.tool));
+    };
+    let tool_timed_out = Arc::new(AtomicBool::new(false));
+    let report = Arc::new(CallReport::default());
+    let outputs = call_with_timeout(
+        config,
+        tool.as_ref(),
+        call.params,
+        cancel,
+        &tool_timed_out,
+        &report,
+    )?;
+    Ok(ToolOutputs {
+        outputs,
+        num_failures: 0,
+        tool_timed_out,
+        report,
+    })
+}
+
+/// Reads a JSON-serialized tool call or an array of them from a given file.
+pub fn read_tool_calls(path: &Path) -> Result<Vec<ToolCall>, Error> {
+    let s = std::fs::read_to_string(path)?;
+    if s.trim_start().starts_with('[') {
+        serde_json::from_str(&s).map_err(Error::MalformedCalls)
+    } else {
+        serde_json::from_str(&s)
+            .map(|call| vec![call])
+            .map_err(Error::MalformedCalls)
+    }
+}
+
+/// Calls given tool calls one by one after checking their params against tool schemas.
+///
+/// The param defaults of given options are injected into the calls. A failed call is logged
+/// and doesn't prevent the others from being executed.
+pub fn call_tools(
+    config: &Config,
+    options: &QueryOptions,
+    tools: &DynTools,
+    calls: Vec<ToolCall>,
+    cancel: &Arc<AtomicBool>,
+) -> ToolOutputs {
+    let mut outputs = Vec::with_capacity(calls.len());
+    let mut num_failures = 0;
+    let tool_timed_out = Arc::new(AtomicBool::new(false));
+    let report = Arc::new(CallReport::default());
+
+    for mut call in calls {
+        if cancel.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let name = call.tool.clone();
+        if let Err(err) = apply_param_defaults(tools, &mut call, &options.param_defaults) {
+            warn!("call of '{name}' failed: {}", ErrorChainDisplay(&err));
+            num_failures += 1;
+            continue;
+        }
+        if options.confirm.is_some_and(|confirm| !confirm(&call)) {
+            info!("tool call declined");
+            continue;
+        }
+
+        let result = match tools.get(&call.tool.as_str()) {
+            Some(tool) => tool
+                .validate(&call.params)
+                .and_then(|_| {
+                    call_with_timeout(
+                        config,
+                        tool.as_ref(),
+                        call.params,
+                        cancel,
+                        &tool_timed_out,
+                        &report,
+                    )
+                })
+                .map_err(Into::into),
+            None => Err(Error::UnknownTool(call.tool)),
+        };
+        match result {
+            Ok(it) => outputs.push(it),
+            Err(err) => {
+                warn!("call of '{name}' failed: {}", ErrorChainDisplay(&err));
+                num_failures += 1;
+            }
+        }
+    }
+
+    ToolOutputs {
+        outputs: Box::new(outputs.into_iter().flatten()),
+        num_failures,
+        tool_timed_out,
+        report,
+    }
+}
+
+/// Describes in human language what a given tool call would do without performing it.
+pub fn explain_call(tools: &DynTools, call: &ToolCall) -> Result<String, Error> {
+    let Some(tool) = tools.get(&call.tool.as_str()) else {
+        return Err(Error::UnknownTool(call.tool.clone()));
+    };
+    tool.explain(&call.params).map_err(Into::into)
+}
+
+/// Interprets a given query as a tool call to be executed without LLM if possible.
+///
+/// The query is either an alias use or a JSON-serialized tool call.
+pub fn direct_tool_call(config: &Config, query: &str) -> Option<ToolCall> {
+    if let Some(call) = expand_alias(config, query) {
+        info!("expanded query alias into a tool call");
+        return Some(call);
+    }
+    let call = serde_json::from_str::<ToolCall>(query).ok()?;
+    info!("interpreted query as a tool call");
+    Some(call)
+}
+
+/// Expands a query starting with an alias name into a tool call.
+fn expand_alias(config: &Config, query: &str) -> Option<ToolCall> {
+    let query = query.trim();
+    let (name, text) = query.split_once(char::is_whitespace).unwrap_or((query, ""));
+    let alias = config.aliases.get(name)?;
+
+    let text = text.trim();
+    let words: Vec<_> = text.split_whitespace().collect();
+    let mut params = serde_json::Value::Object(alias.params.clone());
+    if !substitute_captures(&mut params, text, &words) {
+        debug!("missing captures for alias '{name}'");
+        return None;
+    }
+
+    Some(ToolCall {
+        tool: alias.tool.clone(),
+        params: RawValue::from_string(params.to_string()).unwrap(),
+        reason: None,
+    })
+}
+
+/// Substitutes captures in string values recursively returning false if any is missing.
+fn substitute_captures(value: &mut serde_json::Value, text: &str, words: &[&str]) -> bool {
+    use serde_json::Value::*;
+    match value {
+        String(s) => match substitute_string_captures(s, text, words) {
+            Some(substituted) => {
+                *s = substituted;
+                true
+            }
+            None => false,
+        },
+        Array(values) => values
+            .iter_mut()
+            .all(|v| substitute_captures(v, text, words)),
+        Object(values) => values
+            .values_mut()
+            .all(|v| substitute_captures(v, text, words)),
+        _ => true,
+    }
+}
+
+fn substitute_string_captures(template: &str, text: &str, words: &[&str]) -> Option<String> {
+    let mut result = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.next_if_eq(&'{').is_some() => result.push('{'),
+            '}' if chars.next_if_eq(&'}').is_some() => result.push('}'),
+            '{' => {
+                let capture: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                let value = match capture.as_str() {
+                    "*" => Some(text).filter(|t| !t.is_empty()),
+                    index => index
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|i| words.get(i.checked_sub(1)?).copied()),
+                };
+                result.push_str(value?);
+            }
+            c => result.push(c),
+        }
+    }
+    Some(result)
+}
+
+/// Processes a given query collecting the tool outputs.
+///
+/// A query which is an alias use or a JSON-serialized tool call is executed directly.
+pub fn run_query(
+    config: &Config,
+    query: &str,
+    cancel: Arc<AtomicBool>,
+) -> Result<Vec<Box<RawValue>>, Error> {
+    let tools = create_tools(Some(config));
+    let options = QueryOptions::default();
+
+    let ToolOutputs {
+        outputs,
+        num_failures,
+        tool_timed_out,
+        ..
+    } = if let Some(call) = direct_tool_call(config, query) {
+        call_tool(config, &tools, call, &cancel)?
+    } else {
+        match derive_and_call_tool(config, query, &options, &tools, cancel.clone())? {
+            Either::Left(outputs) => outputs,
+            Either::Right(_) => unreachable!("derive_only is not set"),
+        }
+    };
+
+    let outputs = outputs.collect();
+
+    if cancel.load(Ordering::SeqCst) {
+        Err(Error::Cancelled)
+    } else if tool_timed_out.load(Ordering::SeqCst) {
+        Err(Error::ToolTimedOut)
+    } else if num_failures > 0 {
+        Err(Error::FailedCalls(num_failures))
+    } else {
+        Ok(outputs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(tool: &str) -> ToolCall {
+        ToolCall {
+            tool: tool.to_owned(),
+            params: RawValue::from_string("{}".to_owned()).unwrap(),
+            reason: None,
+        }
+    }
+
+    #[test]
+    fn test_correct_tool_name() {
+        let tools = create_tools(None);
+
+        let mut c = call("find_file");
+        correct_tool_name(&mut c, &tools);
+        assert_eq!(c.tool, "find_files");
+
+        let mut c = call("find_process");
+        correct_tool_name(&mut c, &tools);
+        assert_eq!(c.tool, "find_processes");
+
+        let mut c = call("delete_files");
+        correct_tool_name(&mut c, &tools);
+        assert_eq!(c.tool, "delete_files");
+    }
+
+    #[test]
+    fn test_call_tools() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            file.path(),
+            r#"[
+                {"tool": "get_env", "params": {"name_regex": "^PATH$"}},
+                {"tool": "nope", "params": {}},
+                {"tool": "get_env", "params": {"name_regexp": "^PATH$"}}
+            ]"#,
+        )
+        .unwrap();
+        let calls = read_tool_calls(file.path()).unwrap();
+        assert_eq!(calls.len(), 3);
+
+        let config: Config = toml::from_str("[llm]\napi = \"ollama\"\n").unwrap();
+        let tools = create_tools(Some(&config));
+        let cancel = Arc::new(AtomicBool::new(false));
+        let outputs = call_tools(&config, &Default::default(), &tools, calls, &cancel);
+        assert_eq!(outputs.num_failures, 2);
+        assert_eq!(outputs.outputs.count(), 1);
+    }
+
+    #[test]
+    fn test_chained_calls_timeout() {
+        let temp = tempfile::tempdir().unwrap();
+        std::fs::write(temp.path().join("a.txt"), "a").unwrap();
+        let calls = serde_json::json!([
+            {"tool": "get_env", "params": {"name_regex": "^PATH$"}},
+            {"tool": "find_files", "params": {"in_directory": temp.path()}},
+        ]);
+        let calls = serde_json::from_value(calls).unwrap();
+
+        let config: Config =
+            toml::from_str("tool_timeout_secs = 1\n[llm]\napi = \"ollama\"\n").unwrap();
+        let tools = create_tools(Some(&config));
+        let cancel = Arc::new(AtomicBool::new(false));
+        let mut outputs = call_tools(&config, &Default::default(), &tools, calls, &cancel);
+
+        // The timeout of a call starts once its outputs are requested.
+        assert!(outputs.outputs.next().is_some());
+        std::thread::sleep(Duration::from_millis(1500));
+        assert_eq!(outputs.outputs.count(), 1);
+        assert!(!outputs.tool_timed_out.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_expand_alias() {
+        let config: Config = toml::from_str(
+            r#"
+            [llm]
+            api = "ollama"
+            [aliases.du]
+            tool = "disk_usage"
+            params = { in_directory = "{1}", top = 5 }
+            [aliases.env]
+            tool = "get_env"
+            params = { name_regex = "^{{{*}}}$" }
+            "#,
+        )
+        .unwrap();
+        let params = |query| {
+            let call = expand_alias(&config, query)?;
+            Some(serde_json::from_str::<serde_json::Value>(call.params.get()).unwrap())
+        };
+
+        assert_eq!(
+            params(" du  ~/Downloads extra"),
+            Some(serde_json::json!({"in_directory": "~/Downloads", "top": 5}))
+        );
+        assert_eq!(
+            params("env HOME PATH"),
+            Some(serde_json::json!({"name_regex": "^{HOME PATH}$"}))
+        );
+        assert_eq!(params("du"), None);
+        assert_eq!(params("dust ~/Downloads"), None);
+        assert!(direct_tool_call(&config, "show disk usage").is_none());
+    }
+
+    #[test]
+    fn test_error_code() {
+        assert_eq!(Error::TimedOut.code(), "timed_out");
+        let err = Error::from(crate::config::Error::InvalidField("llm", "missing"));
+        assert_eq!(err.code(), "config");
+    }
+
+    #[test]
+    fn test_mock_derive_and_call_tool() {
+        let config: Config = toml::from_str(
+            r#"
+            [llm]
+            api = "mock"
+            [llm.mock]
+            params = { name_regex = "^PATH$" }
+            query_contains = "path"
+            tool = "get_env"
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        let tools = create_tools(Some(&config));
+        let options = QueryOptions {
+            no_context: true,
+            ..Default::default()
+        };
+        let cancel = Arc::new(AtomicBool::new(false));
+
+        let result = derive_and_call_tool(&config, "show path", &options, &tools, cancel.clone());
+        let Ok(Either::Left(outputs)) = result else {
+            panic!("no outputs");
+        };
+        let outputs: Vec<_> = outputs.outputs.collect();
+        assert_eq!(outputs.len(), 1);
+        assert!(outputs[0].get().contains("\"PATH\""));
+
+        let result = derive_and_call_tool(&config, "show home", &options, &tools, cancel.clone());
+        assert!(matches!(result, Err(Error::DeriveToolCall)));
+
+        let options = QueryOptions {
+            double_pass_derive: Some(true),
+            ..options
+        };
+        let result = derive_and_call_tool(&config, "show path", &options, &tools, cancel);
+        let Ok(Either::Left(outputs)) = result else {
+            panic!("no outputs");
+        };
+        assert_eq!(outputs.outputs.count(), 1);
+    }
+
+    #[test]
+    fn test_failed_derived_call() {
+        let derive = |params: &str| {
+            let config: Config = toml::from_str(&format!(
+                "num_derive_tries = 3\n[llm]\napi = \"mock\"\n\
+                [llm.mock]\nparams = {params}\ntool = \"read_file\"\n"
+            ))
+            .unwrap();
+            let tools = create_tools(Some(&config));
+            let options = QueryOptions {
+                no_context: true,
+                ..Default::default()
+            };
+            let cancel = Arc::new(AtomicBool::new(false));
+            derive_and_call_tool(&config, "read it", &options, &tools, cancel)
+        };
+
+        // An executed call isn't retried, its error is reported as the only one.
+        let result = derive(r#"{ path = "/nonexistent/korah" }"#);
+        assert!(matches!(
+            result,
+            Err(Error::Tool(crate::tool::Error::Io(_)))
+        ));
+
+        // An invalid call is rejected before execution and rederived.
+        let result = derive(r#"{ pathname = "/nonexistent/korah" }"#);
+        assert!(matches!(result, Err(Error::Tool(_))));
+    }
+
+    #[test]
+    fn test_malformed_derived_call() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}/v1", server.server_addr().to_ip().unwrap());
+        std::thread::spawn(move || {
+            // The first derived call is cut off, the second one is fine.
+            for arguments in [r#"{"name_regex":"#, r#"{"name_regex":"^PATH$"}"#] {
+                let request = server.recv().unwrap();
+                let body = serde_json::json!({
+                    "choices": [{
+                        "message": {
+                            "role": "assistant",
+                            "tool_calls": [{
+                                "function": {"name": "get_env", "arguments": arguments},
+                            }],
+                        },
+                    }],
+                });
+                let response = tiny_http::Response::from_string(body.to_string());
+                request.respond(response).unwrap();
+            }
+        });
+
+        let config: Config = toml::from_str(&format!(
+            "num_derive_tries = 2\n[llm]\napi = \"open_ai\"\n\
+            [llm.open_ai]\nbase_url = \"{base_url}\"\nmodel = \"gpt-4o-mini\"\n"
+        ))
+        .unwrap();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let outputs = run_query(&config, "show path", cancel).unwrap();
+        assert_eq!(outputs.len(), 1);
+    }
+
+    #[test]
+    fn test_run_query_tool_error() {
+        let config: Config = toml::from_str(
+            "[llm]\napi = \"mock\"\n\
+            [llm.mock]\nparams = { path = \"/nonexistent/korah\" }\ntool = \"read_file\"\n",
+        )
+        .unwrap();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let result = run_query(&config, "read it", cancel);
+        assert!(matches!(
+            result,
+            Err(Error::Tool(crate::tool::Error::Io(_)))
+        ));
+    }
+
+    #[test]
+    fn test_levenshtein() {
+        assert_eq!(levenshtein("", ""), 0);
+        assert_eq!(levenshtein("abc", ""), 3);
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+        assert_eq!(levenshtein("find_file", "find_files"), 1);
+    }
+}
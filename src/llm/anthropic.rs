@@ -0,0 +1,152 @@
+use crate::{
+    llm::{
+        open_ai::{create_request_tool_parameters, RequestToolParameters, Role},
+        send_with_retry, set_headers, BoxLlm, Error, LlmClient, RetryConfig, ToolCall,
+    },
+    tool::ToolMeta,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{value::RawValue, Value};
+use std::collections::HashMap;
+use url::Url;
+
+const API_VERSION: &str = "2023-06-01";
+const DEFAULT_MAX_TOKENS: u32 = 1024;
+
+/// An Anthropic LLM API configuration.
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct AnthropicConfig {
+    pub base_url: Url,
+    pub headers: Option<HashMap<String, String>>,
+    pub key: String,
+    pub max_tokens: Option<u32>,
+    pub model: String,
+    #[serde(flatten)]
+    pub retry: RetryConfig,
+}
+
+/// An Anthropic API client.
+pub struct AnthropicClient {
+    config: AnthropicConfig,
+}
+
+impl AnthropicClient {
+    /// Creates a boxed Anthropic instance.
+    pub fn new_boxed(config: AnthropicConfig) -> BoxLlm {
+        Box::new(Self { config })
+    }
+}
+
+impl LlmClient for AnthropicClient {
+    fn derive_tool_calls(
+        &self,
+        tools: Vec<ToolMeta>,
+        query: String,
+    ) -> Result<Vec<ToolCall>, Error> {
+        let messages = vec![Message {
+            role: Role::User,
+            content: query,
+        }];
+        let request = MessagesRequestPayload {
+            model: self.config.model.clone(),
+            max_tokens: self.config.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            messages,
+            tools: create_request_tools(tools),
+        };
+
+        let mut url = self.config.base_url.clone();
+        url.set_path(&format!("{}/messages", url.path()));
+
+        let key = shellexpand::env(&self.config.key)?;
+
+        let (http_request, mut secrets) =
+            set_headers(ureq::post(url.as_str()), &self.config.headers)?;
+        let http_request = http_request
+            .set("x-api-key", &key)
+            .set("anthropic-version", API_VERSION);
+        secrets.push(key.into_owned());
+        let response: MessagesResponsePayload =
+            send_with_retry(&self.config.retry, http_request, &request, &secrets)?.into_json()?;
+
+        create_tool_calls(response)
+    }
+}
+
+#[derive(Serialize)]
+struct MessagesRequestPayload {
+    model: String,
+    max_tokens: u32,
+    messages: Vec<Message>,
+    tools: Vec<RequestTool>,
+}
+
+#[derive(Serialize)]
+struct Message {
+    role: Role,
+    content: String,
+}
+
+#[derive(Serialize)]
+struct RequestTool {
+    name: String,
+    description: Option<String>,
+    input_schema: RequestToolParameters,
+}
+
+#[derive(Deserialize)]
+struct MessagesResponsePayload {
+    content: Vec<ResponseContentBlock>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+enum ResponseContentBlock {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        name: String,
+        // Raw values can't be buffered by internally tagged enums.
+        input: Value,
+    },
+    #[serde(other)]
+    Other,
+}
+
+fn create_request_tools(tools: Vec<ToolMeta>) -> Vec<RequestTool> {
+    tools
+        .into_iter()
+        .map(|t| RequestTool {
+            name: t.name,
+            description: t.description,
+            input_schema: create_request_tool_parameters(t.params_schema),
+        })
+        .collect()
+}
+
+fn create_tool_calls(response: MessagesResponsePayload) -> Result<Vec<ToolCall>, Error> {
+    let mut text = String::new();
+    let mut calls = Vec::new();
+    for block in response.content {
+        use ResponseContentBlock::*;
+        match block {
+            Text { text: t } => text.push_str(&t),
+            ToolUse { name, input } => calls.push((name, input)),
+            Other => {}
+        }
+    }
+
+    let text = text.trim();
+    let reason = (!text.is_empty()).then(|| text.to_owned());
+    calls
+        .into_iter()
+        .map(|(name, input)| {
+            Ok(ToolCall {
+                tool: name,
+                params: RawValue::from_string(input.to_string())?,
+                reason: reason.clone(),
+            })
+        })
+        .collect()
+}
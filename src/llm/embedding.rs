@@ -0,0 +1,192 @@
+use crate::{
+    llm::{send_with_retry, set_headers, Error, RetryConfig},
+    tool::ToolMeta,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use url::Url;
+
+/// An API providing embeddings.
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EmbeddingApi {
+    Ollama,
+    OpenAi,
+}
+
+/// An embedding API configuration.
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct EmbeddingConfig {
+    pub api: EmbeddingApi,
+    pub base_url: Url,
+    pub headers: Option<HashMap<String, String>>,
+    /// An API key (no authorization is sent if empty).
+    #[serde(default)]
+    pub key: String,
+    pub model: String,
+    #[serde(flatten)]
+    pub retry: RetryConfig,
+}
+
+/// An embedding API client.
+pub trait EmbeddingClient {
+    /// Embeds given texts getting a vector per text.
+    fn embed(&self, inputs: Vec<String>) -> Result<Vec<Vec<f32>>, Error>;
+}
+
+/// An owned dynamically typed embedding API client.
+pub type BoxEmbedding = Box<dyn EmbeddingClient>;
+
+/// Creates an embedding API client.
+pub fn create_embedding_client(config: &EmbeddingConfig) -> BoxEmbedding {
+    Box::new(HttpEmbeddingClient {
+        config: config.clone(),
+    })
+}
+
+/// Selects up to `k` tools most similar to a given query by their names and descriptions.
+pub fn preselect_tools(
+    client: &dyn EmbeddingClient,
+    tools: &[ToolMeta],
+    query: &str,
+    k: usize,
+) -> Result<Vec<ToolMeta>, Error> {
+    let mut inputs = vec![query.to_owned()];
+    inputs.extend(tools.iter().map(|t| match &t.description {
+        Some(desc) => format!("{}: {desc}", t.name),
+        None => t.name.clone(),
+    }));
+
+    let embeddings = client.embed(inputs)?;
+    if embeddings.len() != tools.len() + 1 {
+        return Err(Error::MalformedResponse("unexpected number of embeddings"));
+    }
+
+    let query = &embeddings[0];
+    let mut similarities: Vec<_> = embeddings[1..]
+        .iter()
+        .map(|e| cosine_similarity(query, e))
+        .zip(tools)
+        .collect();
+    similarities.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    Ok(similarities
+        .into_iter()
+        .take(k)
+        .map(|(_, t)| t.clone())
+        .collect())
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+struct HttpEmbeddingClient {
+    config: EmbeddingConfig,
+}
+
+impl EmbeddingClient for HttpEmbeddingClient {
+    fn embed(&self, inputs: Vec<String>) -> Result<Vec<Vec<f32>>, Error> {
+        let request = EmbedRequestPayload {
+            model: self.config.model.clone(),
+            input: inputs,
+        };
+
+        let mut url = self.config.base_url.clone();
+        match self.config.api {
+            EmbeddingApi::Ollama => url.set_path(&format!("{}api/embed", url.path())),
+            EmbeddingApi::OpenAi => url.set_path(&format!("{}/embeddings", url.path())),
+        }
+
+        let key = shellexpand::env(&self.config.key)?;
+        let (mut http_request, mut secrets) =
+            set_headers(ureq::post(url.as_str()), &self.config.headers)?;
+        if !key.is_empty() {
+            http_request = http_request.set("Authorization", &format!("Bearer {key}"));
+        }
+        secrets.push(key.into_owned());
+        let response = send_with_retry(&self.config.retry, http_request, &request, &secrets)?;
+
+        Ok(match self.config.api {
+            EmbeddingApi::Ollama => {
+                let response: OllamaEmbedResponsePayload = response.into_json()?;
+                response.embeddings
+            }
+            EmbeddingApi::OpenAi => {
+                let mut response: OpenAiEmbedResponsePayload = response.into_json()?;
+                response.data.sort_by_key(|d| d.index);
+                response.data.into_iter().map(|d| d.embedding).collect()
+            }
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct EmbedRequestPayload {
+    model: String,
+    input: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct OllamaEmbedResponsePayload {
+    embeddings: Vec<Vec<f32>>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiEmbedResponsePayload {
+    data: Vec<OpenAiEmbedding>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiEmbedding {
+    embedding: Vec<f32>,
+    index: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use schemars::schema_for;
+
+    struct FakeEmbeddingClient;
+
+    impl EmbeddingClient for FakeEmbeddingClient {
+        fn embed(&self, inputs: Vec<String>) -> Result<Vec<Vec<f32>>, Error> {
+            // Embeds texts by the presence of two keywords.
+            Ok(inputs
+                .iter()
+                .map(|i| {
+                    let file = if i.contains("file") { 1.0 } else { 0.0 };
+                    let process = if i.contains("process") { 1.0 } else { 0.0 };
+                    vec![file, process]
+                })
+                .collect())
+        }
+    }
+
+    fn meta(name: &str) -> ToolMeta {
+        ToolMeta {
+            name: name.to_owned(),
+            description: None,
+            destructive: false,
+            params_schema: schema_for!(()),
+            output_schema: schema_for!(()),
+        }
+    }
+
+    #[test]
+    fn test_preselect_tools() {
+        let tools = [meta("find_processes"), meta("get_env"), meta("find_files")];
+        let selected = preselect_tools(&FakeEmbeddingClient, &tools, "show big files", 2).unwrap();
+        let names: Vec<_> = selected.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names.len(), 2);
+        assert_eq!(names[0], "find_files");
+    }
+}
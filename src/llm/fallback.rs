@@ -0,0 +1,127 @@
+use crate::{
+    llm::{BoxLlm, ContentCallback, Error, LlmApi, LlmClient, ToolCall},
+    tool::ToolMeta,
+    util::fmt::ErrorChainDisplay,
+};
+use log::{info, warn};
+
+/// A client trying LLM APIs in order until one derives a tool call.
+pub struct FallbackClient {
+    clients: Vec<(LlmApi, BoxLlm)>,
+}
+
+impl FallbackClient {
+    /// Creates a boxed FallbackClient instance from clients in order of preference.
+    pub fn new_boxed(clients: Vec<(LlmApi, BoxLlm)>) -> BoxLlm {
+        Box::new(Self { clients })
+    }
+
+    /// Calls clients in order until one succeeds with a non-empty result.
+    ///
+    /// An empty result of the last client is returned as is, an error of the last client
+    /// is returned if all the clients fail.
+    fn try_in_order<T>(
+        &self,
+        derive: impl Fn(&BoxLlm) -> Result<T, Error>,
+        is_empty: impl Fn(&T) -> bool,
+    ) -> Result<T, Error> {
+        let mut last_result = None;
+        for (api, client) in &self.clients {
+            match derive(client) {
+                Ok(result) if !is_empty(&result) => {
+                    info!("derived using {api:?} api");
+                    return Ok(result);
+                }
+                Ok(result) => {
+                    warn!("no tool call derived using {api:?} api");
+                    last_result = Some(Ok(result));
+                }
+                Err(err) => {
+                    warn!(
+                        "failed to derive using {api:?} api: {}",
+                        ErrorChainDisplay(&err)
+                    );
+                    last_result = Some(Err(err));
+                }
+            }
+        }
+        last_result.unwrap_or(Err(Error::MalformedConfig("no llm apis")))
+    }
+}
+
+impl LlmClient for FallbackClient {
+    fn derive_tool_calls(
+        &self,
+        tools: Vec<ToolMeta>,
+        query: String,
+    ) -> Result<Vec<ToolCall>, Error> {
+        self.try_in_order(
+            |c| c.derive_tool_calls(tools.clone(), query.clone()),
+            Vec::is_empty,
+        )
+    }
+
+    fn derive_tool_call(
+        &self,
+        tools: Vec<ToolMeta>,
+        query: String,
+    ) -> Result<Option<ToolCall>, Error> {
+        self.try_in_order(
+            |c| c.derive_tool_call(tools.clone(), query.clone()),
+            Option::is_none,
+        )
+    }
+
+    fn set_content_callback(&mut self, callback: ContentCallback) {
+        for (_, client) in &mut self.clients {
+            client.set_content_callback(callback.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::value::RawValue;
+
+    /// A client failing, deriving nothing or deriving a call of its tool.
+    struct FakeClient(Option<Option<&'static str>>);
+
+    impl LlmClient for FakeClient {
+        fn derive_tool_calls(
+            &self,
+            _tools: Vec<ToolMeta>,
+            _query: String,
+        ) -> Result<Vec<ToolCall>, Error> {
+            match self.0 {
+                None => Err(Error::MalformedConfig("fake")),
+                Some(tool) => Ok(tool
+                    .map(|tool| ToolCall {
+                        tool: tool.to_owned(),
+                        params: RawValue::from_string("{}".to_owned()).unwrap(),
+                        reason: None,
+                    })
+                    .into_iter()
+                    .collect()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_fallback_client() {
+        let client = FallbackClient::new_boxed(vec![
+            (LlmApi::OpenAi, Box::new(FakeClient(None))),
+            (LlmApi::Anthropic, Box::new(FakeClient(Some(None)))),
+            (LlmApi::Ollama, Box::new(FakeClient(Some(Some("get_env"))))),
+        ]);
+        let call = client.derive_tool_call(vec![], "query".to_owned()).unwrap();
+        assert_eq!(call.unwrap().tool, "get_env");
+
+        let client = FallbackClient::new_boxed(vec![
+            (LlmApi::Anthropic, Box::new(FakeClient(Some(None)))),
+            (LlmApi::OpenAi, Box::new(FakeClient(None))),
+        ]);
+        let err = client.derive_tool_calls(vec![], "query".to_owned());
+        assert!(matches!(err, Err(Error::MalformedConfig("fake"))));
+    }
+}
@@ -0,0 +1,177 @@
+use crate::{
+    llm::{
+        open_ai::{create_request_tool_parameters, RequestToolParameters},
+        send_with_retry, set_headers, BoxLlm, Error, LlmClient, RetryConfig, ToolCall,
+    },
+    tool::ToolMeta,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{value::RawValue, Value};
+use std::collections::HashMap;
+use url::Url;
+
+const DEFAULT_BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
+
+/// A Gemini LLM API configuration.
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct GeminiConfig {
+    pub api_key: String,
+    pub base_url: Option<Url>,
+    pub headers: Option<HashMap<String, String>>,
+    pub model: String,
+    #[serde(flatten)]
+    pub retry: RetryConfig,
+}
+
+/// A Gemini API client.
+pub struct GeminiClient {
+    config: GeminiConfig,
+}
+
+impl GeminiClient {
+    /// Creates a boxed Gemini instance.
+    pub fn new_boxed(config: GeminiConfig) -> BoxLlm {
+        Box::new(Self { config })
+    }
+}
+
+impl LlmClient for GeminiClient {
+    fn derive_tool_calls(
+        &self,
+        tools: Vec<ToolMeta>,
+        query: String,
+    ) -> Result<Vec<ToolCall>, Error> {
+        let contents = vec![Content {
+            role: "user".to_owned(),
+            parts: vec![Part {
+                text: Some(query),
+                function_call: None,
+            }],
+        }];
+        let request = GenerateContentRequestPayload {
+            contents,
+            tools: vec![RequestTool {
+                function_declarations: create_function_declarations(tools),
+            }],
+        };
+
+        let mut url = match &self.config.base_url {
+            Some(base_url) => base_url.clone(),
+            None => Url::parse(DEFAULT_BASE_URL).unwrap(),
+        };
+        url.set_path(&format!(
+            "{}/models/{}:generateContent",
+            url.path(),
+            self.config.model
+        ));
+
+        let key = shellexpand::env(&self.config.api_key)?;
+
+        let (http_request, mut secrets) =
+            set_headers(ureq::post(url.as_str()), &self.config.headers)?;
+        let http_request = http_request.set("x-goog-api-key", &key);
+        secrets.push(key.into_owned());
+        let response: GenerateContentResponsePayload =
+            send_with_retry(&self.config.retry, http_request, &request, &secrets)?.into_json()?;
+
+        create_tool_calls(response)
+    }
+}
+
+#[derive(Serialize)]
+struct GenerateContentRequestPayload {
+    contents: Vec<Content>,
+    tools: Vec<RequestTool>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Content {
+    #[serde(default)]
+    role: String,
+    #[serde(default)]
+    parts: Vec<Part>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Part {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    function_call: Option<FunctionCall>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct FunctionCall {
+    name: String,
+    #[serde(default)]
+    args: Value,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RequestTool {
+    function_declarations: Vec<FunctionDeclaration>,
+}
+
+#[derive(Serialize)]
+struct FunctionDeclaration {
+    name: String,
+    description: Option<String>,
+    parameters: RequestToolParameters,
+}
+
+#[derive(Deserialize)]
+struct GenerateContentResponsePayload {
+    #[serde(default)]
+    candidates: Vec<Candidate>,
+}
+
+#[derive(Deserialize)]
+struct Candidate {
+    content: Content,
+}
+
+fn create_function_declarations(tools: Vec<ToolMeta>) -> Vec<FunctionDeclaration> {
+    tools
+        .into_iter()
+        .map(|t| FunctionDeclaration {
+            name: t.name,
+            description: t.description,
+            parameters: create_request_tool_parameters(t.params_schema),
+        })
+        .collect()
+}
+
+fn create_tool_calls(mut response: GenerateContentResponsePayload) -> Result<Vec<ToolCall>, Error> {
+    if response.candidates.is_empty() {
+        return Ok(Vec::new());
+    }
+    let candidate = response.candidates.remove(0);
+
+    let mut text = String::new();
+    let mut calls = Vec::new();
+    for part in candidate.content.parts {
+        text.push_str(part.text.as_deref().unwrap_or_default());
+        calls.extend(part.function_call);
+    }
+
+    let text = text.trim();
+    let reason = (!text.is_empty()).then(|| text.to_owned());
+    calls
+        .into_iter()
+        .map(|call| {
+            // Calls without arguments may omit them altogether.
+            let args = match call.args {
+                Value::Null => Value::Object(Default::default()),
+                args => args,
+            };
+            Ok(ToolCall {
+                tool: call.name,
+                params: RawValue::from_string(args.to_string())?,
+                reason: reason.clone(),
+            })
+        })
+        .collect()
+}
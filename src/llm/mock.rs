@@ -0,0 +1,52 @@
+use crate::{
+    llm::{BoxLlm, Error, LlmClient, ToolCall},
+    tool::ToolMeta,
+};
+use log::debug;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::value::RawValue;
+
+/// A mock LLM API configuration with a canned tool call.
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct MockConfig {
+    #[serde(default)]
+    pub params: serde_json::Map<String, serde_json::Value>,
+    /// A substring a query must contain to derive the call (any query if unset).
+    pub query_contains: Option<String>,
+    pub tool: String,
+}
+
+/// A mock LLM API client deriving a canned tool call without network.
+pub struct MockClient {
+    config: MockConfig,
+}
+
+impl MockClient {
+    /// Creates a boxed MockClient instance.
+    pub fn new_boxed(config: MockConfig) -> BoxLlm {
+        Box::new(Self { config })
+    }
+}
+
+impl LlmClient for MockClient {
+    fn derive_tool_calls(
+        &self,
+        _tools: Vec<ToolMeta>,
+        query: String,
+    ) -> Result<Vec<ToolCall>, Error> {
+        if let Some(substring) = &self.config.query_contains {
+            if !query.contains(substring.as_str()) {
+                debug!("query doesn't contain '{substring}'");
+                return Ok(vec![]);
+            }
+        }
+
+        let params = serde_json::Value::Object(self.config.params.clone()).to_string();
+        Ok(vec![ToolCall {
+            tool: self.config.tool.clone(),
+            params: RawValue::from_string(params).unwrap(),
+            reason: None,
+        }])
+    }
+}
@@ -1,36 +1,147 @@
+pub mod anthropic;
+pub mod embedding;
+pub mod fallback;
+pub mod gemini;
+pub mod mock;
 pub mod ollama;
 pub mod open_ai;
 
 use crate::{
     llm::{
+        anthropic::{AnthropicClient, AnthropicConfig},
+        embedding::EmbeddingConfig,
+        fallback::FallbackClient,
+        gemini::{GeminiClient, GeminiConfig},
+        mock::{MockClient, MockConfig},
         ollama::{OllamaClient, OllamaConfig},
         open_ai::{OpenAiClient, OpenAiConfig},
     },
     tool::ToolMeta,
+    util::fmt::{redact, ErrorChainDisplay, RedactedError},
 };
-use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, FixedOffset, Local, NaiveTime, Offset, TimeDelta, TimeZone};
+use log::{debug, warn};
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::value::RawValue;
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    path::PathBuf,
+    sync::Arc,
+    thread::sleep,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use strfmt::strfmt;
 use sys_locale::get_locale;
 
-#[derive(Clone, Copy, Debug, Deserialize)]
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum LlmApi {
+    Anthropic,
+    Gemini,
+    /// A canned tool call for testing and offline use.
+    Mock,
     Ollama,
     OpenAi,
 }
 
+impl LlmApi {
+    /// All the supported LLM APIs.
+    pub const ALL: [LlmApi; 5] = [
+        Self::Anthropic,
+        Self::Gemini,
+        Self::Mock,
+        Self::Ollama,
+        Self::OpenAi,
+    ];
+}
+
 /// An LLM API configuration.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 pub struct LlmConfig {
     pub api: LlmApi,
+    pub anthropic: Option<AnthropicConfig>,
+    /// Context fields included into queries (all if unset).
+    pub context_fields: Option<Vec<String>>,
+    /// An embedding API used to preselect tools.
+    pub embedding: Option<EmbeddingConfig>,
+    /// APIs tried in order if the chosen one fails or derives no tool call.
+    #[serde(default)]
+    pub fallback_apis: Vec<LlmApi>,
+    pub gemini: Option<GeminiConfig>,
+    pub mock: Option<MockConfig>,
     pub ollama: Option<OllamaConfig>,
     pub open_ai: Option<OpenAiConfig>,
+    #[serde(
+        default = "default_query_fmt",
+        deserialize_with = "deserialize_query_fmt"
+    )]
     pub query_fmt: String,
 }
 
+impl LlmConfig {
+    /// Gets the model of the chosen API if its section is present.
+    pub fn model(&self) -> Option<&str> {
+        use LlmApi::*;
+        match self.api {
+            Anthropic => self.anthropic.as_ref().map(|c| c.model.as_str()),
+            Gemini => self.gemini.as_ref().map(|c| c.model.as_str()),
+            Mock => None,
+            Ollama => self.ollama.as_ref().map(|c| c.model.as_str()),
+            OpenAi => self.open_ai.as_ref().map(|c| c.model.as_str()),
+        }
+    }
+
+    /// Overrides the model of the chosen API if its section is present.
+    pub fn set_model(&mut self, model: String) {
+        use LlmApi::*;
+        let current = match self.api {
+            Anthropic => self.anthropic.as_mut().map(|c| &mut c.model),
+            Gemini => self.gemini.as_mut().map(|c| &mut c.model),
+            Mock => None,
+            Ollama => self.ollama.as_mut().map(|c| &mut c.model),
+            OpenAi => self.open_ai.as_mut().map(|c| &mut c.model),
+        };
+        if let Some(current) = current {
+            *current = model;
+        }
+    }
+}
+
+/// Named query format templates selectable with the `@name` syntax.
+pub const QUERY_FMT_TEMPLATES: &[(&str, &str)] = &[
+    (
+        "default",
+        "Using the context {context} derive a tool call for the following query. {query}",
+    ),
+    ("concise", "Context: {context}. Query: {query}"),
+    (
+        "verbose",
+        "You are given the context {context} describing the user environment. \
+        Choose the most appropriate tool and derive a call to it with all the parameters \
+        needed to satisfy the following query. Resolve relative paths and times \
+        using the context. {query}",
+    ),
+];
+
+fn default_query_fmt() -> String {
+    QUERY_FMT_TEMPLATES[0].1.to_owned()
+}
+
+fn deserialize_query_fmt<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let query_fmt = String::deserialize(deserializer)?;
+    let Some(name) = query_fmt.strip_prefix('@') else {
+        return Ok(query_fmt);
+    };
+
+    QUERY_FMT_TEMPLATES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, t)| (*t).to_owned())
+        .ok_or_else(|| serde::de::Error::custom(format!("unknown query_fmt template '{name}'")))
+}
+
 /// An LLM API error.
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -42,6 +153,22 @@ pub enum Error {
     ),
     #[error("malformed config: {0}")]
     MalformedConfig(&'static str),
+    #[error("malformed response: {0}")]
+    MalformedResponse(&'static str),
+    #[error("malformed tool call arguments")]
+    MalformedToolCall(#[source] serde_json::Error),
+    #[error("malformed query format")]
+    QueryFormat(
+        #[from]
+        #[source]
+        strfmt::FmtError,
+    ),
+    #[error("request failed")]
+    RedactedRequest(
+        #[from]
+        #[source]
+        RedactedError<Box<ureq::Error>>,
+    ),
     #[error("failed to (de)serialize json")]
     SerdeJson(
         #[from]
@@ -73,25 +200,84 @@ impl From<ureq::Error> for Error {
 pub struct ToolCall {
     pub tool: String,
     pub params: Box<RawValue>,
+    /// A model-stated justification for the call, if supplied.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
 }
 
+/// A callback receiving assistant content as it arrives.
+///
+/// Only the Ollama and OpenAI clients (including fallbacks to them) call it.
+pub type ContentCallback = Arc<dyn Fn(&str) + Send + Sync>;
+
 /// An LLM API client.
 pub trait LlmClient {
-    /// Derives a tool call from a given query.
+    /// Derives all tool calls proposed for a given query (none if empty).
+    fn derive_tool_calls(
+        &self,
+        tools: Vec<ToolMeta>,
+        query: String,
+    ) -> Result<Vec<ToolCall>, Error>;
+
+    /// Derives a tool call from a given query unless none or multiple calls are proposed.
     fn derive_tool_call(
         &self,
         tools: Vec<ToolMeta>,
         query: String,
-    ) -> Result<Option<ToolCall>, Error>;
+    ) -> Result<Option<ToolCall>, Error> {
+        let mut calls = self.derive_tool_calls(tools, query)?;
+        Ok(if calls.len() == 1 { calls.pop() } else { None })
+    }
+
+    /// Sets a callback receiving assistant content pieces (ignored unless Ollama or OpenAI).
+    fn set_content_callback(&mut self, _callback: ContentCallback) {}
 }
 
 /// An owned dynamically typed LLM API client.
 pub type BoxLlm = Box<dyn LlmClient>;
 
 /// Creates an LLM API client.
+///
+/// If fallback APIs are configured, the client tries them in order after the chosen one.
 pub fn create_llm_client(config: &LlmConfig) -> Result<BoxLlm, Error> {
+    if let Some(model) = config.model() {
+        debug!("using {:?} model '{model}'", config.api);
+    }
+
+    let client = create_api_client(config, config.api)?;
+    if config.fallback_apis.is_empty() {
+        return Ok(client);
+    }
+
+    let mut clients = vec![(config.api, client)];
+    for &api in &config.fallback_apis {
+        clients.push((api, create_api_client(config, api)?));
+    }
+    Ok(FallbackClient::new_boxed(clients))
+}
+
+/// Creates a client of a given LLM API.
+fn create_api_client(config: &LlmConfig, api: LlmApi) -> Result<BoxLlm, Error> {
     use LlmApi::*;
-    Ok(match config.api {
+    Ok(match api {
+        Anthropic => {
+            let Some(config) = &config.anthropic else {
+                return Err(Error::MalformedConfig("missing anthropic config"));
+            };
+            AnthropicClient::new_boxed(config.clone())
+        }
+        Gemini => {
+            let Some(config) = &config.gemini else {
+                return Err(Error::MalformedConfig("missing gemini config"));
+            };
+            GeminiClient::new_boxed(config.clone())
+        }
+        Mock => {
+            let Some(config) = &config.mock else {
+                return Err(Error::MalformedConfig("missing mock config"));
+            };
+            MockClient::new_boxed(config.clone())
+        }
         Ollama => {
             let Some(config) = &config.ollama else {
                 return Err(Error::MalformedConfig("missing ollama config"));
@@ -107,34 +293,288 @@ pub fn create_llm_client(config: &LlmConfig) -> Result<BoxLlm, Error> {
     })
 }
 
+/// Attaches custom headers to a request expanding environment variables in their values.
+///
+/// Returns the values changed by expansion as secrets to be redacted from request errors,
+/// literal values like API versions are left as is.
+pub(in crate::llm) fn set_headers(
+    mut request: ureq::Request,
+    headers: &Option<HashMap<String, String>>,
+) -> Result<(ureq::Request, Vec<String>), Error> {
+    let mut secrets = Vec::new();
+    for (name, value) in headers.iter().flatten() {
+        let expanded = shellexpand::env(value)?;
+        request = request.set(name, &expanded);
+        if expanded != value.as_str() {
+            secrets.push(expanded.into_owned());
+        }
+    }
+    Ok((request, secrets))
+}
+
+const DEFAULT_MAX_RETRIES: u32 = 2;
+const DEFAULT_RETRY_BASE_MS: u64 = 500;
+
+/// Retry options for transient LLM API errors.
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+pub struct RetryConfig {
+    pub max_retries: Option<u32>,
+    pub retry_base_ms: Option<u64>,
+}
+
+/// Sends a JSON request retrying on timeouts, 429 and 5xx statuses with exponential backoff.
+///
+/// Errors containing any of given secrets are converted to redacted ones.
+pub(in crate::llm) fn send_with_retry(
+    retry: &RetryConfig,
+    request: ureq::Request,
+    data: impl Serialize,
+    secrets: &[String],
+) -> Result<ureq::Response, Error> {
+    let secrets: Vec<_> = secrets.iter().map(String::as_str).collect();
+    let max_retries = retry.max_retries.unwrap_or(DEFAULT_MAX_RETRIES);
+    let base_ms = retry.retry_base_ms.unwrap_or(DEFAULT_RETRY_BASE_MS);
+
+    let mut attempt = 0;
+    loop {
+        let err = match request.clone().send_json(&data) {
+            Ok(response) => return Ok(response),
+            Err(err) => err,
+        };
+        if attempt >= max_retries || !is_retryable(&err) {
+            let chain = ErrorChainDisplay(&err).to_string();
+            let redacted = RedactedError::new(Box::new(err), &secrets);
+            return Err(if redacted.to_string() == chain {
+                Error::Ureq(redacted.into_inner())
+            } else {
+                redacted.into()
+            });
+        }
+
+        let delay_ms = base_ms.saturating_mul(1 << attempt.min(16));
+        let delay_ms = delay_ms.saturating_add(jitter_ms(delay_ms / 2));
+        attempt += 1;
+        warn!(
+            "retrying llm request ({attempt}/{max_retries}) in {delay_ms}ms: {}",
+            redact(&err.to_string(), &secrets)
+        );
+        sleep(Duration::from_millis(delay_ms));
+    }
+}
+
+fn is_retryable(err: &ureq::Error) -> bool {
+    match err {
+        ureq::Error::Status(status, _) => *status == 429 || *status >= 500,
+        ureq::Error::Transport(transport) => {
+            use std::error::Error;
+            transport
+                .source()
+                .and_then(|e| e.downcast_ref::<std::io::Error>())
+                .is_some_and(|e| matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock))
+        }
+    }
+}
+
+/// Returns a pseudo-random delay in `0..=max_ms` good enough to spread retries.
+fn jitter_ms(max_ms: u64) -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or_default();
+    nanos % (max_ms + 1)
+}
+
 /// An LLM query context.
 #[derive(Serialize)]
 pub struct Context {
+    current_dir: Option<PathBuf>,
+    hostname: Option<String>,
     os_name: &'static str,
+    shell: Option<String>,
     system_locale: String,
-    time_now: DateTime<Utc>,
+    /// Precomputed times to copy into time params instead of deriving them from `time_now`.
+    time_anchors: TimeAnchors,
+    /// An RFC 3339 timestamp with the local offset, e.g. `2025-01-31T23:05:00.123+02:00`.
+    time_now: DateTime<FixedOffset>,
     username: String,
 }
 
+/// Local times relative to now serialized as RFC 3339 timestamps.
+#[derive(Serialize)]
+struct TimeAnchors {
+    one_hour_ago: DateTime<FixedOffset>,
+    one_week_ago: DateTime<FixedOffset>,
+    start_of_today: DateTime<FixedOffset>,
+    start_of_yesterday: DateTime<FixedOffset>,
+}
+
+impl TimeAnchors {
+    fn new(now: DateTime<Local>) -> Self {
+        let start_of_day = |days_ago| {
+            let date = now.date_naive() - TimeDelta::days(days_ago);
+            let midnight = date.and_time(NaiveTime::MIN);
+            // Midnight may be skipped by a DST transition, in which case the day starts later.
+            Local
+                .from_local_datetime(&midnight)
+                .earliest()
+                .unwrap_or_else(|| Local.from_utc_datetime(&(midnight - now.offset().fix())))
+                .fixed_offset()
+        };
+        Self {
+            one_hour_ago: (now - TimeDelta::hours(1)).fixed_offset(),
+            one_week_ago: (now - TimeDelta::weeks(1)).fixed_offset(),
+            start_of_today: start_of_day(0),
+            start_of_yesterday: start_of_day(1),
+        }
+    }
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Context {
     /// Creates a default Context instance.
     pub fn new() -> Context {
+        let now = Local::now();
         Context {
+            current_dir: std::env::current_dir().ok(),
+            hostname: whoami::fallible::hostname().ok(),
             os_name: std::env::consts::OS,
+            shell: std::env::var("SHELL")
+                .or_else(|_| std::env::var("COMSPEC"))
+                .ok(),
             system_locale: get_locale().unwrap_or("en-US".to_owned()),
-            time_now: Utc::now(),
+            time_anchors: TimeAnchors::new(now),
+            time_now: now.fixed_offset(),
             username: whoami::username(),
         }
     }
 
     /// Contextualizes a given LLM query.
-    pub fn contextualize(&self, config: &LlmConfig, query: String) -> String {
-        let context = serde_json::to_string(&Self::new()).unwrap();
+    pub fn contextualize(&self, config: &LlmConfig, query: String) -> Result<String, Error> {
+        let serde_json::Value::Object(mut context) = serde_json::to_value(self)? else {
+            unreachable!("context is serialized as an object");
+        };
+
+        if let Some(fields) = &config.context_fields {
+            if fields.iter().any(|f| !context.contains_key(f)) {
+                return Err(Error::MalformedConfig("unknown context field"));
+            }
+            context.retain(|k, _| fields.contains(k));
+        }
+        let context = serde_json::to_string(&context)?;
 
         let mut vars = HashMap::new();
         vars.insert("context".to_owned(), context);
         vars.insert("query".to_owned(), query);
 
-        strfmt(&config.query_fmt, &vars).unwrap()
+        Ok(strfmt(&config.query_fmt, &vars)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_fmt_resolution() {
+        let config: LlmConfig = toml::from_str(r#"api = "ollama""#).unwrap();
+        assert_eq!(config.query_fmt, QUERY_FMT_TEMPLATES[0].1);
+
+        let config: LlmConfig =
+            toml::from_str("api = \"ollama\"\nquery_fmt = \"@concise\"").unwrap();
+        assert_eq!(config.query_fmt, "Context: {context}. Query: {query}");
+
+        let config: LlmConfig =
+            toml::from_str("api = \"ollama\"\nquery_fmt = \"{query}\"").unwrap();
+        assert_eq!(config.query_fmt, "{query}");
+
+        assert!(toml::from_str::<LlmConfig>("api = \"ollama\"\nquery_fmt = \"@foo\"").is_err());
+    }
+
+    #[test]
+    fn test_context_fields() {
+        let context = serde_json::to_value(Context::new()).unwrap();
+        for key in ["current_dir", "hostname", "os_name", "shell", "username"] {
+            assert!(context.get(key).is_some(), "missing {key}");
+        }
+
+        let time_now = context["time_now"].as_str().unwrap();
+        assert!(DateTime::parse_from_rfc3339(time_now).is_ok());
+    }
+
+    #[test]
+    fn test_time_anchors() {
+        let now = Local::now();
+        let anchors = TimeAnchors::new(now);
+        assert!(anchors.start_of_yesterday < anchors.start_of_today);
+        assert!(anchors.start_of_today <= now);
+        assert!(anchors.one_week_ago < anchors.one_hour_ago);
+        assert_eq!(anchors.start_of_today.date_naive(), now.date_naive());
+
+        let context = serde_json::to_value(Context::new()).unwrap();
+        let start_of_today = context["time_anchors"]["start_of_today"].as_str().unwrap();
+        assert!(DateTime::parse_from_rfc3339(start_of_today).is_ok());
+    }
+
+    #[test]
+    fn test_context_field_selection() {
+        let config: LlmConfig = toml::from_str(
+            "api = \"ollama\"\ncontext_fields = [\"os_name\"]\nquery_fmt = \"{context}\"",
+        )
+        .unwrap();
+        let query = Context::new()
+            .contextualize(&config, String::new())
+            .unwrap();
+        let context: serde_json::Value = serde_json::from_str(&query).unwrap();
+        assert_eq!(
+            context,
+            serde_json::json!({"os_name": std::env::consts::OS})
+        );
+
+        let config: LlmConfig =
+            toml::from_str("api = \"ollama\"\ncontext_fields = [\"foo\"]").unwrap();
+        assert!(matches!(
+            Context::new().contextualize(&config, String::new()),
+            Err(Error::MalformedConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_malformed_query_fmt() {
+        for query_fmt in ["{query", "{user} {query}"] {
+            let config = LlmConfig {
+                query_fmt: query_fmt.to_owned(),
+                ..toml::from_str(r#"api = "ollama""#).unwrap()
+            };
+            assert!(matches!(
+                Context::new().contextualize(&config, "find files".to_owned()),
+                Err(Error::QueryFormat(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_set_headers_secrets() {
+        let headers = HashMap::from([
+            ("anthropic-version".to_owned(), "2023-06-01".to_owned()),
+            ("x-api-key".to_owned(), "${PATH}".to_owned()),
+        ]);
+        let (_, secrets) = set_headers(ureq::post("http://localhost"), &Some(headers)).unwrap();
+        assert_eq!(secrets, [std::env::var("PATH").unwrap()]);
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        let status = |code| ureq::Error::Status(code, ureq::Response::new(code, "", "").unwrap());
+        assert!(is_retryable(&status(429)));
+        assert!(is_retryable(&status(500)));
+        assert!(is_retryable(&status(503)));
+        assert!(!is_retryable(&status(400)));
+        assert!(!is_retryable(&status(401)));
+        assert!(!is_retryable(&status(404)));
     }
 }
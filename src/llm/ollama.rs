@@ -1,25 +1,49 @@
 use crate::{
     llm::{
         open_ai::{create_request_tools, RequestTool, Role},
-        BoxLlm, Error, LlmClient, ToolCall,
+        send_with_retry, set_headers, BoxLlm, ContentCallback, Error, LlmClient, RetryConfig,
+        ToolCall,
     },
     tool::ToolMeta,
 };
+use log::{debug, info};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::value::RawValue;
+use std::{
+    collections::HashMap,
+    io::{BufReader, ErrorKind, Read},
+};
 use url::Url;
 
 /// An Ollama LLM API configuration.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
 pub struct OllamaConfig {
     pub base_url: Url,
+    pub headers: Option<HashMap<String, String>>,
+    /// How long the model stays loaded after a request, e.g. "5m" or -1 for indefinitely.
+    pub keep_alive: Option<KeepAlive>,
     pub model: String,
     #[serde(flatten)]
     pub options: OllamaOptions,
+    /// Stream the response in chunks instead of a single object.
+    pub stream: Option<bool>,
+    #[serde(flatten)]
+    pub retry: RetryConfig,
+}
+
+/// A duration a model stays loaded for.
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
+#[serde(untagged)]
+pub enum KeepAlive {
+    /// A duration string with a unit, e.g. "5m" or "1h".
+    Duration(String),
+    /// A number of seconds, a negative one keeps the model loaded indefinitely.
+    Seconds(i64),
 }
 
 /// Ollama request options.
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
 pub struct OllamaOptions {
     frequency_penalty: Option<f32>,
     low_vram: Option<bool>,
@@ -53,21 +77,25 @@ pub struct OllamaOptions {
 /// An Ollama API client.
 pub struct OllamaClient {
     config: OllamaConfig,
+    on_content: Option<ContentCallback>,
 }
 
 impl OllamaClient {
     /// Creates a boxed Ollama instance.
     pub fn new_boxed(config: OllamaConfig) -> BoxLlm {
-        Box::new(Self { config })
+        Box::new(Self {
+            config,
+            on_content: None,
+        })
     }
 }
 
 impl LlmClient for OllamaClient {
-    fn derive_tool_call(
+    fn derive_tool_calls(
         &self,
         tools: Vec<ToolMeta>,
         query: String,
-    ) -> Result<Option<ToolCall>, Error> {
+    ) -> Result<Vec<ToolCall>, Error> {
         let messages = vec![Message {
             role: Role::User,
             content: query,
@@ -76,7 +104,8 @@ impl LlmClient for OllamaClient {
         let request = ChatRequestPayload {
             model: self.config.model.clone(),
             messages,
-            stream: false,
+            keep_alive: self.config.keep_alive.clone(),
+            stream: self.config.stream.unwrap_or_default(),
             tools: create_request_tools(tools),
             options: self.config.options.clone(),
         };
@@ -84,30 +113,81 @@ impl LlmClient for OllamaClient {
         let mut url = self.config.base_url.clone();
         url.set_path(&format!("{}api/chat", url.path()));
 
-        let response: ChatResponsePayload =
-            ureq::post(url.as_str()).send_json(request)?.into_json()?;
+        let (http_request, secrets) = set_headers(ureq::post(url.as_str()), &self.config.headers)?;
+        let reader =
+            send_with_retry(&self.config.retry, http_request, &request, &secrets)?.into_reader();
+        let response = parse_response(BufReader::new(reader), self.on_content.as_ref())?;
+
+        if let (Some(prompt_eval_count), Some(eval_count)) =
+            (response.prompt_eval_count, response.eval_count)
+        {
+            info!("used {prompt_eval_count} prompt, {eval_count} completion tokens");
+        }
 
-        Ok(create_tool_call(response))
+        Ok(create_tool_calls(response))
+    }
+
+    fn set_content_callback(&mut self, callback: ContentCallback) {
+        self.on_content = Some(callback);
     }
 }
 
-fn create_tool_call(response: ChatResponsePayload) -> Option<ToolCall> {
-    let mut calls = response.message.tool_calls;
-    if calls.len() == 1 {
-        let call = calls.remove(0);
-        Some(ToolCall {
+/// Incrementally parses a chat response as a sequence of newline-delimited chunks.
+///
+/// A non-streamed response is just a single chunk, though some servers send chunks anyway.
+fn parse_response(
+    reader: impl Read,
+    on_content: Option<&ContentCallback>,
+) -> Result<ChatResponsePayload, Error> {
+    let mut chunks =
+        serde_json::Deserializer::from_reader(reader).into_iter::<ChatResponsePayload>();
+    let mut response = match chunks.next() {
+        Some(response) => response?,
+        None => return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+    };
+    let notify = |content: &str| {
+        if let Some(on_content) = on_content.filter(|_| !content.is_empty()) {
+            on_content(content);
+        }
+    };
+    notify(&response.message.content);
+
+    // Tool calls and content may be spread over the chunks, so merge them.
+    for chunk in chunks {
+        let chunk = chunk?;
+        debug!("received chunk '{}'", chunk.message.content);
+        notify(&chunk.message.content);
+        response.message.content.push_str(&chunk.message.content);
+        response.message.tool_calls.extend(chunk.message.tool_calls);
+        // Token counts are reported in the final chunk only.
+        response.prompt_eval_count = chunk.prompt_eval_count.or(response.prompt_eval_count);
+        response.eval_count = chunk.eval_count.or(response.eval_count);
+    }
+
+    Ok(response)
+}
+
+fn create_tool_calls(response: ChatResponsePayload) -> Vec<ToolCall> {
+    let content = response.message.content.trim();
+    let reason = (!content.is_empty()).then(|| content.to_owned());
+    response
+        .message
+        .tool_calls
+        .into_iter()
+        .map(|call| ToolCall {
             tool: call.function.name,
             params: call.function.arguments,
+            reason: reason.clone(),
         })
-    } else {
-        None
-    }
+        .collect()
 }
 
 #[derive(Serialize)]
 struct ChatRequestPayload {
     model: String,
     messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<KeepAlive>,
     stream: bool,
     tools: Vec<RequestTool>,
     options: OllamaOptions,
@@ -124,6 +204,8 @@ struct Message {
 #[derive(Deserialize)]
 struct ChatResponsePayload {
     message: Message,
+    prompt_eval_count: Option<u64>,
+    eval_count: Option<u64>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -136,3 +218,44 @@ struct ResponseToolCallFunction {
     name: String,
     arguments: Box<RawValue>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_response_chunks() {
+        let body = concat!(
+            r#"{"message":{"role":"assistant","content":"Looking "}}"#,
+            "\n",
+            r#"{"message":{"role":"assistant","content":"for files.","tool_calls":"#,
+            r#"[{"function":{"name":"find_files","arguments":{"in_directory":"~"}}}]}}"#,
+            "\n",
+            r#"{"message":{"role":"assistant","content":""},"done":true,"#,
+            r#""prompt_eval_count":42,"eval_count":7}"#,
+            "\n",
+        );
+
+        let response = parse_response(body.as_bytes(), None).unwrap();
+        assert_eq!(response.prompt_eval_count, Some(42));
+        assert_eq!(response.eval_count, Some(7));
+
+        let mut calls = create_tool_calls(response);
+        assert_eq!(calls.len(), 1);
+        let call = calls.remove(0);
+        assert_eq!(call.tool, "find_files");
+        assert_eq!(call.params.get(), r#"{"in_directory":"~"}"#);
+        assert_eq!(call.reason.as_deref(), Some("Looking for files."));
+    }
+
+    #[test]
+    fn test_parse_response_truncated_chunk() {
+        let body = concat!(
+            r#"{"message":{"role":"assistant","content":"Looking "}}"#,
+            "\n",
+            r#"{"message":{"role":"assistant","content":"for files.","tool_calls":"#,
+        );
+        let result = parse_response(body.as_bytes(), None);
+        assert!(matches!(result, Err(Error::SerdeJson(_))));
+    }
+}
@@ -1,58 +1,107 @@
 use crate::{
-    llm::{BoxLlm, Error, LlmClient, ToolCall},
+    llm::{
+        send_with_retry, set_headers, BoxLlm, ContentCallback, Error, LlmClient, RetryConfig,
+        ToolCall,
+    },
     tool::ToolMeta,
 };
-use schemars::schema::SingleOrVec;
+use log::{debug, info};
+use schemars::{
+    schema::{InstanceType, RootSchema, Schema, SchemaObject, SingleOrVec},
+    JsonSchema, Map,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::value::RawValue;
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader},
+};
 use url::Url;
 
 /// An OpenAI LLM API configuration.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
 pub struct OpenAiConfig {
     pub base_url: Url,
+    pub headers: Option<HashMap<String, String>>,
+    /// An API key (no authorization is sent if empty).
+    #[serde(default)]
     pub key: String,
     pub model: String,
     #[serde(flatten)]
     pub options: OpenAiOptions,
+    /// Stream the response as server-sent events instead of a single object.
+    pub stream: Option<bool>,
+    /// Either "auto", "none", "required" or a function name to force (omitted if unset).
+    ///
+    /// Unless "auto" or "none", a call of the only offered tool is forced, e.g. in the second
+    /// derive pass.
+    pub tool_choice: Option<String>,
+    #[serde(flatten)]
+    pub retry: RetryConfig,
 }
 
-// OpenAI request options.
-#[derive(Clone, Debug, Deserialize, Serialize)]
+/// OpenAI request options (omitted from requests unless set).
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
 pub struct OpenAiOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
     max_completion_tokens: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     seed: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     top_p: Option<f32>,
 }
 
 /// An Ollama API client.
 pub struct OpenAiClient {
     config: OpenAiConfig,
+    on_content: Option<ContentCallback>,
 }
 
 impl OpenAiClient {
     /// Creates a boxed Ollama instance.
     pub fn new_boxed(config: OpenAiConfig) -> BoxLlm {
-        Box::new(Self { config })
+        Box::new(Self {
+            config,
+            on_content: None,
+        })
+    }
+}
+
+impl OpenAiClient {
+    /// Gets the tool choice to request for given tools.
+    fn tool_choice(&self, tools: &[ToolMeta]) -> Option<serde_json::Value> {
+        let tool_choice = self.config.tool_choice.as_deref()?;
+        let function = match (tool_choice, tools) {
+            ("auto" | "none", _) => return Some(tool_choice.into()),
+            (_, [tool]) => tool.name.as_str(),
+            ("required", _) => return Some(tool_choice.into()),
+            _ => tool_choice,
+        };
+        Some(serde_json::json!({"type": "function", "function": {"name": function}}))
     }
 }
 
 impl LlmClient for OpenAiClient {
-    fn derive_tool_call(
+    fn derive_tool_calls(
         &self,
         tools: Vec<ToolMeta>,
         query: String,
-    ) -> Result<Option<ToolCall>, Error> {
+    ) -> Result<Vec<ToolCall>, Error> {
         let messages = vec![Message {
             role: Role::User,
             content: Some(query),
             tool_calls: vec![],
         }];
+        let stream = self.config.stream.unwrap_or_default();
         let request = ChatRequestPayload {
             model: self.config.model.clone(),
             messages,
-            stream: false,
+            stream,
+            tool_choice: self.tool_choice(&tools),
             tools: create_request_tools(tools),
             options: self.config.options.clone(),
         };
@@ -62,12 +111,42 @@ impl LlmClient for OpenAiClient {
 
         let key = shellexpand::env(&self.config.key)?;
 
-        let response: ChatResponsePayload = ureq::post(url.as_str())
-            .set("Authorization", &format!("Bearer {key}"))
-            .send_json(request)?
-            .into_json()?;
+        let (mut http_request, mut secrets) =
+            set_headers(ureq::post(url.as_str()), &self.config.headers)?;
+        if !key.is_empty() {
+            http_request = http_request.set("Authorization", &format!("Bearer {key}"));
+        }
+        secrets.push(key.into_owned());
+        let response = send_with_retry(&self.config.retry, http_request, &request, &secrets)?;
+        let response = if stream {
+            parse_stream(
+                BufReader::new(response.into_reader()),
+                self.on_content.as_ref(),
+            )?
+        } else {
+            let response: ChatResponsePayload = response.into_json()?;
+            let content = response
+                .choices
+                .first()
+                .and_then(|c| c.message.content.as_deref());
+            if let (Some(on_content), Some(content)) = (&self.on_content, content) {
+                on_content(content);
+            }
+            response
+        };
+
+        if let Some(usage) = &response.usage {
+            info!(
+                "used {} prompt, {} completion, {} total tokens",
+                usage.prompt_tokens, usage.completion_tokens, usage.total_tokens
+            );
+        }
 
-        create_tool_call(response)
+        create_tool_calls(response)
+    }
+
+    fn set_content_callback(&mut self, callback: ContentCallback) {
+        self.on_content = Some(callback);
     }
 }
 
@@ -76,6 +155,8 @@ struct ChatRequestPayload {
     model: String,
     messages: Vec<Message>,
     stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<serde_json::Value>,
     tools: Vec<RequestTool>,
     #[serde(flatten)]
     options: OpenAiOptions,
@@ -122,7 +203,7 @@ struct RequestToolFunction {
 }
 
 #[derive(Clone, Serialize)]
-struct RequestToolParameters {
+pub(in crate::llm) struct RequestToolParameters {
     r#type: &'static str,
     required: Vec<String>,
     properties: Box<RawValue>,
@@ -141,6 +222,45 @@ impl RequestToolParameters {
 #[derive(Deserialize)]
 struct ChatResponsePayload {
     choices: Vec<ResponseChoice>,
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct Usage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    total_tokens: u64,
+}
+
+#[derive(Deserialize)]
+struct ChatChunkPayload {
+    #[serde(default)]
+    choices: Vec<ChunkChoice>,
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct ChunkChoice {
+    delta: ChunkDelta,
+}
+
+#[derive(Deserialize)]
+struct ChunkDelta {
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ChunkToolCall>,
+}
+
+#[derive(Deserialize)]
+struct ChunkToolCall {
+    index: usize,
+    function: Option<ChunkToolCallFunction>,
+}
+
+#[derive(Deserialize)]
+struct ChunkToolCallFunction {
+    name: Option<String>,
+    arguments: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -163,46 +283,340 @@ pub(in crate::llm) fn create_request_tools(tools: Vec<ToolMeta>) -> Vec<RequestT
     tools
         .into_iter()
         .map(|t| {
-            let mut params = t.params_schema.schema.object.unwrap();
-
-            // Enforce single instance types since some compatible APIs don't support arrays.
-            for (_, property) in params.properties.iter_mut() {
-                let mut property_object = property.clone().into_object();
-                property_object.instance_type = property_object.instance_type.map(|t| match t {
-                    SingleOrVec::Vec(mut v) => SingleOrVec::Single(Box::new(v.remove(0))),
-                    s => s,
-                });
-                *property = property_object.into();
-            }
-
-            let properties = serde_json::to_string(&params.properties).unwrap();
-            let properties = RawValue::from_string(properties).unwrap();
-
-            let required: Vec<String> = params.required.into_iter().collect();
-
             let function = RequestToolFunction {
                 name: t.name,
                 description: t.description,
-                parameters: RequestToolParameters::new(required, properties),
+                parameters: create_request_tool_parameters(t.params_schema),
             };
             RequestTool::new(function)
         })
         .collect()
 }
 
-fn create_tool_call(mut response: ChatResponsePayload) -> Result<Option<ToolCall>, Error> {
+pub(in crate::llm) fn create_request_tool_parameters(schema: RootSchema) -> RequestToolParameters {
+    let mut params = schema.schema.object.unwrap();
+
+    // Definitions aren't sent, so references to them must be inlined.
+    for (_, property) in params.properties.iter_mut() {
+        inline_refs(property, &schema.definitions, &mut Vec::new());
+    }
+
+    // Enforce single instance types since some compatible APIs don't support arrays.
+    for (_, property) in params.properties.iter_mut() {
+        unwrap_nullable(property);
+        let mut property_object = property.clone().into_object();
+        property_object.instance_type = property_object.instance_type.map(|t| match t {
+            SingleOrVec::Vec(mut v) => SingleOrVec::Single(Box::new(v.remove(0))),
+            s => s,
+        });
+        *property = property_object.into();
+    }
+
+    let properties = serde_json::to_string(&params.properties).unwrap();
+    let properties = RawValue::from_string(properties).unwrap();
+
+    let required: Vec<String> = params.required.into_iter().collect();
+
+    RequestToolParameters::new(required, properties)
+}
+
+/// Replaces references to definitions with their contents recursively.
+///
+/// Recursive references are replaced with unconstrained schemas to avoid infinite inlining.
+fn inline_refs(schema: &mut Schema, definitions: &Map<String, Schema>, visiting: &mut Vec<String>) {
+    let Schema::Object(object) = schema else {
+        return;
+    };
+
+    if let Some(name) = object
+        .reference
+        .as_deref()
+        .and_then(|r| r.strip_prefix("#/definitions/"))
+    {
+        let name = name.to_owned();
+        let metadata = object.metadata.take();
+        let mut inlined = match definitions.get(&name) {
+            Some(definition) if !visiting.contains(&name) => {
+                let mut definition = definition.clone();
+                visiting.push(name);
+                inline_refs(&mut definition, definitions, visiting);
+                visiting.pop();
+                definition.into_object()
+            }
+            _ => SchemaObject::default(),
+        };
+
+        // Metadata of the referencing schema takes precedence.
+        if let Some(metadata) = metadata {
+            let inlined_metadata = inlined.metadata();
+            inlined_metadata.description =
+                metadata.description.or(inlined_metadata.description.take());
+            inlined_metadata.title = metadata.title.or(inlined_metadata.title.take());
+            inlined_metadata.default = metadata.default.or(inlined_metadata.default.take());
+        }
+        *schema = inlined.into();
+        return;
+    }
+
+    if let Some(subschemas) = &mut object.subschemas {
+        let lists = [
+            &mut subschemas.all_of,
+            &mut subschemas.any_of,
+            &mut subschemas.one_of,
+        ];
+        for schemas in lists.into_iter().flatten() {
+            for schema in schemas {
+                inline_refs(schema, definitions, visiting);
+            }
+        }
+    }
+    if let Some(array) = &mut object.array {
+        match &mut array.items {
+            Some(SingleOrVec::Single(schema)) => inline_refs(schema, definitions, visiting),
+            Some(SingleOrVec::Vec(schemas)) => {
+                for schema in schemas {
+                    inline_refs(schema, definitions, visiting);
+                }
+            }
+            None => {}
+        }
+    }
+    if let Some(validation) = &mut object.object {
+        for schema in validation.properties.values_mut() {
+            inline_refs(schema, definitions, visiting);
+        }
+    }
+}
+
+/// Replaces `anyOf: [T, null]` produced for optional non-primitive types with `T`.
+fn unwrap_nullable(schema: &mut Schema) {
+    let Schema::Object(object) = schema else {
+        return;
+    };
+    let Some(any_of) = object.subschemas.as_ref().and_then(|s| s.any_of.as_ref()) else {
+        return;
+    };
+    if any_of.len() != 2 {
+        return;
+    }
+
+    let is_null = |s: &Schema| match s {
+        Schema::Object(o) => {
+            o.instance_type == Some(SingleOrVec::Single(Box::new(InstanceType::Null)))
+        }
+        Schema::Bool(_) => false,
+    };
+    let Some(index) = any_of.iter().position(|s| !is_null(s)) else {
+        return;
+    };
+    if !is_null(&any_of[1 - index]) {
+        return;
+    }
+
+    let mut unwrapped = any_of[index].clone().into_object();
+    if let Some(metadata) = object.metadata.take() {
+        let unwrapped_metadata = unwrapped.metadata();
+        unwrapped_metadata.description = metadata
+            .description
+            .or(unwrapped_metadata.description.take());
+    }
+    *schema = unwrapped.into();
+}
+
+/// Incrementally parses server-sent chat chunks reassembling them into a single response.
+fn parse_stream(
+    reader: impl BufRead,
+    on_content: Option<&ContentCallback>,
+) -> Result<ChatResponsePayload, Error> {
+    let mut content: Option<String> = None;
+    let mut tool_calls: Vec<ResponseToolCall> = Vec::new();
+    let mut usage = None;
+
+    for line in reader.lines() {
+        let line = line?;
+        let Some(data) = line.strip_prefix("data:").map(str::trim) else {
+            continue;
+        };
+        if data == "[DONE]" {
+            break;
+        }
+
+        let mut chunk: ChatChunkPayload = serde_json::from_str(data)?;
+        usage = chunk.usage.or(usage);
+        if chunk.choices.is_empty() {
+            continue;
+        }
+        let delta = chunk.choices.remove(0).delta;
+
+        if let Some(delta_content) = delta.content {
+            debug!("received chunk '{delta_content}'");
+            if let Some(on_content) = on_content {
+                on_content(&delta_content);
+            }
+            content.get_or_insert_default().push_str(&delta_content);
+        }
+
+        // Tool call names and arguments arrive in pieces keyed by call index.
+        for call in delta.tool_calls {
+            while tool_calls.len() <= call.index {
+                tool_calls.push(ResponseToolCall {
+                    function: ResponseToolCallFunction {
+                        name: String::new(),
+                        arguments: String::new(),
+                    },
+                });
+            }
+            let function = &mut tool_calls[call.index].function;
+            if let Some(delta_function) = call.function {
+                function.name.extend(delta_function.name);
+                function.arguments.extend(delta_function.arguments);
+            }
+        }
+    }
+
+    let choices = if content.is_some() || !tool_calls.is_empty() {
+        vec![ResponseChoice {
+            message: Message {
+                role: Role::Assistant,
+                content,
+                tool_calls,
+            },
+        }]
+    } else {
+        Vec::new()
+    };
+    Ok(ChatResponsePayload { choices, usage })
+}
+
+fn create_tool_calls(mut response: ChatResponsePayload) -> Result<Vec<ToolCall>, Error> {
     if response.choices.is_empty() {
-        return Ok(None);
+        return Ok(Vec::new());
     }
     let choice = response.choices.remove(0);
 
-    let mut calls = choice.message.tool_calls;
-    if calls.len() != 1 {
-        return Ok(None);
+    let reason = choice
+        .message
+        .content
+        .map(|c| c.trim().to_owned())
+        .filter(|c| !c.is_empty());
+    choice
+        .message
+        .tool_calls
+        .into_iter()
+        .map(|call| {
+            Ok(ToolCall {
+                tool: call.function.name,
+                params: serde_json::from_str(&call.function.arguments)
+                    .map_err(Error::MalformedToolCall)?,
+                reason: reason.clone(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_recursive_refs() {
+        #[allow(dead_code)]
+        #[derive(schemars::JsonSchema)]
+        struct Node {
+            children: Vec<Node>,
+        }
+
+        #[allow(dead_code)]
+        #[derive(schemars::JsonSchema)]
+        struct Params {
+            root: Option<Node>,
+        }
+
+        let params = create_request_tool_parameters(schemars::schema_for!(Params));
+        let properties: serde_json::Value = serde_json::from_str(params.properties.get()).unwrap();
+        let root = &properties["root"];
+        assert_eq!(root["type"], "object");
+        assert_eq!(root["properties"]["children"]["type"], "array");
+        assert!(!properties.to_string().contains("$ref"));
+    }
+
+    #[test]
+    fn test_stripped_params() {
+        let meta = crate::tool::compiled_tools()["find_files"]
+            .meta()
+            .strip_params();
+        let tools = serde_json::to_value(create_request_tools(vec![meta])).unwrap();
+        let params = &tools[0]["function"]["parameters"];
+        assert_eq!(params["properties"], serde_json::json!({}));
+        assert_eq!(params["required"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn test_tool_choice() {
+        let client = |tool_choice: Option<&str>| OpenAiClient {
+            config: toml::from_str(&format!(
+                "base_url = \"http://localhost\"\nmodel = \"m\"\n{}",
+                tool_choice
+                    .map(|c| format!("tool_choice = \"{c}\""))
+                    .unwrap_or_default()
+            ))
+            .unwrap(),
+            on_content: None,
+        };
+        let tools = crate::tool::compiled_tools();
+        let metas = [tools["find_files"].meta(), tools["get_env"].meta()];
+        let function = |name| serde_json::json!({"type": "function", "function": {"name": name}});
+
+        assert_eq!(client(None).tool_choice(&metas), None);
+        assert_eq!(
+            client(Some("required")).tool_choice(&metas),
+            Some("required".into())
+        );
+        assert_eq!(
+            client(Some("required")).tool_choice(&metas[1..]),
+            Some(function("get_env"))
+        );
+        assert_eq!(
+            client(Some("none")).tool_choice(&metas[1..]),
+            Some("none".into())
+        );
+        assert_eq!(
+            client(Some("find_files")).tool_choice(&metas),
+            Some(function("find_files"))
+        );
     }
-    let call = calls.remove(0);
 
-    let tool = call.function.name;
-    let params = serde_json::from_str(&call.function.arguments)?;
-    Ok(Some(ToolCall { tool, params }))
+    #[test]
+    fn test_parse_stream() {
+        let body = concat!(
+            r#"data: {"choices":[{"delta":{"role":"assistant","content":"Listing "}}]}"#,
+            "\n\n",
+            r#"data: {"choices":[{"delta":{"content":"processes."}}]}"#,
+            "\n\n",
+            r#"data: {"choices":[{"delta":{"tool_calls":[{"index":0,"#,
+            r#""function":{"name":"find_processes","arguments":"{\"name_"}}]}}]}"#,
+            "\n\n",
+            r#"data: {"choices":[{"delta":{"tool_calls":[{"index":0,"#,
+            r#""function":{"arguments":"regex\":\"gram\"}"}}]}}]}"#,
+            "\n\n",
+            "data: [DONE]\n\n",
+        );
+
+        let content = Arc::new(Mutex::new(Vec::new()));
+        let on_content: ContentCallback = {
+            let content = content.clone();
+            Arc::new(move |piece: &str| content.lock().unwrap().push(piece.to_owned()))
+        };
+
+        let response = parse_stream(body.as_bytes(), Some(&on_content)).unwrap();
+        assert_eq!(*content.lock().unwrap(), ["Listing ", "processes."]);
+
+        let mut calls = create_tool_calls(response).unwrap();
+        assert_eq!(calls.len(), 1);
+        let call = calls.remove(0);
+        assert_eq!(call.tool, "find_processes");
+        assert_eq!(call.params.get(), r#"{"name_regex":"gram"}"#);
+        assert_eq!(call.reason.as_deref(), Some("Listing processes."));
+    }
 }
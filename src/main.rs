@@ -1,74 +1,52 @@
-mod config;
-mod llm;
-mod tool;
-mod util;
-
-use crate::{
-    config::Config,
-    llm::{create_llm_client, Context, ToolCall},
-    tool::{create_tools, BoxOutputIter},
-    tool::{DynTools, ToolMeta},
-    util::fmt::ErrorChainDisplay,
-};
+use chrono::{DateTime, Local, Utc};
 use clap::{
     builder::{IntoResettable, OsStr},
     Parser,
 };
 use either::Either;
-use log::{debug, error, info, log_enabled, warn};
+use korah::{
+    apply_param_defaults, call_tool, call_tools,
+    config::Config,
+    create_tools, derive_and_call_tool, direct_tool_call, explain_call,
+    llm::{ContentCallback, LlmApi, ToolCall},
+    output::{create_output_sink, sort_outputs, FieldProjection, OutputFormat, Summary},
+    read_tool_calls,
+    tool::{compiled_tools, BoxOutputIter, DynTools},
+    util::fmt::ErrorChainDisplay,
+    Error, QueryOptions, ToolOutputs,
+};
+use log::{error, warn};
+use schemars::schema_for;
+use serde_json::value::RawValue;
 use std::{
-    path::PathBuf,
+    io::{stderr, stdin, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
     process::exit,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
+    thread::{sleep, spawn},
+    time::Duration,
 };
 
-#[derive(Debug, thiserror::Error)]
-enum Error {
-    #[error("processing cancelled")]
-    Cancelled,
-    #[error("failed to read config")]
-    Config(
-        #[from]
-        #[source]
-        crate::config::Error,
-    ),
-    #[error("failed to derive tool call")]
-    DeriveToolCall,
-    #[error("llm error")]
-    Llm(
-        #[from]
-        #[source]
-        crate::llm::Error,
-    ),
-    #[error("failed to perform io")]
-    SerdeJson(
-        #[from]
-        #[source]
-        std::io::Error,
-    ),
-    #[error("failed to deserialize toml")]
-    TomlDe(
-        #[from]
-        #[source]
-        toml::de::Error,
-    ),
-    #[error("tool error")]
-    Tool(
-        #[from]
-        #[source]
-        crate::tool::Error,
-    ),
-    #[error("unknown tool '{0}'")]
-    UnknownTool(String),
-}
-
 #[derive(clap::Parser)]
 struct Args {
+    #[clap(
+        long,
+        conflicts_with = "query",
+        help = "Execute a tool call (or an array of them) from a JSON file without LLM"
+    )]
+    call_file: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Print JSON of compiled-in tools and supported LLM APIs instead of running a query"
+    )]
+    capabilities: bool,
     #[clap(long, short='c', help="Path to config", default_value=default_config_path())]
     config_path: PathBuf,
+    #[clap(long, help = "Ask for confirmation before calling a derived tool")]
+    confirm: bool,
     #[clap(
         long,
         short = 'd',
@@ -76,8 +54,96 @@ struct Args {
         default_value = "false"
     )]
     derive_only: bool,
-    #[clap(help = "Query in human language")]
-    query: String,
+    #[clap(
+        long,
+        conflicts_with = "single_pass",
+        help = "Derive tool and parameters in two passes overriding the config"
+    )]
+    double_pass: bool,
+    #[clap(long, hide = true, help = "Print JSON schema of the config file")]
+    dump_config_schema: bool,
+    #[clap(
+        long,
+        help = "Print JSON schema of a given tool params instead of running a query"
+    )]
+    dump_schema: Option<String>,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "A format of the error printed to stderr on failure"
+    )]
+    error_format: ErrorFormat,
+    #[clap(
+        long,
+        help = "Describe derived tool calls in human language without running them"
+    )]
+    explain: bool,
+    #[clap(
+        long,
+        value_delimiter = ',',
+        help = "Comma-separated output fields to keep (all if absent)"
+    )]
+    fields: Vec<String>,
+    #[clap(long, help = "List available tools instead of running a query")]
+    list_tools: bool,
+    #[clap(
+        long,
+        short = 'm',
+        help = "Override the model of the configured LLM API"
+    )]
+    model: Option<String>,
+    #[clap(
+        long,
+        help = "Send the raw query without context",
+        default_value = "false"
+    )]
+    no_context: bool,
+    #[clap(
+        long,
+        short = 'o',
+        alias = "format",
+        help = "Output format",
+        default_value = "lines"
+    )]
+    output: OutputFormat,
+    #[clap(long, help = "Path to write outputs to instead of stdout")]
+    output_file: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Terminate outputs with NUL unwrapping paths (same as --output nul)"
+    )]
+    print0: bool,
+    #[clap(long, requires = "sort", help = "Sort outputs in descending order")]
+    reverse: bool,
+    #[clap(
+        long,
+        help = "Print model content to stderr as it arrives during derivation (ollama and open_ai)"
+    )]
+    show_thinking: bool,
+    #[clap(long, help = "Only files modified since a given time", value_parser = parse_time)]
+    since: Option<DateTime<Utc>>,
+    #[clap(
+        long,
+        help = "Derive tool and parameters in a single pass overriding the config"
+    )]
+    single_pass: bool,
+    #[clap(
+        long,
+        help = "Sort outputs by a field (dotted for nested ones) buffering them in memory"
+    )]
+    sort: Option<String>,
+    #[clap(
+        long,
+        help = "Print a final summary output with the number of results (and process totals)"
+    )]
+    summary: bool,
+    #[clap(long, help = "Cancel processing after a given number of seconds")]
+    timeout: Option<u64>,
+    #[clap(long, help = "Only files modified until a given time", value_parser = parse_time)]
+    until: Option<DateTime<Utc>>,
+    #[clap(help = "Query in human language (read from stdin if omitted)")]
+    query: Option<String>,
 }
 
 fn default_config_path() -> impl IntoResettable<OsStr> {
@@ -86,91 +152,85 @@ fn default_config_path() -> impl IntoResettable<OsStr> {
         .into_os_string()
 }
 
-macro_rules! check_cancel {
-    ($cancel: expr) => {
-        if $cancel.load(Ordering::SeqCst) {
-            return Ok(Either::Left(Box::new(std::iter::empty())));
-        }
-    };
+fn parse_time(s: &str) -> Result<DateTime<Utc>, String> {
+    interim::parse_date_string(s, Local::now(), interim::Dialect::Us)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|err| err.to_string())
 }
 
-fn derive_and_call_tool(
-    config: &Config,
-    args: &Args,
-    tools: DynTools,
-    cancel: Arc<AtomicBool>,
-) -> Result<Either<BoxOutputIter, ToolCall>, Error> {
-    let contextualized_query = Context::new().contextualize(&config.llm, args.query.clone());
-    debug!("contextualized query '{contextualized_query}'");
-
-    let tools_meta: Vec<_> = tools.values().map(|t| t.meta()).collect();
-    let llm = create_llm_client(&config.llm)?;
-
-    let outputs = 'a: {
-        for _ in 0..config.num_derive_tries {
-            check_cancel!(cancel);
-
-            let call = if config.double_pass_derive {
-                let tools_stripped_meta: Vec<_> = tools_meta
-                    .iter()
-                    .cloned()
-                    .map(ToolMeta::strip_params)
-                    .collect();
-                let Some(call) = llm.derive_tool_call(tools_stripped_meta, args.query.clone())?
-                else {
-                    warn!("no tool name derived");
-                    continue;
-                };
-
-                let mut tools_meta = tools_meta.clone();
-                tools_meta.retain(|t| t.name == call.tool);
-                if tools_meta.is_empty() {
-                    warn!("unknown derived tool '{}'", call.tool);
-                    continue;
-                }
+/// Returns a given query or reads it from stdin unless it's a terminal.
+fn read_query(query: Option<String>) -> Result<String, Error> {
+    if let Some(query) = query {
+        return Ok(query);
+    }
 
-                check_cancel!(cancel);
+    let mut stdin = stdin();
+    if stdin.is_terminal() {
+        return Err(Error::MissingQuery);
+    }
 
-                match llm.derive_tool_call(tools_meta.clone(), contextualized_query.clone())? {
-                    Some(call) => call,
-                    None => {
-                        warn!("no tool call params derived");
-                        continue;
-                    }
-                }
-            } else {
-                match llm.derive_tool_call(tools_meta.clone(), contextualized_query.clone())? {
-                    Some(call) => call,
-                    None => {
-                        warn!("no tool calls derived");
-                        continue;
-                    }
-                }
-            };
+    let mut query = String::new();
+    stdin.read_to_string(&mut query)?;
+    let query = query.trim();
+    if query.is_empty() {
+        return Err(Error::MissingQuery);
+    }
+    Ok(query.to_owned())
+}
 
-            if args.derive_only {
-                return Ok(Either::Right(call));
-            }
+/// Writes names and descriptions of given tools sorted by name.
+fn list_tools(tools: &DynTools, format: OutputFormat, file: Option<&Path>) -> Result<(), Error> {
+    let mut metas: Vec<_> = tools.values().map(|t| t.meta()).collect();
+    metas.sort_by(|a, b| a.name.cmp(&b.name));
 
-            if log_enabled!(log::Level::Info) {
-                let json = serde_json::to_string(&call).unwrap();
-                info!("derived call {json}");
-            }
+    let mut sink = create_output_sink(format, file)?;
+    sink.begin()?;
+    for meta in metas {
+        let output = serde_json::json!({"name": meta.name, "description": meta.description});
+        sink.write(&RawValue::from_string(output.to_string()).unwrap())?;
+    }
+    sink.finish()?;
+    Ok(())
+}
 
-            let Some(tool) = tools.get(&call.tool.as_str()) else {
-                warn!("unknown derived tool '{}'", call.tool);
-                continue;
-            };
+/// Prints capabilities of the binary as a pretty JSON object.
+fn print_capabilities() {
+    let mut tools: Vec<_> = compiled_tools()
+        .values()
+        .map(|t| {
+            let meta = t.meta();
+            serde_json::json!({"name": meta.name, "destructive": meta.destructive})
+        })
+        .collect();
+    tools.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+    let json = serde_json::json!({
+        "llm_apis": LlmApi::ALL,
+        "tools": tools,
+        "version": env!("CARGO_PKG_VERSION"),
+    });
+    println!("{}", serde_json::to_string_pretty(&json).unwrap());
+}
 
-            match tool.call(call.params, cancel.clone()) {
-                Ok(it) => break 'a it,
-                Err(err) => warn!("derived call failed: {}", ErrorChainDisplay(&err)),
-            }
-        }
-        return Err(Error::DeriveToolCall);
-    };
+/// Prints a given piece of model content to stderr.
+fn show_thinking(content: &str) {
+    eprint!("{content}");
+    let _ = stderr().flush();
+}
+
+/// Asks on the terminal whether to call a given tool declining if there is no terminal.
+fn confirm_call(call: &ToolCall) -> bool {
+    let stdin = stdin();
+    if !stdin.is_terminal() {
+        warn!("declined tool call since stdin is not a terminal");
+        return false;
+    }
 
-    Ok(Either::Left(outputs))
+    eprint!("Call {} with {}? [y/N] ", call.tool, call.params.get());
+    let mut answer = String::new();
+    if stdin.read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
 }
 
 fn run(args: Args) -> Result<(), Error> {
@@ -179,8 +239,47 @@ fn run(args: Args) -> Result<(), Error> {
         .parse_default_env()
         .init();
 
-    let config = Config::read(&args.config_path)?;
-    let tools = create_tools();
+    let format = if args.print0 {
+        OutputFormat::Nul
+    } else {
+        args.output
+    };
+
+    if args.capabilities {
+        print_capabilities();
+        return Ok(());
+    }
+
+    if args.dump_config_schema {
+        let json = serde_json::to_string_pretty(&schema_for!(Config)).unwrap();
+        println!("{json}");
+        return Ok(());
+    }
+
+    if args.dump_schema.is_some() || args.list_tools {
+        // Tools are described as configured, or with default settings if there is no config.
+        let config = if args.config_path.exists() {
+            Some(Config::read(&args.config_path)?)
+        } else {
+            None
+        };
+        let tools = create_tools(config.as_ref());
+        if let Some(name) = args.dump_schema {
+            let Some(tool) = tools.get(name.as_str()) else {
+                return Err(Error::UnknownTool(name));
+            };
+            let json = serde_json::to_string_pretty(&tool.meta().params_schema).unwrap();
+            println!("{json}");
+            return Ok(());
+        }
+        return list_tools(&tools, format, args.output_file.as_deref());
+    }
+
+    let mut config = Config::read(&args.config_path)?;
+    if let Some(model) = args.model {
+        config.llm.set_model(model);
+    }
+    let tools = create_tools(Some(&config));
 
     let cancel = Arc::new(AtomicBool::new(false));
     {
@@ -192,39 +291,165 @@ fn run(args: Args) -> Result<(), Error> {
         .unwrap();
     }
 
-    let outputs = if let Ok(call) = serde_json::from_str::<ToolCall>(&args.query) {
-        info!("interpreted query as a tool call");
-        let Some(tool) = tools.get(&call.tool.as_str()) else {
-            return Err(Error::UnknownTool(call.tool));
-        };
-        tool.call(call.params, cancel.clone())?
+    let timed_out = Arc::new(AtomicBool::new(false));
+    if let Some(timeout) = args.timeout {
+        let cancel_cloned = cancel.clone();
+        let timed_out_cloned = timed_out.clone();
+        spawn(move || {
+            sleep(Duration::from_secs(timeout));
+            warn!("timed out after {timeout}s");
+            timed_out_cloned.store(true, Ordering::SeqCst);
+            cancel_cloned.store(true, Ordering::SeqCst);
+        });
+    }
+
+    let mut param_defaults = serde_json::Map::new();
+    if let Some(since) = args.since {
+        param_defaults.insert("min_time_modified".to_owned(), since.to_rfc3339().into());
+    }
+    if let Some(until) = args.until {
+        param_defaults.insert("max_time_modified".to_owned(), until.to_rfc3339().into());
+    }
+
+    let options = QueryOptions {
+        confirm: args
+            .confirm
+            .then_some(confirm_call as fn(&ToolCall) -> bool),
+        derive_only: args.derive_only || args.explain,
+        double_pass_derive: match (args.single_pass, args.double_pass) {
+            (true, _) => Some(false),
+            (_, true) => Some(true),
+            _ => None,
+        },
+        no_context: args.no_context,
+        on_content: args
+            .show_thinking
+            .then(|| Arc::new(show_thinking) as ContentCallback),
+        param_defaults,
+    };
+
+    let ToolOutputs {
+        outputs,
+        num_failures,
+        tool_timed_out,
+        report,
+    } = if let Some(path) = &args.call_file {
+        let calls = read_tool_calls(path)?;
+        if args.explain {
+            for mut call in calls {
+                apply_param_defaults(&tools, &mut call, &options.param_defaults)?;
+                println!("{}", explain_call(&tools, &call)?);
+            }
+            return Ok(());
+        }
+        call_tools(&config, &options, &tools, calls, &cancel)
     } else {
-        match derive_and_call_tool(&config, &args, tools, cancel.clone())? {
-            Either::Left(outputs) => outputs,
-            Either::Right(call) => {
-                // The derive_only case.
-                let json = serde_json::to_string(&call).unwrap();
-                println!("{json}");
+        let query = read_query(args.query)?;
+        if let Some(mut call) = direct_tool_call(&config, &query) {
+            apply_param_defaults(&tools, &mut call, &options.param_defaults)?;
+            if args.explain {
+                println!("{}", explain_call(&tools, &call)?);
                 return Ok(());
             }
+            call_tool(&config, &tools, call, &cancel)?
+        } else {
+            match derive_and_call_tool(&config, &query, &options, &tools, cancel.clone())? {
+                Either::Left(outputs) => outputs,
+                Either::Right(calls) => {
+                    // The derive_only case.
+                    for call in calls {
+                        if args.explain {
+                            println!("{}", explain_call(&tools, &call)?);
+                        } else {
+                            let json = serde_json::to_string(&call).unwrap();
+                            println!("{json}");
+                        }
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    };
+
+    let outputs: BoxOutputIter = match &args.sort {
+        Some(field) => {
+            // Sorting requires all the outputs, so they are buffered in memory.
+            let outputs = sort_outputs(outputs.collect(), field, args.reverse);
+            Box::new(outputs.into_iter())
         }
+        None => outputs,
     };
 
+    let mut sink = create_output_sink(format, args.output_file.as_deref())?;
+    sink.begin()?;
+    let mut summary = args.summary.then(Summary::default);
+    let mut projection = (!args.fields.is_empty()).then(|| FieldProjection::new(args.fields));
     for output in outputs {
-        println!("{}", output.get());
+        if let Some(summary) = &mut summary {
+            summary.add(&output);
+        }
+        match &mut projection {
+            Some(projection) => sink.write(&projection.project(&output))?,
+            None => sink.write(&output)?,
+        }
+    }
+    // Written even if cancelled so that partial results are still accounted.
+    if let Some(mut summary) = summary {
+        summary.add_unreadable_dirs(report.unreadable_dirs.load(Ordering::Relaxed));
+        sink.write(&summary.to_output())?;
     }
+    sink.finish()?;
 
-    if cancel.load(Ordering::SeqCst) {
+    if timed_out.load(Ordering::SeqCst) {
+        Err(Error::TimedOut)
+    } else if cancel.load(Ordering::SeqCst) {
         Err(Error::Cancelled)
+    } else if tool_timed_out.load(Ordering::SeqCst) {
+        Err(Error::ToolTimedOut)
+    } else if num_failures > 0 {
+        Err(Error::FailedCalls(num_failures))
     } else {
         Ok(())
     }
 }
 
+/// A format of the error printed on failure.
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+enum ErrorFormat {
+    /// A log line with the error chain.
+    #[default]
+    Human,
+    /// A JSON object with the error code and message chain.
+    Json,
+}
+
+/// Maps a given error to the process exit code.
+fn exit_code(err: &Error) -> i32 {
+    match err {
+        // Mimic the timeout utility so that scripts can tell timeouts apart.
+        Error::TimedOut => 124,
+        // The conventional code of termination by SIGINT.
+        Error::Cancelled => 130,
+        // EX_CONFIG from sysexits.h.
+        Error::Config(_) => 78,
+        _ => 1,
+    }
+}
+
 fn main() {
     let args = Args::parse();
+    let error_format = args.error_format;
     if let Err(err) = run(args) {
-        error!("{}", ErrorChainDisplay(&err));
-        exit(1);
+        match error_format {
+            ErrorFormat::Human => error!("{}", ErrorChainDisplay(&err)),
+            ErrorFormat::Json => {
+                let json = serde_json::json!({
+                    "code": err.code(),
+                    "message": ErrorChainDisplay(&err).to_string(),
+                });
+                eprintln!("{json}");
+            }
+        }
+        exit(exit_code(&err));
     }
 }
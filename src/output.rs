@@ -0,0 +1,439 @@
+use log::warn;
+use serde_json::{value::RawValue, Map, Value};
+use std::{
+    cmp::Ordering,
+    collections::HashSet,
+    fs::File,
+    io::{stdout, BufWriter, Result, Write},
+    path::Path,
+};
+
+/// A format of tool outputs.
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// One JSON per line.
+    #[default]
+    Lines,
+    /// A single JSON array.
+    #[value(alias = "json")]
+    JsonArray,
+    /// One JSON per line flushed immediately, stable for piping.
+    Ndjson,
+    /// NUL-terminated outputs with paths and strings unwrapped, safe for `xargs -0`.
+    Nul,
+}
+
+/// A destination for tool outputs.
+pub trait OutputSink {
+    /// Begins writing outputs.
+    fn begin(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Writes a single output.
+    fn write(&mut self, output: &RawValue) -> Result<()>;
+
+    /// Finishes writing outputs.
+    fn finish(&mut self) -> Result<()>;
+}
+
+/// An owned dynamically typed output sink.
+pub type BoxOutputSink = Box<dyn OutputSink>;
+
+/// An output sink writing one JSON per line.
+pub struct LinesSink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> LinesSink<W> {
+    /// Creates a LinesSink instance.
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+
+impl<W: Write> OutputSink for LinesSink<W> {
+    fn write(&mut self, output: &RawValue) -> Result<()> {
+        writeln!(self.writer, "{}", output.get())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// An output sink writing one JSON per line flushing after each of them.
+pub struct NdjsonSink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> NdjsonSink<W> {
+    /// Creates an NdjsonSink instance.
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+
+impl<W: Write> OutputSink for NdjsonSink<W> {
+    fn write(&mut self, output: &RawValue) -> Result<()> {
+        writeln!(self.writer, "{}", output.get())?;
+        self.writer.flush()
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// An output sink terminating outputs with NUL bytes.
+///
+/// String outputs and objects consisting of a single `path` string field are written raw,
+/// anything else is written as JSON.
+pub struct NulSink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> NulSink<W> {
+    /// Creates a NulSink instance.
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+
+impl<W: Write> OutputSink for NulSink<W> {
+    fn write(&mut self, output: &RawValue) -> Result<()> {
+        use serde_json::Value;
+        let raw = match serde_json::from_str(output.get()) {
+            Ok(Value::String(s)) => Some(s),
+            Ok(Value::Object(mut o)) if o.len() == 1 => match o.remove("path") {
+                Some(Value::String(s)) => Some(s),
+                _ => None,
+            },
+            _ => None,
+        };
+        self.writer
+            .write_all(raw.as_deref().unwrap_or(output.get()).as_bytes())?;
+        self.writer.write_all(b"\0")
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// An output sink wrapping outputs into a JSON array.
+pub struct JsonArraySink<W: Write> {
+    writer: W,
+    is_empty: bool,
+}
+
+impl<W: Write> JsonArraySink<W> {
+    /// Creates a JsonArraySink instance.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            is_empty: true,
+        }
+    }
+}
+
+impl<W: Write> OutputSink for JsonArraySink<W> {
+    fn begin(&mut self) -> Result<()> {
+        write!(self.writer, "[")
+    }
+
+    fn write(&mut self, output: &RawValue) -> Result<()> {
+        if !self.is_empty {
+            write!(self.writer, ",")?;
+        }
+        self.is_empty = false;
+        write!(self.writer, "{}", output.get())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        writeln!(self.writer, "]")?;
+        self.writer.flush()
+    }
+}
+
+/// A tally of written outputs reported after the last one.
+#[derive(Debug, Default)]
+pub struct Summary {
+    count: u64,
+    total_cpu_usage: Option<f64>,
+    total_memory: Option<u64>,
+    unreadable_dirs: Option<u64>,
+}
+
+impl Summary {
+    /// Accounts a given output summing process memory and CPU usage if present.
+    pub fn add(&mut self, output: &RawValue) {
+        self.count += 1;
+
+        #[derive(serde::Deserialize)]
+        struct ProcessFields {
+            cpu_usage: Option<f64>,
+            memory: Option<u64>,
+        }
+
+        let Ok(fields) = serde_json::from_str::<ProcessFields>(output.get()) else {
+            return;
+        };
+        if let Some(cpu_usage) = fields.cpu_usage {
+            *self.total_cpu_usage.get_or_insert_default() += cpu_usage;
+        }
+        if let Some(memory) = fields.memory {
+            *self.total_memory.get_or_insert_default() += memory;
+        }
+    }
+
+    /// Accounts directories which couldn't be read, so the results may be incomplete.
+    pub fn add_unreadable_dirs(&mut self, num: u64) {
+        if num > 0 {
+            *self.unreadable_dirs.get_or_insert_default() += num;
+        }
+    }
+
+    /// Converts the summary into an output.
+    pub fn to_output(&self) -> Box<RawValue> {
+        let mut summary = serde_json::json!({"count": self.count});
+        if let Some(total_cpu_usage) = self.total_cpu_usage {
+            summary["total_cpu_usage"] = total_cpu_usage.into();
+        }
+        if let Some(total_memory) = self.total_memory {
+            summary["total_memory"] = total_memory.into();
+        }
+        if let Some(unreadable_dirs) = self.unreadable_dirs {
+            summary["unreadable_dirs"] = unreadable_dirs.into();
+        }
+        let output = serde_json::json!({ "summary": summary });
+        RawValue::from_string(output.to_string()).unwrap()
+    }
+}
+
+/// A projection of output objects down to given fields.
+#[derive(Debug)]
+pub struct FieldProjection {
+    fields: Vec<String>,
+    missing_fields: HashSet<String>,
+}
+
+impl FieldProjection {
+    /// Creates a FieldProjection instance keeping given fields.
+    pub fn new(fields: Vec<String>) -> Self {
+        Self {
+            fields,
+            missing_fields: HashSet::new(),
+        }
+    }
+
+    /// Keeps only the projected fields of a given output object (other outputs are kept as is).
+    ///
+    /// Absent fields are skipped warning once per field.
+    pub fn project(&mut self, output: &RawValue) -> Box<RawValue> {
+        let Ok(mut object) = serde_json::from_str::<Map<String, Value>>(output.get()) else {
+            return output.to_owned();
+        };
+
+        let mut projected = Map::new();
+        for field in &self.fields {
+            match object.remove(field) {
+                Some(value) => {
+                    projected.insert(field.clone(), value);
+                }
+                None => {
+                    if self.missing_fields.insert(field.clone()) {
+                        warn!("no output field '{field}'");
+                    }
+                }
+            }
+        }
+        RawValue::from_string(Value::Object(projected).to_string()).unwrap()
+    }
+}
+
+/// Sorts outputs by a field given as a dotted path, e.g. `size` or `details.size`.
+///
+/// Numbers are compared numerically, other values by their JSON text. Outputs missing the
+/// field are placed last in their original order regardless of `reverse`.
+pub fn sort_outputs(outputs: Vec<Box<RawValue>>, field: &str, reverse: bool) -> Vec<Box<RawValue>> {
+    let (mut keyed, missing): (Vec<_>, Vec<_>) = outputs
+        .into_iter()
+        .map(|output| {
+            let value = serde_json::from_str::<Value>(output.get())
+                .ok()
+                .and_then(|v| field.split('.').try_fold(v, |v, k| v.get(k).cloned()));
+            (value, output)
+        })
+        .partition(|(value, _)| value.is_some());
+
+    if !missing.is_empty() {
+        warn!("{} output(s) have no field '{field}'", missing.len());
+    }
+
+    keyed.sort_by(|(a, _), (b, _)| {
+        let ordering = compare_values(a.as_ref().unwrap(), b.as_ref().unwrap());
+        if reverse {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    });
+    keyed
+        .into_iter()
+        .chain(missing)
+        .map(|(_, output)| output)
+        .collect()
+}
+
+fn compare_values(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => {
+            let (a, b) = (
+                a.as_f64().unwrap_or(f64::NAN),
+                b.as_f64().unwrap_or(f64::NAN),
+            );
+            a.total_cmp(&b)
+        }
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (a, b) => a.to_string().cmp(&b.to_string()),
+    }
+}
+
+/// Creates an output sink writing to a given file or to stdout.
+pub fn create_output_sink(format: OutputFormat, file: Option<&Path>) -> Result<BoxOutputSink> {
+    let writer: Box<dyn Write> = match file {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(stdout()),
+    };
+
+    use OutputFormat::*;
+    Ok(match format {
+        Lines => Box::new(LinesSink::new(writer)),
+        JsonArray => Box::new(JsonArraySink::new(writer)),
+        Ndjson => Box::new(NdjsonSink::new(writer)),
+        Nul => Box::new(NulSink::new(writer)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_array_sink() {
+        let outputs = [r#"{"a":1}"#, r#"{"b":2}"#];
+
+        let mut buf = Vec::new();
+        let mut sink = JsonArraySink::new(&mut buf);
+        sink.begin().unwrap();
+        for output in outputs {
+            sink.write(&RawValue::from_string(output.to_owned()).unwrap())
+                .unwrap();
+        }
+        sink.finish().unwrap();
+
+        assert_eq!(String::from_utf8(buf).unwrap(), "[{\"a\":1},{\"b\":2}]\n");
+    }
+
+    #[test]
+    fn test_summary() {
+        let outputs = [
+            r#"{"cpu_usage":1.5,"memory":100,"name":"a","pid":1}"#,
+            r#"{"cpu_usage":2.0,"memory":50,"name":"b","pid":2}"#,
+        ];
+
+        let mut summary = Summary::default();
+        for output in outputs {
+            summary.add(&RawValue::from_string(output.to_owned()).unwrap());
+        }
+        assert_eq!(
+            summary.to_output().get(),
+            r#"{"summary":{"count":2,"total_cpu_usage":3.5,"total_memory":150}}"#
+        );
+
+        let mut summary = Summary::default();
+        summary.add(&RawValue::from_string(r#"{"path":"/a"}"#.to_owned()).unwrap());
+        summary.add(&RawValue::from_string(r#""foo""#.to_owned()).unwrap());
+        assert_eq!(summary.to_output().get(), r#"{"summary":{"count":2}}"#);
+
+        summary.add_unreadable_dirs(0);
+        assert_eq!(summary.to_output().get(), r#"{"summary":{"count":2}}"#);
+        summary.add_unreadable_dirs(3);
+        assert_eq!(
+            summary.to_output().get(),
+            r#"{"summary":{"count":2,"unreadable_dirs":3}}"#
+        );
+    }
+
+    #[test]
+    fn test_field_projection() {
+        let mut projection = FieldProjection::new(vec!["name".to_owned(), "memory".to_owned()]);
+        let project = |projection: &mut FieldProjection, output: &str| {
+            let output = RawValue::from_string(output.to_owned()).unwrap();
+            projection.project(&output).get().to_owned()
+        };
+
+        assert_eq!(
+            project(
+                &mut projection,
+                r#"{"cpu_usage":1.5,"memory":100,"name":"a","pid":1}"#
+            ),
+            r#"{"memory":100,"name":"a"}"#
+        );
+        assert_eq!(
+            project(&mut projection, r#"{"name":"b","pid":2}"#),
+            r#"{"name":"b"}"#
+        );
+        assert_eq!(project(&mut projection, r#""foo""#), r#""foo""#);
+        assert!(projection.missing_fields.contains("memory"));
+    }
+
+    #[test]
+    fn test_sort_outputs() {
+        let outputs = [
+            r#"{"path":"b","size":10}"#,
+            r#"{"path":"c"}"#,
+            r#"{"path":"a","size":9}"#,
+            r#"{"path":"d","size":100}"#,
+        ];
+        let sort = |field, reverse| {
+            let outputs = outputs
+                .iter()
+                .map(|o| RawValue::from_string((*o).to_owned()).unwrap())
+                .collect();
+            sort_outputs(outputs, field, reverse)
+                .iter()
+                .map(|o| serde_json::from_str::<Value>(o.get()).unwrap()["path"].clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(sort("size", false), ["a", "b", "d", "c"]);
+        assert_eq!(sort("size", true), ["d", "b", "a", "c"]);
+        assert_eq!(sort("path", true), ["d", "c", "b", "a"]);
+        assert_eq!(sort("size.bytes", false), ["b", "c", "a", "d"]);
+    }
+
+    #[test]
+    fn test_nul_sink() {
+        let outputs = [
+            r#"{"path":"/a b\nc"}"#,
+            r#""foo""#,
+            r#"{"path":"/d","size":1}"#,
+            "42",
+        ];
+
+        let mut buf = Vec::new();
+        let mut sink = NulSink::new(&mut buf);
+        for output in outputs {
+            sink.write(&RawValue::from_string(output.to_owned()).unwrap())
+                .unwrap();
+        }
+        sink.finish().unwrap();
+
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "/a b\nc\0foo\0{\"path\":\"/d\",\"size\":1}\x0042\0"
+        );
+    }
+}
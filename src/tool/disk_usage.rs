@@ -0,0 +1,258 @@
+use crate::{
+    tool::{check_filesystem_roots, CallReport, Error, Tool},
+    util::fmt::ErrorChainDisplay,
+};
+use log::warn;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    fs::{read_dir, Metadata, ReadDir},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// Parameters specific to the DiskUsage tool.
+#[derive(Deserialize, JsonSchema)]
+pub struct DiskUsageParams {
+    in_directory: PathBuf,
+    #[schemars(
+        description = "How deep to descend below the immediate children (unlimited by default). \
+        Deeper entries aren't counted."
+    )]
+    max_depth: Option<usize>,
+    #[schemars(description = "Return only a given number of the largest children.")]
+    top: Option<usize>,
+}
+
+/// An output specific to the DiskUsage tool.
+#[derive(Debug, JsonSchema, Serialize)]
+pub struct DiskUsageOutput {
+    file_count: u64,
+    path: PathBuf,
+    total_bytes: u64,
+}
+
+/// A tool for summarizing sizes of directory children.
+///
+/// The sizes are apparent (not allocated) ones. Symlinks are never followed and count with
+/// their own size. On Unix files with multiple hard links are counted once per call.
+pub struct DiskUsage {
+    filesystem_roots: Vec<PathBuf>,
+}
+
+impl DiskUsage {
+    /// Creates a DiskUsage instance restricted to given filesystem roots (if any).
+    pub fn new(filesystem_roots: Vec<PathBuf>) -> Self {
+        DiskUsage { filesystem_roots }
+    }
+}
+
+impl Tool for DiskUsage {
+    type Params = DiskUsageParams;
+    type Output = DiskUsageOutput;
+
+    fn name(&self) -> &'static str {
+        "disk_usage"
+    }
+
+    fn call(
+        &self,
+        params: DiskUsageParams,
+        cancel: Arc<AtomicBool>,
+        _report: Arc<CallReport>,
+    ) -> Result<impl Iterator<Item = DiskUsageOutput> + 'static, Error> {
+        let in_directory = shellexpand::path::full(&params.in_directory)?;
+        check_filesystem_roots(&in_directory, &self.filesystem_roots)?;
+        let mut counter = Counter {
+            cancel,
+            max_depth: params.max_depth,
+            seen_inodes: HashSet::new(),
+        };
+
+        let mut outputs = Vec::new();
+        for entry in read_dir(&in_directory)? {
+            if counter.is_cancelled() {
+                return Ok(Vec::new().into_iter());
+            }
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    warn!(
+                        "failed to read entry of {}: {}",
+                        in_directory.display(),
+                        ErrorChainDisplay(&err)
+                    );
+                    continue;
+                }
+            };
+            if let Some(output) = counter.count(entry.path()) {
+                outputs.push(output);
+            }
+        }
+        if counter.is_cancelled() {
+            return Ok(Vec::new().into_iter());
+        }
+
+        outputs.sort_by_key(|o| Reverse(o.total_bytes));
+        if let Some(top) = params.top {
+            outputs.truncate(top);
+        }
+        Ok(outputs.into_iter())
+    }
+}
+
+struct Counter {
+    cancel: Arc<AtomicBool>,
+    max_depth: Option<usize>,
+    seen_inodes: HashSet<(u64, u64)>,
+}
+
+impl Counter {
+    fn is_cancelled(&self) -> bool {
+        self.cancel.load(Ordering::SeqCst)
+    }
+
+    /// Accumulates sizes of a given entry and all its descendants.
+    fn count(&mut self, path: PathBuf) -> Option<DiskUsageOutput> {
+        let meta = self.symlink_metadata(&path)?;
+        let mut output = DiskUsageOutput {
+            file_count: 0,
+            path,
+            total_bytes: 0,
+        };
+        self.add(&mut output, &meta);
+        if !meta.is_dir() {
+            return Some(output);
+        }
+
+        // Traverse iteratively keeping track of the depth of each directory.
+        let mut entries_stack: Vec<(ReadDir, usize)> = Vec::new();
+        self.push_dir(&mut entries_stack, &output.path, 1);
+        while let Some((entries, depth)) = entries_stack.last_mut() {
+            if self.is_cancelled() {
+                return None;
+            }
+            let depth = *depth;
+            let Some(entry) = entries.next() else {
+                entries_stack.pop();
+                continue;
+            };
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    warn!("failed to read dir entry: {}", ErrorChainDisplay(&err));
+                    continue;
+                }
+            };
+
+            let path = entry.path();
+            let Some(meta) = self.symlink_metadata(&path) else {
+                continue;
+            };
+            self.add(&mut output, &meta);
+            if meta.is_dir() && self.max_depth.is_none_or(|max| depth < max) {
+                self.push_dir(&mut entries_stack, &path, depth + 1);
+            }
+        }
+
+        Some(output)
+    }
+
+    fn push_dir(&self, entries_stack: &mut Vec<(ReadDir, usize)>, path: &Path, depth: usize) {
+        match read_dir(path) {
+            Ok(entries) => entries_stack.push((entries, depth)),
+            Err(err) => warn!(
+                "failed to read dir {}: {}",
+                path.display(),
+                ErrorChainDisplay(&err)
+            ),
+        }
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> Option<Metadata> {
+        match path.symlink_metadata() {
+            Ok(meta) => Some(meta),
+            Err(err) => {
+                warn!(
+                    "failed to read meta for {}: {}",
+                    path.display(),
+                    ErrorChainDisplay(&err)
+                );
+                None
+            }
+        }
+    }
+
+    /// Adds a non-directory entry to the output unless it's an already counted hard link.
+    fn add(&mut self, output: &mut DiskUsageOutput, meta: &Metadata) {
+        if meta.is_dir() || !self.is_first_link(meta) {
+            return;
+        }
+        output.file_count += 1;
+        output.total_bytes += meta.len();
+    }
+
+    #[cfg(unix)]
+    fn is_first_link(&mut self, meta: &Metadata) -> bool {
+        use std::os::unix::fs::MetadataExt;
+        meta.nlink() <= 1 || self.seen_inodes.insert((meta.dev(), meta.ino()))
+    }
+
+    #[cfg(not(unix))]
+    fn is_first_link(&mut self, _meta: &Metadata) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_disk_usage() {
+        let temp = tempdir().unwrap();
+        let dir = temp.path();
+        create_dir_all(dir.join("big/nested")).unwrap();
+        create_dir_all(dir.join("small")).unwrap();
+        write(dir.join("big/a"), [0; 100]).unwrap();
+        write(dir.join("big/nested/b"), [0; 50]).unwrap();
+        write(dir.join("small/c"), [0; 10]).unwrap();
+        write(dir.join("file"), [0; 20]).unwrap();
+        #[cfg(unix)]
+        std::fs::hard_link(dir.join("big/a"), dir.join("big/a_link")).unwrap();
+
+        let call = |mut params: serde_json::Value| -> Vec<(String, u64, u64)> {
+            params["in_directory"] = serde_json::json!(dir);
+            let params = serde_json::from_value(params).unwrap();
+            let cancel = Arc::new(AtomicBool::new(false));
+            DiskUsage::new(Vec::new())
+                .call(params, cancel, Default::default())
+                .unwrap()
+                .map(|o| {
+                    let name = o.path.file_name().unwrap().to_string_lossy().into_owned();
+                    (name, o.total_bytes, o.file_count)
+                })
+                .collect()
+        };
+
+        assert_eq!(
+            call(serde_json::json!({})),
+            [
+                ("big".to_owned(), 150, 2),
+                ("file".to_owned(), 20, 1),
+                ("small".to_owned(), 10, 1)
+            ]
+        );
+        assert_eq!(
+            call(serde_json::json!({"max_depth": 1, "top": 1})),
+            [("big".to_owned(), 100, 1)]
+        );
+    }
+}
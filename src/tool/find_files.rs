@@ -1,37 +1,94 @@
 use crate::{
-    tool::{Error, Tool},
-    util::fmt::ErrorChainDisplay,
+    tool::{build_regex, canonical_roots, CallReport, Error, Tool},
+    util::{
+        fmt::{format_size, ErrorChainDisplay},
+        size::deserialize_size_opt,
+    },
 };
 use chrono::{DateTime, Utc};
-use log::warn;
+use either::Either;
+use globset::{Glob, GlobMatcher};
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+use log::{debug, info, warn};
 use regex::Regex;
 use schemars::JsonSchema;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
 use std::{
+    cmp::Ordering as CmpOrdering,
+    collections::HashSet,
     ffi::OsStr,
-    fs::{read_dir, File, Metadata, ReadDir},
-    io::{BufRead, BufReader},
-    path::PathBuf,
+    fs::{read_dir, DirEntry, File, Metadata, ReadDir},
+    io::{BufRead, BufReader, ErrorKind, Read},
+    path::{absolute, Path, PathBuf},
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        mpsc::{sync_channel, Receiver, SyncSender},
+        Arc, Condvar, Mutex,
     },
+    thread::spawn,
     time::SystemTime,
 };
+#[cfg(unix)]
+use sysinfo::Users;
+
+/// A number of bytes of each file scanned for content matches by default.
+const DEFAULT_CONTENT_MAX_BYTES: u64 = 16 * 1024 * 1024;
+
+/// A number of leading bytes checked for NUL to detect binary files.
+const BINARY_CHECK_BYTES: usize = 8 * 1024;
+
+/// Permission bits including setuid, setgid and sticky ones.
+const PERMISSION_BITS: u32 = 0o7777;
 
 /// Parameters specific to the FindFiles tool.
 #[derive(Deserialize, JsonSchema)]
 pub struct FindFilesParams {
+    #[schemars(
+        description = "Bytes of each file to scan for content_regex (defaults to 16 MiB). \
+        Files without matches within the limit don't match."
+    )]
+    #[serde(default, deserialize_with = "deserialize_size_opt")]
+    content_max_bytes: Option<u64>,
+    #[schemars(description = "Maximum number of lines matching content_regex.")]
+    content_max_matches: Option<usize>,
+    #[schemars(description = "Minimum number of lines matching content_regex.")]
+    content_min_matches: Option<usize>,
     #[schemars(description = "RE2-compatible.")]
     content_regex: Option<String>,
-    in_directory: PathBuf,
+    #[schemars(
+        description = "Skip duplicates of already found files, e.g. reached through symlinks. \
+        Keeps paths of all the found files in memory."
+    )]
+    dedupe: Option<bool>,
+    detailed_output: Option<bool>,
+    #[schemars(description = "Descend into directories reached through symlinks.")]
+    follow_symlinks: Option<bool>,
+    #[schemars(description = "Required unless a default directory is configured.")]
+    in_directory: Option<PathBuf>,
+    #[schemars(description = "Also match in_directory itself against the filters.")]
+    include_root: Option<bool>,
+    #[schemars(
+        description = "Defaults to true. Hidden entries are dot-prefixed on Unix \
+        and have the hidden attribute on Windows."
+    )]
+    include_hidden: Option<bool>,
     is_directory: Option<bool>,
+    #[schemars(
+        description = "Files are empty if zero-sized, directories if they have no entries. \
+        Matches both kinds unless is_directory is set."
+    )]
+    is_empty: Option<bool>,
     is_symlink: Option<bool>,
     #[schemars(description = "In bytes")]
+    #[serde(default, deserialize_with = "deserialize_size_opt")]
     min_size: Option<u64>,
     #[schemars(description = "In ISO 8601 format")]
     min_time_created: Option<DateTime<Utc>>,
     #[schemars(description = "In bytes")]
+    #[serde(default, deserialize_with = "deserialize_size_opt")]
     max_size: Option<u64>,
     #[schemars(description = "In ISO 8601 format")]
     max_time_created: Option<DateTime<Utc>>,
@@ -39,23 +96,197 @@ pub struct FindFilesParams {
     min_time_modified: Option<DateTime<Utc>>,
     #[schemars(description = "In ISO 8601 format")]
     max_time_modified: Option<DateTime<Utc>>,
+    #[schemars(description = "Maximum depth to descend to, 1 means entries of in_directory only.")]
+    max_depth: Option<usize>,
+    max_results: Option<u64>,
+    #[schemars(description = "Octal permission bits to equal, e.g. 644. Unix only.")]
+    mode_equals: Option<String>,
+    #[schemars(description = "Octal permission bits which all must be set, \
+        e.g. 002 for world-writable. Unix only.")]
+    mode_mask: Option<String>,
+    #[schemars(
+        description = "A literal case-insensitive substring of the file name, not a pattern. \
+        Combined with name_glob and name_regex if set."
+    )]
+    name_contains: Option<String>,
+    #[schemars(
+        description = "Matched against the file name only, so '**' is the same as '*'. \
+        Combined with name_regex if both are set."
+    )]
+    name_glob: Option<String>,
     #[schemars(description = "RE2-compatible.")]
     name_regex: Option<String>,
+    #[schemars(description = "Ignored if no name_regex is given.")]
+    name_regex_case_insensitive: Option<bool>,
+    #[schemars(description = "Exact owner username. Unix only.")]
+    owner: Option<String>,
+    #[schemars(description = "A number of threads to traverse directories with.")]
+    parallelism: Option<usize>,
+    #[schemars(description = "Skip entries ignored by .gitignore or .ignore files.")]
+    respect_gitignore: Option<bool>,
+    #[schemars(
+        description = "Don't descend into directories on other filesystems than in_directory. \
+        Ignored on non-Unix platforms."
+    )]
+    same_filesystem: Option<bool>,
+    #[schemars(description = "Buffers all matching files before returning any, \
+        max_results is applied after sorting.")]
+    sort_by: Option<SortField>,
+    #[schemars(description = "Sort in descending order.")]
+    sort_desc: Option<bool>,
+}
+
+/// A field to sort files by.
+#[derive(Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SortField {
+    Depth,
+    Modified,
+    Path,
+    Size,
 }
 
 /// An output specific to the FindFiles tool.
 #[derive(Debug, JsonSchema, Serialize)]
 pub struct FindFilesOutput {
+    #[serde(flatten)]
+    details: Option<FindFilesOutputDetails>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    match_count: Option<usize>,
+    #[schemars(
+        description = "Found entries are joined to in_directory with the platform separator \
+        ('/' on Unix, '\\' on Windows), in_directory itself is kept as given. \
+        Invalid UTF-8 sequences are replaced with U+FFFD, see path_lossy."
+    )]
+    #[serde(serialize_with = "serialize_path_lossy")]
     path: PathBuf,
+    #[schemars(description = "Set if the path isn't valid UTF-8 and can't be used as is.")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path_lossy: Option<bool>,
+}
+
+fn serialize_path_lossy<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&path.to_string_lossy())
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
+pub struct FindFilesOutputDetails {
+    #[schemars(description = "1 means an entry of in_directory, 0 means in_directory itself.")]
+    depth: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gid: Option<u32>,
+    is_dir: bool,
+    #[schemars(description = "Octal permission bits (Unix only).")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<String>,
+    modified: Option<DateTime<Utc>>,
+    size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uid: Option<u32>,
+}
+
+impl FindFilesOutputDetails {
+    fn new(meta: &Metadata, depth: usize) -> Self {
+        let (uid, gid, mode) = Self::permissions(meta);
+        Self {
+            depth: depth as u32,
+            gid,
+            is_dir: meta.is_dir(),
+            mode,
+            modified: meta.modified().ok().map(Into::into),
+            size: meta.len(),
+            uid,
+        }
+    }
+
+    #[cfg(unix)]
+    fn permissions(meta: &Metadata) -> (Option<u32>, Option<u32>, Option<String>) {
+        use std::os::unix::fs::MetadataExt;
+        let mode = format!("{:o}", meta.mode() & PERMISSION_BITS);
+        (Some(meta.uid()), Some(meta.gid()), Some(mode))
+    }
+
+    #[cfg(not(unix))]
+    fn permissions(_meta: &Metadata) -> (Option<u32>, Option<u32>, Option<String>) {
+        (None, None, None)
+    }
+}
+
+/// A deployment-specific configuration of the FindFiles tool.
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+pub struct FindFilesConfig {
+    /// Directories a search must stay within (unrestricted if absent).
+    pub allowed_roots: Option<Vec<PathBuf>>,
+    /// A directory searched if none is given in params (e.g. `~` or `.`).
+    pub default_directory: Option<PathBuf>,
+    /// A maximum depth used unless given in params.
+    pub max_depth: Option<usize>,
 }
 
 /// A tool for finding files on the local file system.
-pub struct FindFiles;
+pub struct FindFiles {
+    config: FindFilesConfig,
+    filesystem_roots: Vec<PathBuf>,
+}
 
 impl FindFiles {
-    /// Creates a FindFiles instance.
-    pub fn new() -> Self {
-        FindFiles
+    /// Creates a FindFiles instance restricted to given filesystem roots (if any).
+    pub fn new(config: FindFilesConfig, filesystem_roots: Vec<PathBuf>) -> Self {
+        FindFiles {
+            config,
+            filesystem_roots,
+        }
+    }
+
+    fn compare(a: &FindFilesOutput, b: &FindFilesOutput, sort_by: SortField) -> CmpOrdering {
+        use SortField::*;
+        let (a_details, b_details) = (a.details.as_ref(), b.details.as_ref());
+        match sort_by {
+            Depth => a_details.map(|d| d.depth).cmp(&b_details.map(|d| d.depth)),
+            Modified => a_details
+                .and_then(|d| d.modified)
+                .cmp(&b_details.and_then(|d| d.modified)),
+            Path => a.path.cmp(&b.path),
+            Size => a_details.map(|d| d.size).cmp(&b_details.map(|d| d.size)),
+        }
+    }
+
+    /// Resolves directories a search must stay within skipping nonexistent ones.
+    ///
+    /// Both the configured allowed roots and the global filesystem roots apply,
+    /// so the result is their intersection.
+    fn allowed_roots(&self) -> Result<Option<Vec<PathBuf>>, Error> {
+        let tool_roots = self
+            .config
+            .allowed_roots
+            .as_deref()
+            .map(canonical_roots)
+            .transpose()?;
+        let filesystem_roots = if self.filesystem_roots.is_empty() {
+            None
+        } else {
+            Some(canonical_roots(&self.filesystem_roots)?)
+        };
+
+        Ok(match (tool_roots, filesystem_roots) {
+            (Some(tool_roots), Some(filesystem_roots)) => {
+                let within =
+                    |root: &PathBuf, roots: &[PathBuf]| roots.iter().any(|r| root.starts_with(r));
+                let mut roots: Vec<_> = tool_roots
+                    .iter()
+                    .filter(|r| within(r, &filesystem_roots))
+                    .cloned()
+                    .collect();
+                roots.extend(
+                    filesystem_roots
+                        .iter()
+                        .filter(|r| within(r, &tool_roots))
+                        .cloned(),
+                );
+                Some(roots)
+            }
+            (roots, None) | (None, roots) => roots,
+        })
     }
 }
 
@@ -71,6 +302,7 @@ impl Tool for FindFiles {
         &self,
         params: FindFilesParams,
         cancel: Arc<AtomicBool>,
+        report: Arc<CallReport>,
     ) -> Result<impl Iterator<Item = FindFilesOutput> + 'static, Error> {
         if params.is_directory == Some(true)
             && (params.content_regex.is_some()
@@ -80,21 +312,277 @@ impl Tool for FindFiles {
             return Err(Error::InconsistentParams);
         }
 
-        let in_directory = shellexpand::path::full(&params.in_directory)?;
+        if params.content_regex.is_none()
+            && (params.content_max_bytes.is_some()
+                || params.content_max_matches.is_some()
+                || params.content_min_matches.is_some())
+        {
+            return Err(Error::InconsistentParams);
+        }
+
+        let in_directory = match &params.in_directory {
+            Some(in_directory) => shellexpand::path::full(in_directory)?.into_owned(),
+            None => {
+                let Some(default_directory) = &self.config.default_directory else {
+                    return Err(Error::MissingParam("in_directory"));
+                };
+                let in_directory = shellexpand::path::full(default_directory)?.into_owned();
+                info!(
+                    "no in_directory given, searching default {}",
+                    in_directory.display()
+                );
+                in_directory
+            }
+        };
+        let allowed_roots = self.allowed_roots()?;
+        if let Some(allowed_roots) = &allowed_roots {
+            let canonical = in_directory.canonicalize()?;
+            if !allowed_roots.iter().any(|r| canonical.starts_with(r)) {
+                return Err(Error::OutsideRoots(in_directory));
+            }
+        }
+
         let entries = read_dir(&in_directory)?;
-        let filter = params.try_into()?;
+        let visited_dirs = if params.follow_symlinks.unwrap_or_default() {
+            let mut visited_dirs = HashSet::new();
+            visited_dirs.insert(in_directory.canonicalize()?);
+            Some(Mutex::new(visited_dirs))
+        } else {
+            None
+        };
+        let respect_gitignore = params.respect_gitignore.unwrap_or_default();
+        let gitignores = if respect_gitignore {
+            Arc::new(root_gitignores(&in_directory))
+        } else {
+            Default::default()
+        };
+        let root = DirEntries {
+            depth: 1,
+            entries,
+            gitignores,
+        };
+        let detailed_output = params.detailed_output.unwrap_or_default();
+        let max_results = params.max_results;
+        let sort_by = params.sort_by;
+        let sort_desc = params.sort_desc.unwrap_or_default();
+        let include_root = params.include_root.unwrap_or_default();
+        let mut results = Results {
+            emitted: params.dedupe.unwrap_or_default().then(HashSet::new),
+            max_results: if sort_by.is_some() { None } else { max_results },
+            num_results: 0,
+        };
+        let parallelism = params.parallelism.unwrap_or(1);
+        let walker = Walker {
+            allowed_roots,
+            // Details are needed for sorting, so they are stripped afterwards.
+            detailed_output: detailed_output || sort_by.is_some(),
+            include_hidden: params.include_hidden.unwrap_or(true),
+            max_depth: params.max_depth.or(self.config.max_depth),
+            respect_gitignore,
+            root_device: if params.same_filesystem.unwrap_or_default() {
+                Walker::device(&in_directory)?
+            } else {
+                None
+            },
+            report: report.clone(),
+            visited_dirs,
+            filter: params.try_into()?,
+        };
 
-        Ok(FindFilesIterator {
-            filter,
-            cancel,
-            entries_stack: vec![entries],
+        let root_output = include_root
+            .then(|| walker.visit_root(&in_directory))
+            .flatten()
+            .filter(|o| !results.is_exhausted() && results.accept(o));
+
+        let outputs = if parallelism > 1 {
+            Either::Right(ParallelFindFilesIterator::new(
+                walker,
+                cancel,
+                root,
+                results,
+                parallelism,
+            ))
+        } else {
+            Either::Left(FindFilesIterator {
+                walker,
+                cancel,
+                entries_stack: vec![root],
+                results,
+            })
+        };
+
+        let outputs = root_output.into_iter().chain(outputs);
+
+        // Evaluated lazily once the traversal is over.
+        let diagnostics = std::iter::once_with(move || {
+            let num_unreadable_dirs = report.unreadable_dirs.load(Ordering::Relaxed);
+            if num_unreadable_dirs > 0 {
+                warn!("failed to read {num_unreadable_dirs} dir(s), results may be incomplete");
+            }
+            None::<FindFilesOutput>
         })
+        .flatten();
+
+        let Some(sort_by) = sort_by else {
+            return Ok(Either::Left(outputs.chain(diagnostics)));
+        };
+
+        // Sorting requires all outputs, so they are buffered in memory.
+        let mut outputs: Vec<_> = outputs.collect();
+        outputs.sort_by(|a, b| Self::compare(a, b, sort_by));
+        if sort_desc {
+            outputs.reverse();
+        }
+        if let Some(max_results) = max_results {
+            outputs.truncate(max_results as usize);
+        }
+        if !detailed_output {
+            outputs.iter_mut().for_each(|o| o.details = None);
+        }
+        Ok(Either::Right(outputs.into_iter().chain(diagnostics)))
+    }
+
+    fn required_params(&self) -> Vec<&'static str> {
+        if self.config.default_directory.is_some() {
+            Vec::new()
+        } else {
+            vec!["in_directory"]
+        }
+    }
+
+    fn explained_params(&self) -> &'static [&'static str] {
+        &[
+            "content_regex",
+            "in_directory",
+            "is_directory",
+            "is_empty",
+            "is_symlink",
+            "max_depth",
+            "max_results",
+            "max_size",
+            "max_time_created",
+            "max_time_modified",
+            "min_size",
+            "min_time_created",
+            "min_time_modified",
+            "mode_equals",
+            "mode_mask",
+            "name_contains",
+            "name_glob",
+            "name_regex",
+            "owner",
+            "sort_by",
+            "sort_desc",
+        ]
+    }
+
+    fn explain(&self, params: &FindFilesParams) -> Option<String> {
+        let kind = match params.is_directory {
+            Some(true) => "directories",
+            Some(false) => "files",
+            None => "entries",
+        };
+        let depth = match params.max_depth.or(self.config.max_depth) {
+            Some(depth) => format!("up to depth {depth}"),
+            None => "recursively".to_owned(),
+        };
+
+        let mut conditions = Vec::new();
+        if let Some(substring) = &params.name_contains {
+            conditions.push(format!("with names containing '{substring}'"));
+        }
+        match params.is_empty {
+            Some(true) => conditions.push("which are empty".to_owned()),
+            Some(false) => conditions.push("which aren't empty".to_owned()),
+            None => {}
+        }
+        if let Some(glob) = &params.name_glob {
+            conditions.push(format!("named like '{glob}'"));
+        }
+        if let Some(regex) = &params.name_regex {
+            conditions.push(format!("with names matching /{regex}/"));
+        }
+        if let Some(regex) = &params.content_regex {
+            conditions.push(format!("containing lines matching /{regex}/"));
+        }
+        if let Some(size) = params.min_size {
+            conditions.push(format!("larger than {}", format_size(size)));
+        }
+        if let Some(size) = params.max_size {
+            conditions.push(format!("smaller than {}", format_size(size)));
+        }
+        if let Some(time) = params.min_time_modified {
+            conditions.push(format!("modified after {time}"));
+        }
+        if let Some(time) = params.max_time_modified {
+            conditions.push(format!("modified before {time}"));
+        }
+        if let Some(time) = params.min_time_created {
+            conditions.push(format!("created after {time}"));
+        }
+        if let Some(time) = params.max_time_created {
+            conditions.push(format!("created before {time}"));
+        }
+        match params.is_symlink {
+            Some(true) => conditions.push("which are symlinks".to_owned()),
+            Some(false) => conditions.push("which aren't symlinks".to_owned()),
+            None => {}
+        }
+        if let Some(owner) = &params.owner {
+            conditions.push(format!("owned by {owner}"));
+        }
+        if let Some(mode) = &params.mode_equals {
+            conditions.push(format!("with mode {mode}"));
+        }
+        if let Some(mode) = &params.mode_mask {
+            conditions.push(format!("with mode bits {mode} set"));
+        }
+
+        let in_directory = params
+            .in_directory
+            .as_ref()
+            .or(self.config.default_directory.as_ref())
+            .map_or("the default directory".into(), |d| d.display().to_string());
+        let mut explanation = format!("Search {in_directory} ({depth}) for {kind}");
+        if !conditions.is_empty() {
+            explanation += &format!(" {}", conditions.join(", "));
+        }
+        if let Some(sort_by) = params.sort_by {
+            let field = match sort_by {
+                SortField::Depth => "depth",
+                SortField::Modified => "modification time",
+                SortField::Path => "path",
+                SortField::Size => "size",
+            };
+            let order = if params.sort_desc.unwrap_or_default() {
+                "descending"
+            } else {
+                "ascending"
+            };
+            explanation += &format!(", sorted by {field} ({order})");
+        }
+        if let Some(max_results) = params.max_results {
+            explanation += &format!(", returning at most {max_results}");
+        }
+        Some(explanation)
     }
 }
 
+/// A matched file system entry.
+struct EntryMatch {
+    /// A content match count if it was requested.
+    match_count: Option<usize>,
+    /// Metadata with a possible symlink resolved.
+    meta: Metadata,
+}
+
 struct Filter {
+    content_max_bytes: u64,
+    content_max_matches: Option<usize>,
+    content_min_matches: Option<usize>,
     content_regex: Option<Regex>,
     is_directory: Option<bool>,
+    is_empty: Option<bool>,
     is_symlink: Option<bool>,
     min_size: Option<u64>,
     min_time_created: Option<SystemTime>,
@@ -102,14 +590,30 @@ struct Filter {
     max_time_created: Option<SystemTime>,
     min_time_modified: Option<SystemTime>,
     max_time_modified: Option<SystemTime>,
+    mode_equals: Option<u32>,
+    mode_mask: Option<u32>,
+    /// A lowercased substring.
+    name_contains: Option<String>,
+    name_glob: Option<GlobMatcher>,
     name_regex: Option<Regex>,
+    owner: Option<u32>,
 }
 
 impl Filter {
-    fn is_matching(&self, path: &str, name: &OsStr, mut meta: Metadata) -> bool {
+    /// Matches an entry against the filters using a lossy path string for logging only.
+    ///
+    /// Symlink targets outside given allowed roots are never read.
+    fn match_entry(
+        &self,
+        path_buf: &Path,
+        path: &str,
+        name: &OsStr,
+        mut meta: Metadata,
+        allowed_roots: Option<&[PathBuf]>,
+    ) -> Option<EntryMatch> {
         if let Some(is_symlink) = self.is_symlink {
             if meta.is_symlink() != is_symlink {
-                return false;
+                return None;
             }
         }
 
@@ -121,17 +625,17 @@ impl Filter {
                         "failed to get created time for {path}: {}",
                         ErrorChainDisplay(&err)
                     );
-                    return false;
+                    return None;
                 }
             };
             if let Some(min_time_created) = self.min_time_created {
                 if time_created < min_time_created {
-                    return false;
+                    return None;
                 }
             }
             if let Some(max_time_created) = self.max_time_created {
                 if time_created > max_time_created {
-                    return false;
+                    return None;
                 }
             }
         }
@@ -144,35 +648,53 @@ impl Filter {
                         "failed to get modified time for {path}: {}",
                         ErrorChainDisplay(&err)
                     );
-                    return false;
+                    return None;
                 }
             };
             if let Some(min_time_modified) = self.min_time_modified {
                 if time_modified < min_time_modified {
-                    return false;
+                    return None;
                 }
             }
             if let Some(max_time_modified) = self.max_time_modified {
                 if time_modified > max_time_modified {
-                    return false;
+                    return None;
                 }
             }
         }
 
+        if let Some(name_contains) = &self.name_contains {
+            if !name
+                .to_string_lossy()
+                .to_lowercase()
+                .contains(name_contains)
+            {
+                return None;
+            }
+        }
+
+        if let Some(name_glob) = &self.name_glob {
+            if !name_glob.is_match(name) {
+                return None;
+            }
+        }
+
         if let Some(name_regex) = &self.name_regex {
-            if let Some(name) = name.to_str() {
-                if !name_regex.is_match(name) {
-                    return false;
-                }
-            } else {
-                return false;
+            if !name_regex.is_match(&name.to_string_lossy()) {
+                return None;
             }
         }
 
         // Here we resolve a possible symlink.
         // The following checks are only related to the final target.
+        let mut is_readable = true;
         if meta.is_symlink() {
-            match std::fs::metadata(path) {
+            if let Some(allowed_roots) = allowed_roots {
+                is_readable = path_buf
+                    .canonicalize()
+                    .is_ok_and(|c| allowed_roots.iter().any(|r| c.starts_with(r)));
+            }
+            match std::fs::metadata(path_buf) {
                 Ok(m) => meta = m,
                 Err(err) => {
                     warn!(
@@ -185,51 +707,155 @@ impl Filter {
 
         if let Some(is_directory) = self.is_directory {
             if meta.is_dir() != is_directory {
-                return false;
+                return None;
             }
         }
 
+        if !self.is_matching_permissions(&meta) {
+            return None;
+        }
+
         if let Some(min_size) = self.min_size {
             if meta.len() < min_size {
-                return false;
+                return None;
             }
         }
 
         if let Some(max_size) = self.max_size {
             if meta.len() > max_size {
-                return false;
+                return None;
             }
         }
 
+        if let Some(is_empty) = self.is_empty {
+            // Directory emptiness requires reading it, so it's checked after cheaper filters.
+            let entry_is_empty = if meta.is_dir() {
+                if !is_readable {
+                    warn!("skipped reading {path} leading outside allowed roots");
+                    return None;
+                }
+                match read_dir(path_buf) {
+                    Ok(mut entries) => entries.next().is_none(),
+                    Err(err) => {
+                        warn!("failed to read dir {path}: {}", ErrorChainDisplay(&err));
+                        return None;
+                    }
+                }
+            } else {
+                meta.len() == 0
+            };
+            if entry_is_empty != is_empty {
+                return None;
+            }
+        }
+
+        let mut match_count = None;
         if let Some(content_regex) = &self.content_regex {
             if meta.is_file() {
-                match Self::match_file_content(path, content_regex) {
-                    Ok(false) => return false,
+                if !is_readable {
+                    warn!("skipped reading {path} leading outside allowed roots");
+                    return None;
+                }
+                let count_all =
+                    self.content_min_matches.is_some() || self.content_max_matches.is_some();
+                match Self::count_content_matches(
+                    path_buf,
+                    content_regex,
+                    count_all,
+                    self.content_max_bytes,
+                ) {
+                    Ok(Some(count)) if count_all => {
+                        if let Some(content_min_matches) = self.content_min_matches {
+                            if count < content_min_matches {
+                                return None;
+                            }
+                        }
+                        if let Some(content_max_matches) = self.content_max_matches {
+                            if count > content_max_matches {
+                                return None;
+                            }
+                        }
+                        match_count = Some(count);
+                    }
+                    Ok(None | Some(0)) => return None,
                     Err(err) => {
                         warn!(
                             "failed to match content for file {path}: {}",
                             ErrorChainDisplay(&err)
                         );
+                        return None;
                     }
                     _ => (),
                 }
             }
         }
 
+        Some(EntryMatch { match_count, meta })
+    }
+
+    #[cfg(unix)]
+    fn is_matching_permissions(&self, meta: &Metadata) -> bool {
+        use std::os::unix::fs::MetadataExt;
+        let mode = meta.mode() & PERMISSION_BITS;
+        self.mode_equals.is_none_or(|m| mode == m)
+            && self.mode_mask.is_none_or(|m| mode & m == m)
+            && self.owner.is_none_or(|uid| meta.uid() == uid)
+    }
+
+    /// Permission filters are rejected on construction on non-Unix platforms.
+    #[cfg(not(unix))]
+    fn is_matching_permissions(&self, _meta: &Metadata) -> bool {
         true
     }
 
-    fn match_file_content(path: &str, regex: &Regex) -> Result<bool, Error> {
+    /// Counts lines matching a given regex streaming the file line by line.
+    ///
+    /// Unless `count_all` is set, stops at the first match. Otherwise the file is scanned
+    /// up to `max_bytes`, and a file exceeding that is skipped. Binary files and files which
+    /// aren't valid UTF-8 are skipped too. Skipped files give None and never match.
+    fn count_content_matches(
+        path: &Path,
+        regex: &Regex,
+        count_all: bool,
+        max_bytes: u64,
+    ) -> Result<Option<usize>, Error> {
         let file = File::open(path)?;
-        let reader = BufReader::new(file);
+        let mut reader = BufReader::new(file.take(max_bytes));
+
+        let head = reader.fill_buf()?;
+        if head[..head.len().min(BINARY_CHECK_BYTES)].contains(&0) {
+            debug!("skipped binary file {}", path.display());
+            return Ok(None);
+        }
 
-        for line in reader.lines().map_while(Result::ok) {
+        let mut count = 0;
+        for line in (&mut reader).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) if err.kind() == ErrorKind::InvalidData => {
+                    debug!("skipped non-utf8 file {}", path.display());
+                    return Ok(None);
+                }
+                Err(err) => return Err(err.into()),
+            };
             if regex.is_match(&line) {
-                return Ok(true);
+                count += 1;
+                if !count_all {
+                    return Ok(Some(count));
+                }
             }
         }
 
-        Ok(false)
+        let mut file = reader.into_inner().into_inner();
+        if file.read(&mut [0])? > 0 {
+            debug!(
+                "gave up matching content of {} after {max_bytes} bytes",
+                path.display()
+            );
+            return Ok(None);
+        }
+
+        Ok(Some(count))
     }
 }
 
@@ -246,10 +872,36 @@ impl TryFrom<FindFilesParams> for Filter {
         let max_time_created = params.max_time_created.map(Into::into);
         let min_time_modified = params.min_time_modified.map(Into::into);
         let max_time_modified = params.max_time_modified.map(Into::into);
-        let name_regex = params.name_regex.as_deref().map(Regex::new).transpose()?;
+        let name_glob = params
+            .name_glob
+            .as_deref()
+            .map(|g| Glob::new(g).map(|g| g.compile_matcher()))
+            .transpose()?;
+        let name_regex = build_regex(
+            params.name_regex.as_deref(),
+            params.name_regex_case_insensitive,
+        )?;
+        let parse_mode = |mode: &str| {
+            u32::from_str_radix(mode, 8)
+                .ok()
+                .filter(|m| m & !PERMISSION_BITS == 0)
+                .ok_or(Error::InconsistentParams)
+        };
+        let mode_equals = params.mode_equals.as_deref().map(parse_mode).transpose()?;
+        let mode_mask = params.mode_mask.as_deref().map(parse_mode).transpose()?;
+        let owner = params.owner.as_deref().map(find_uid).transpose()?;
+        if cfg!(not(unix)) && (mode_equals.is_some() || mode_mask.is_some() || owner.is_some()) {
+            return Err(Error::InconsistentParams);
+        }
         Ok(Self {
+            content_max_bytes: params
+                .content_max_bytes
+                .unwrap_or(DEFAULT_CONTENT_MAX_BYTES),
+            content_max_matches: params.content_max_matches,
+            content_min_matches: params.content_min_matches,
             content_regex,
             is_directory: params.is_directory,
+            is_empty: params.is_empty,
             is_symlink: params.is_symlink,
             min_size: params.min_size,
             min_time_created,
@@ -257,15 +909,417 @@ impl TryFrom<FindFilesParams> for Filter {
             max_time_created,
             min_time_modified,
             max_time_modified,
+            mode_equals,
+            mode_mask,
+            name_contains: params.name_contains.map(|s| s.to_lowercase()),
+            name_glob,
             name_regex,
+            owner,
         })
     }
 }
 
-pub struct FindFilesIterator {
+/// Resolves a user name into a uid once per call rather than per file.
+#[cfg(unix)]
+fn find_uid(name: &str) -> Result<u32, Error> {
+    let users = Users::new_with_refreshed_list();
+    users
+        .iter()
+        .find(|u| u.name() == name)
+        .map(|u| **u.id())
+        .ok_or_else(|| Error::UnknownUser(name.to_owned()))
+}
+
+#[cfg(not(unix))]
+fn find_uid(_name: &str) -> Result<u32, Error> {
+    Err(Error::InconsistentParams)
+}
+
+/// Collects ignore rules of a root directory and its ancestors up to the repository root.
+///
+/// The rules are ordered from the outermost to the innermost directory.
+fn root_gitignores(root: &Path) -> Vec<Gitignore> {
+    let root = match absolute(root) {
+        Ok(root) => root,
+        Err(err) => {
+            warn!(
+                "failed to make {} absolute: {}",
+                root.display(),
+                ErrorChainDisplay(&err)
+            );
+            return Vec::new();
+        }
+    };
+
+    let mut gitignores = Vec::new();
+    for dir in root.ancestors() {
+        gitignores.extend(dir_gitignore(dir));
+        if dir.join(".git").exists() {
+            break;
+        }
+    }
+    gitignores.reverse();
+    gitignores
+}
+
+/// Builds ignore rules from .gitignore and .ignore files of a given directory if any.
+fn dir_gitignore(dir: &Path) -> Option<Gitignore> {
+    let mut builder = GitignoreBuilder::new(dir);
+    let mut has_files = false;
+    for basename in [".gitignore", ".ignore"] {
+        let path = dir.join(basename);
+        if !path.is_file() {
+            continue;
+        }
+        has_files = true;
+        if let Some(err) = builder.add(&path) {
+            warn!(
+                "failed to parse {}: {}",
+                path.display(),
+                ErrorChainDisplay(&err)
+            );
+        }
+    }
+    if !has_files {
+        return None;
+    }
+
+    match builder.build() {
+        Ok(gitignore) => Some(gitignore),
+        Err(err) => {
+            warn!(
+                "failed to build ignore rules for {}: {}",
+                dir.display(),
+                ErrorChainDisplay(&err)
+            );
+            None
+        }
+    }
+}
+
+/// A directory to be traversed along with the inherited ignore rules.
+struct PendingDir {
+    /// A depth of the entries relative to the search root.
+    depth: usize,
+    gitignores: Arc<Vec<Gitignore>>,
+    path: PathBuf,
+}
+
+/// Entries of a directory being traversed along with the inherited ignore rules.
+struct DirEntries {
+    /// A depth of the entries relative to the search root.
+    depth: usize,
+    entries: ReadDir,
+    gitignores: Arc<Vec<Gitignore>>,
+}
+
+/// Traversal settings shared by the sequential and parallel walks.
+struct Walker {
+    /// Canonical directories symlinks may lead to.
+    allowed_roots: Option<Vec<PathBuf>>,
     filter: Filter,
+    detailed_output: bool,
+    include_hidden: bool,
+    max_depth: Option<usize>,
+    respect_gitignore: bool,
+    /// A device of in_directory if not crossing filesystems.
+    root_device: Option<u64>,
+    /// Collects the number of directories failed to be read.
+    report: Arc<CallReport>,
+    visited_dirs: Option<Mutex<HashSet<PathBuf>>>,
+}
+
+impl Walker {
+    /// Visits a directory entry getting its subdirectory to descend into
+    /// and an output if the entry is matching.
+    fn visit(
+        &self,
+        entry: DirEntry,
+        gitignores: &Arc<Vec<Gitignore>>,
+        depth: usize,
+    ) -> (Option<PendingDir>, Option<FindFilesOutput>) {
+        let path = entry.path().to_string_lossy().into_owned();
+
+        let meta = match entry.metadata() {
+            Ok(meta) => meta,
+            Err(err) => {
+                warn!(
+                    "failed to read meta for {path}: {}",
+                    ErrorChainDisplay(&err)
+                );
+                return (None, None);
+            }
+        };
+
+        if !self.include_hidden && Self::is_hidden(&entry.file_name(), &meta) {
+            return (None, None);
+        }
+
+        if self.respect_gitignore
+            && Self::is_ignored(gitignores, &entry.file_name(), &entry.path(), meta.is_dir())
+        {
+            return (None, None);
+        }
+
+        let mut subdir = None;
+        if self.should_descend(&entry.path(), &meta, depth)
+            && !self.is_other_device(&entry.path(), &meta)
+        {
+            let gitignores = if self.respect_gitignore {
+                Self::child_gitignores(gitignores.clone(), &entry.path())
+            } else {
+                gitignores.clone()
+            };
+            subdir = Some(PendingDir {
+                depth: depth + 1,
+                gitignores,
+                path: entry.path(),
+            });
+        }
+
+        let output = self.match_entry(entry.path(), &path, &entry.file_name(), meta, depth);
+        (subdir, output)
+    }
+
+    /// Opens a directory to be traversed, the failure is logged and counted.
+    fn open(&self, dir: PendingDir) -> Option<DirEntries> {
+        match read_dir(&dir.path) {
+            Ok(entries) => Some(DirEntries {
+                depth: dir.depth,
+                entries,
+                gitignores: dir.gitignores,
+            }),
+            Err(err) => {
+                warn!(
+                    "failed to read dir {}: {}",
+                    dir.path.display(),
+                    ErrorChainDisplay(&err)
+                );
+                self.report.unreadable_dirs.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Visits a starting directory getting an output if it is matching.
+    fn visit_root(&self, root: &Path) -> Option<FindFilesOutput> {
+        let path = root.to_string_lossy().into_owned();
+
+        let meta = match root.symlink_metadata() {
+            Ok(meta) => meta,
+            Err(err) => {
+                warn!(
+                    "failed to read meta for {path}: {}",
+                    ErrorChainDisplay(&err)
+                );
+                return None;
+            }
+        };
+
+        let name = root.file_name().unwrap_or(root.as_os_str());
+        self.match_entry(root.to_owned(), &path, name, meta, 0)
+    }
+
+    fn match_entry(
+        &self,
+        path_buf: PathBuf,
+        path: &str,
+        name: &OsStr,
+        meta: Metadata,
+        depth: usize,
+    ) -> Option<FindFilesOutput> {
+        self.filter
+            .match_entry(&path_buf, path, name, meta, self.allowed_roots.as_deref())
+            .map(|entry_match| FindFilesOutput {
+                details: self
+                    .detailed_output
+                    .then(|| FindFilesOutputDetails::new(&entry_match.meta, depth)),
+                match_count: entry_match.match_count,
+                path_lossy: path_buf.to_str().is_none().then_some(true),
+                path: path_buf,
+            })
+    }
+
+    /// Checks if a given directory entry should be descended into.
+    ///
+    /// When following symlinks, refuses to re-enter an already visited directory
+    /// or to leave the allowed roots.
+    fn should_descend(&self, path: &Path, meta: &Metadata, depth: usize) -> bool {
+        if self.max_depth.is_some_and(|max_depth| depth >= max_depth) {
+            return false;
+        }
+
+        let Some(visited_dirs) = &self.visited_dirs else {
+            return meta.is_dir();
+        };
+
+        let is_dir = if meta.is_symlink() {
+            path.is_dir()
+        } else {
+            meta.is_dir()
+        };
+        if !is_dir {
+            return false;
+        }
+
+        match path.canonicalize() {
+            Ok(canonical) => {
+                if let Some(allowed_roots) = &self.allowed_roots {
+                    if !allowed_roots.iter().any(|r| canonical.starts_with(r)) {
+                        warn!("skipped dir {} outside allowed roots", path.display());
+                        return false;
+                    }
+                }
+                if visited_dirs.lock().unwrap().insert(canonical) {
+                    true
+                } else {
+                    warn!("skipped already visited dir {}", path.display());
+                    false
+                }
+            }
+            Err(err) => {
+                warn!(
+                    "failed to canonicalize {}: {}",
+                    path.display(),
+                    ErrorChainDisplay(&err)
+                );
+                false
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    fn device(path: &Path) -> Result<Option<u64>, Error> {
+        use std::os::unix::fs::MetadataExt;
+        Ok(Some(path.metadata()?.dev()))
+    }
+
+    #[cfg(not(unix))]
+    fn device(_path: &Path) -> Result<Option<u64>, Error> {
+        Ok(None)
+    }
+
+    /// Checks if a given directory (or a symlink target) resides on another filesystem.
+    #[cfg(unix)]
+    fn is_other_device(&self, path: &Path, meta: &Metadata) -> bool {
+        use std::os::unix::fs::MetadataExt;
+
+        let Some(root_device) = self.root_device else {
+            return false;
+        };
+        let device = if meta.is_symlink() {
+            match path.metadata() {
+                Ok(meta) => meta.dev(),
+                Err(_) => return false,
+            }
+        } else {
+            meta.dev()
+        };
+
+        if device != root_device {
+            warn!("skipped dir {} on another filesystem", path.display());
+            return true;
+        }
+        false
+    }
+
+    #[cfg(not(unix))]
+    fn is_other_device(&self, _path: &Path, _meta: &Metadata) -> bool {
+        false
+    }
+
+    #[cfg(windows)]
+    fn is_hidden(_name: &OsStr, meta: &Metadata) -> bool {
+        use std::os::windows::fs::MetadataExt;
+        const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+        meta.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0
+    }
+
+    #[cfg(not(windows))]
+    fn is_hidden(name: &OsStr, _meta: &Metadata) -> bool {
+        name.as_encoded_bytes().starts_with(b".")
+    }
+
+    /// Extends inherited ignore rules with ones of a given directory.
+    fn child_gitignores(gitignores: Arc<Vec<Gitignore>>, dir: &Path) -> Arc<Vec<Gitignore>> {
+        let Some(gitignore) = absolute(dir).ok().and_then(|dir| dir_gitignore(&dir)) else {
+            return gitignores;
+        };
+        let mut gitignores = gitignores.as_ref().clone();
+        gitignores.push(gitignore);
+        Arc::new(gitignores)
+    }
+
+    fn is_ignored(gitignores: &[Gitignore], name: &OsStr, path: &Path, is_dir: bool) -> bool {
+        if is_dir && name == ".git" {
+            return true;
+        }
+        if gitignores.is_empty() {
+            return false;
+        }
+
+        // The rules are rooted at absolute paths.
+        let Ok(path) = absolute(path) else {
+            return false;
+        };
+
+        // Inner rules take precedence over outer ones.
+        for gitignore in gitignores.iter().rev() {
+            match gitignore.matched(&path, is_dir) {
+                Match::None => continue,
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+            }
+        }
+        false
+    }
+}
+
+/// Deduplication and capping of the produced outputs.
+struct Results {
+    emitted: Option<HashSet<PathBuf>>,
+    max_results: Option<u64>,
+    num_results: u64,
+}
+
+impl Results {
+    fn is_exhausted(&self) -> bool {
+        self.max_results
+            .is_some_and(|max_results| self.num_results >= max_results)
+    }
+
+    /// Counts a given output unless it is a duplicate.
+    fn accept(&mut self, output: &FindFilesOutput) -> bool {
+        if self.is_duplicate(&output.path) {
+            return false;
+        }
+        self.num_results += 1;
+        true
+    }
+
+    fn is_duplicate(&mut self, path: &Path) -> bool {
+        let Some(emitted) = &mut self.emitted else {
+            return false;
+        };
+        match path.canonicalize() {
+            Ok(canonical) => !emitted.insert(canonical),
+            Err(err) => {
+                warn!(
+                    "failed to canonicalize {}: {}",
+                    path.display(),
+                    ErrorChainDisplay(&err)
+                );
+                false
+            }
+        }
+    }
+}
+
+pub struct FindFilesIterator {
+    walker: Walker,
     cancel: Arc<AtomicBool>,
-    entries_stack: Vec<ReadDir>,
+    entries_stack: Vec<DirEntries>,
+    results: Results,
 }
 
 impl Iterator for FindFilesIterator {
@@ -273,16 +1327,18 @@ impl Iterator for FindFilesIterator {
 
     fn next(&mut self) -> Option<FindFilesOutput> {
         loop {
-            if self.cancel.load(Ordering::SeqCst) {
+            if self.cancel.load(Ordering::SeqCst) || self.results.is_exhausted() {
                 return None;
             }
 
-            let entries = self.entries_stack.last_mut()?;
+            let dir_entries = self.entries_stack.last_mut()?;
 
-            let Some(entry_result) = entries.next() else {
+            let Some(entry_result) = dir_entries.entries.next() else {
                 self.entries_stack.pop();
                 continue;
             };
+            let gitignores = dir_entries.gitignores.clone();
+            let depth = dir_entries.depth;
 
             let entry = match entry_result {
                 Ok(entry) => entry,
@@ -292,33 +1348,593 @@ impl Iterator for FindFilesIterator {
                 }
             };
 
-            let path = entry.path().to_str().unwrap_or("?").to_owned();
+            let (subdir, output) = self.walker.visit(entry, &gitignores, depth);
+            let subdir = subdir.and_then(|subdir| self.walker.open(subdir));
+            self.entries_stack.extend(subdir);
 
-            let meta = match entry.metadata() {
-                Ok(meta) => meta,
-                Err(err) => {
-                    warn!(
-                        "failed to read meta for {path}: {}",
-                        ErrorChainDisplay(&err)
-                    );
-                    continue;
+            if let Some(output) = output {
+                if self.results.accept(&output) {
+                    return Some(output);
+                }
+            }
+        }
+    }
+}
+
+/// Directories pending traversal by the parallel workers.
+///
+/// Directories are opened only once popped by a worker, so the number of open
+/// descriptors is bounded by the parallelism rather than by the queue length.
+struct WorkQueue {
+    dirs: Vec<PendingDir>,
+    num_busy: usize,
+}
+
+/// An iterator receiving outputs of directories traversed by a pool of workers.
+///
+/// The order of outputs is unspecified.
+pub struct ParallelFindFilesIterator {
+    receiver: Receiver<FindFilesOutput>,
+    cancel: Arc<AtomicBool>,
+    stop: Arc<AtomicBool>,
+    queue: Arc<(Mutex<WorkQueue>, Condvar)>,
+    results: Results,
+}
+
+impl ParallelFindFilesIterator {
+    const CHANNEL_CAPACITY: usize = 1024;
+
+    fn new(
+        walker: Walker,
+        cancel: Arc<AtomicBool>,
+        root: DirEntries,
+        results: Results,
+        parallelism: usize,
+    ) -> Self {
+        let walker = Arc::new(walker);
+        let stop = Arc::new(AtomicBool::new(false));
+        // The worker given the already opened root counts as busy from the start.
+        let queue = Arc::new((
+            Mutex::new(WorkQueue {
+                dirs: vec![],
+                num_busy: 1,
+            }),
+            Condvar::new(),
+        ));
+        let (sender, receiver) = sync_channel(Self::CHANNEL_CAPACITY);
+
+        let mut root = Some(root);
+        for _ in 0..parallelism {
+            let walker = walker.clone();
+            let cancel = cancel.clone();
+            let stop = stop.clone();
+            let queue = queue.clone();
+            let sender = sender.clone();
+            let root = root.take();
+            spawn(move || Self::work(&walker, &cancel, &stop, &queue, &sender, root));
+        }
+
+        Self {
+            receiver,
+            cancel,
+            stop,
+            queue,
+            results,
+        }
+    }
+
+    fn work(
+        walker: &Walker,
+        cancel: &AtomicBool,
+        stop: &AtomicBool,
+        queue: &(Mutex<WorkQueue>, Condvar),
+        sender: &SyncSender<FindFilesOutput>,
+        mut root: Option<DirEntries>,
+    ) {
+        let is_stopped = || cancel.load(Ordering::SeqCst) || stop.load(Ordering::SeqCst);
+        let (queue, cvar) = queue;
+
+        loop {
+            let dir_entries = match root.take() {
+                Some(root) => Some(root),
+                None => {
+                    let dir = {
+                        let mut queue = queue.lock().unwrap();
+                        while queue.dirs.is_empty() && queue.num_busy > 0 && !is_stopped() {
+                            queue = cvar.wait(queue).unwrap();
+                        }
+                        if is_stopped() || queue.dirs.is_empty() {
+                            cvar.notify_all();
+                            return;
+                        }
+                        queue.num_busy += 1;
+                        queue.dirs.pop().unwrap()
+                    };
+                    walker.open(dir)
                 }
             };
 
-            if meta.is_dir() {
-                match read_dir(entry.path()) {
-                    Ok(entries) => {
-                        self.entries_stack.push(entries);
+            if let Some(mut dir_entries) = dir_entries {
+                for entry_result in dir_entries.entries.by_ref() {
+                    if is_stopped() {
+                        break;
                     }
-                    Err(err) => {
-                        warn!("failed to read dir {path}: {}", ErrorChainDisplay(&err));
+
+                    let entry = match entry_result {
+                        Ok(entry) => entry,
+                        Err(err) => {
+                            warn!("failed to read dir entry: {}", ErrorChainDisplay(&err));
+                            continue;
+                        }
+                    };
+
+                    let (subdir, output) =
+                        walker.visit(entry, &dir_entries.gitignores, dir_entries.depth);
+                    if let Some(subdir) = subdir {
+                        queue.lock().unwrap().dirs.push(subdir);
+                        cvar.notify_one();
                     }
-                };
+                    if let Some(output) = output {
+                        if sender.send(output).is_err() {
+                            stop.store(true, Ordering::SeqCst);
+                            break;
+                        }
+                    }
+                }
             }
 
-            if self.filter.is_matching(&path, &entry.file_name(), meta) {
-                return Some(FindFilesOutput { path: entry.path() });
+            let mut queue = queue.lock().unwrap();
+            queue.num_busy -= 1;
+            if queue.num_busy == 0 {
+                cvar.notify_all();
             }
         }
     }
+
+    /// Stops the workers waking up the ones waiting for directories.
+    fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let (queue, cvar) = &*self.queue;
+        // Notifying under the lock so no worker misses the wakeup between its check and wait.
+        let _queue = queue.lock().unwrap();
+        cvar.notify_all();
+    }
+}
+
+impl Iterator for ParallelFindFilesIterator {
+    type Item = FindFilesOutput;
+
+    fn next(&mut self) -> Option<FindFilesOutput> {
+        loop {
+            if self.cancel.load(Ordering::SeqCst) || self.results.is_exhausted() {
+                self.stop();
+                return None;
+            }
+
+            let output = self.receiver.recv().ok()?;
+            if self.results.accept(&output) {
+                return Some(output);
+            }
+        }
+    }
+}
+
+impl Drop for ParallelFindFilesIterator {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write};
+    use tempfile::tempdir;
+
+    /// Finds files with the default config.
+    fn find(params: serde_json::Value) -> Result<Vec<FindFilesOutput>, Error> {
+        let params = serde_json::from_value(params).unwrap();
+        let cancel = Arc::new(AtomicBool::new(false));
+        FindFiles::new(Default::default(), Vec::new())
+            .call(params, cancel, Default::default())
+            .map(Iterator::collect)
+    }
+
+    /// Returns the file names of found entries.
+    fn file_names(outputs: Vec<FindFilesOutput>) -> Vec<String> {
+        outputs
+            .iter()
+            .map(|o| o.path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_max_results() {
+        let temp = tempdir().unwrap();
+        let dir = temp.path();
+        for i in 0..5 {
+            write(dir.join(format!("{i}.txt")), "").unwrap();
+        }
+
+        let outputs = find(serde_json::json!({
+            "in_directory": dir,
+            "max_results": 3,
+        }))
+        .unwrap();
+        assert_eq!(outputs.len(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_parallelism() {
+        let temp = tempdir().unwrap();
+        let dir = temp.path();
+        for i in 0..20 {
+            let subdir = dir.join(format!("{i}")).join("nested");
+            create_dir_all(&subdir).unwrap();
+            write(subdir.join("file.txt"), "").unwrap();
+        }
+
+        let paths = |params| {
+            let mut paths: Vec<_> = find(params).unwrap().into_iter().map(|o| o.path).collect();
+            paths.sort();
+            paths
+        };
+        let sequential = paths(serde_json::json!({"in_directory": dir}));
+        let parallel = paths(serde_json::json!({"in_directory": dir, "parallelism": 4}));
+        assert_eq!(sequential.len(), 60);
+        assert_eq!(parallel, sequential);
+
+        // Workers waiting for directories are woken up once the iterator is exhausted.
+        let capped = paths(serde_json::json!({
+            "in_directory": dir,
+            "max_results": 1,
+            "parallelism": 4,
+        }));
+        assert_eq!(capped.len(), 1);
+    }
+
+    #[test]
+    fn test_dedupe() {
+        let temp = tempdir().unwrap();
+        let dir = temp.path();
+        create_dir_all(dir.join("a")).unwrap();
+        for i in 0..2 {
+            write(dir.join(format!("a/{i}.txt")), "").unwrap();
+        }
+        std::os::unix::fs::symlink(dir.join("a/0.txt"), dir.join("link.txt")).unwrap();
+
+        let count = |dedupe: bool, max_results: u64| {
+            find(serde_json::json!({
+                "dedupe": dedupe,
+                "in_directory": dir,
+                "max_results": max_results,
+                "name_glob": "*.txt",
+            }))
+            .unwrap()
+            .len()
+        };
+        assert_eq!(count(false, 10), 3);
+        assert_eq!(count(true, 10), 2);
+        // The cap counts unique outputs only.
+        assert_eq!(count(true, 3), 2);
+        assert_eq!(count(true, 1), 1);
+    }
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    #[test]
+    fn test_non_utf8_path() {
+        use serde_json::value::RawValue;
+        use std::os::unix::ffi::OsStrExt;
+
+        let temp = tempdir().unwrap();
+        let dir = temp.path();
+        let name = OsStr::from_bytes(b"caf\xe9.txt");
+        write(dir.join(name), "coffee").unwrap();
+        write(dir.join("tea.txt"), "tea").unwrap();
+
+        let outputs = find(serde_json::json!({
+            "content_regex": "coffee",
+            "in_directory": dir,
+            "name_regex": "^caf.\\.txt$",
+        }))
+        .unwrap();
+        assert_eq!(outputs.len(), 1);
+        // The path is kept intact rather than replaced with its lossy form.
+        assert_eq!(outputs[0].path.file_name(), Some(name));
+
+        // Serialized outputs carry the lossy form with a marker.
+        let params = RawValue::from_string(
+            serde_json::json!({"in_directory": dir, "name_regex": "^caf"}).to_string(),
+        )
+        .unwrap();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let outputs: Vec<serde_json::Value> = crate::tool::DynTool::call(
+            &FindFiles::new(Default::default(), Vec::new()),
+            params,
+            cancel,
+            Default::default(),
+        )
+        .unwrap()
+        .map(|o| serde_json::from_str(o.get()).unwrap())
+        .collect();
+        assert_eq!(outputs.len(), 1);
+        let path = outputs[0]["path"].as_str().unwrap();
+        assert!(path.ends_with("caf\u{FFFD}.txt"));
+        assert_eq!(outputs[0]["path_lossy"], true);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_report_unreadable() {
+        let temp = tempdir().unwrap();
+        let dir = temp.path();
+        // Permissions don't stop privileged users, but a path exceeding PATH_MAX fails to be
+        // opened for anyone. Such a path can only be built by moving a deep tree into another.
+        let segment = "x".repeat(200);
+        let deep = |root: &Path| (0..12).fold(root.to_owned(), |p, _| p.join(&segment));
+        create_dir_all(deep(&dir.join("outer"))).unwrap();
+        create_dir_all(deep(&dir.join("inner")).join("too_long")).unwrap();
+        std::fs::rename(dir.join("inner"), deep(&dir.join("outer")).join("inner")).unwrap();
+
+        let params = serde_json::from_value(serde_json::json!({
+            "in_directory": dir,
+            "name_contains": "too_long",
+        }))
+        .unwrap();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let report = Arc::new(CallReport::default());
+        let outputs: Vec<_> = FindFiles::new(Default::default(), Vec::new())
+            .call(params, cancel, report.clone())
+            .unwrap()
+            .collect();
+        assert!(outputs.is_empty());
+        assert_eq!(report.unreadable_dirs.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_name_contains() {
+        let temp = tempdir().unwrap();
+        let dir = temp.path();
+        for name in ["Report-2024.txt", "report.md", "notes.txt"] {
+            write(dir.join(name), "").unwrap();
+        }
+
+        let outputs = find(serde_json::json!({
+            "in_directory": dir,
+            "name_contains": "REPORT",
+            "name_glob": "*.txt",
+        }))
+        .unwrap();
+        assert_eq!(outputs.len(), 1);
+        assert!(outputs[0].path.ends_with("Report-2024.txt"));
+    }
+
+    #[test]
+    fn test_include_root() {
+        let temp = tempfile::Builder::new()
+            .prefix("node_modules")
+            .tempdir()
+            .unwrap();
+        let dir = temp.path();
+        create_dir_all(dir.join("nested")).unwrap();
+
+        let call = |include_root: bool| -> Vec<_> {
+            find(serde_json::json!({
+                "in_directory": dir,
+                "include_root": include_root,
+                "is_directory": true,
+                "name_contains": "node_modules",
+            }))
+            .unwrap()
+            .into_iter()
+            .map(|o| o.path)
+            .collect()
+        };
+
+        assert!(call(false).is_empty());
+        assert_eq!(call(true), vec![dir.to_owned()]);
+    }
+
+    #[test]
+    fn test_is_empty() {
+        let temp = tempdir().unwrap();
+        let dir = temp.path();
+        create_dir_all(dir.join("empty")).unwrap();
+        create_dir_all(dir.join("full")).unwrap();
+        write(dir.join("full/a.txt"), "a").unwrap();
+        write(dir.join("b.txt"), "").unwrap();
+
+        let call = |mut params: serde_json::Value| {
+            params["in_directory"] = serde_json::json!(dir);
+            let mut names = file_names(find(params).unwrap());
+            names.sort();
+            names
+        };
+
+        assert_eq!(
+            call(serde_json::json!({"is_empty": true})),
+            ["b.txt", "empty"]
+        );
+        assert_eq!(
+            call(serde_json::json!({"is_empty": true, "is_directory": true})),
+            ["empty"]
+        );
+        assert_eq!(
+            call(serde_json::json!({"is_empty": false, "is_directory": false})),
+            ["a.txt"]
+        );
+    }
+
+    #[test]
+    fn test_content_max_bytes() {
+        let temp = tempdir().unwrap();
+        let dir = temp.path();
+        write(
+            dir.join("early.txt"),
+            format!("needle\n{}\n", "x".repeat(100)),
+        )
+        .unwrap();
+        write(
+            dir.join("late.txt"),
+            format!("{}\nneedle\n", "x".repeat(100)),
+        )
+        .unwrap();
+        write(dir.join("binary.dat"), b"\0needle\n").unwrap();
+        write(dir.join("non-utf8.txt"), b"\xffneedle\n").unwrap();
+        write(dir.join("small.txt"), "haystack\n").unwrap();
+        // A regular file failing to be read even by privileged users.
+        #[cfg(target_os = "linux")]
+        std::os::unix::fs::symlink("/proc/self/mem", dir.join("unreadable.txt")).unwrap();
+
+        let call = |mut params: serde_json::Value| {
+            params["in_directory"] = serde_json::json!(dir);
+            params["follow_symlinks"] = serde_json::json!(true);
+            params["content_regex"] = serde_json::json!("needle");
+            params["content_max_bytes"] = serde_json::json!(50);
+            file_names(find(params).unwrap())
+        };
+
+        assert_eq!(call(serde_json::json!({})), ["early.txt"]);
+        // Counting all matches requires the whole file to fit the limit.
+        assert!(call(serde_json::json!({"content_min_matches": 1})).is_empty());
+        // Skipped and unreadable files are excluded rather than reported with no matches.
+        assert_eq!(
+            call(serde_json::json!({"content_max_matches": 1})),
+            ["small.txt"]
+        );
+    }
+
+    #[test]
+    fn test_config() {
+        let temp = tempdir().unwrap();
+        let dir = temp.path();
+        create_dir_all(dir.join("allowed/nested")).unwrap();
+        write(dir.join("allowed/nested/a.txt"), "").unwrap();
+
+        let call = |config: FindFilesConfig, in_directory: Option<PathBuf>| {
+            let params = serde_json::from_value(serde_json::json!({
+                "in_directory": in_directory,
+            }))
+            .unwrap();
+            let cancel = Arc::new(AtomicBool::new(false));
+            FindFiles::new(config, Vec::new())
+                .call(params, cancel, Default::default())
+                .map(|outputs| outputs.count())
+        };
+
+        let config = FindFilesConfig {
+            allowed_roots: Some(vec![dir.join("allowed")]),
+            default_directory: Some(dir.join("allowed/nested")),
+            max_depth: Some(1),
+        };
+        assert_eq!(call(config.clone(), Some(dir.join("allowed"))).unwrap(), 1);
+        assert_eq!(call(config.clone(), None).unwrap(), 1);
+        let err = call(config.clone(), Some(dir.to_owned())).unwrap_err();
+        assert!(matches!(err, Error::OutsideRoots(_)));
+        assert_eq!(call(Default::default(), Some(dir.to_owned())).unwrap(), 3);
+        let err = call(Default::default(), None).unwrap_err();
+        assert!(matches!(err, Error::MissingParam("in_directory")));
+
+        // The directory is only advertised as optional if there is a default.
+        let required = |config: FindFilesConfig| {
+            let meta = crate::tool::DynTool::meta(&FindFiles::new(config, Vec::new()));
+            meta.params_schema.schema.object.unwrap().required
+        };
+        assert!(required(config.clone()).is_empty());
+        assert!(required(Default::default()).contains("in_directory"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_same_filesystem() {
+        use std::os::unix::fs::{symlink, MetadataExt};
+
+        let temp = tempdir().unwrap();
+        let dir = temp.path();
+        if dir.metadata().unwrap().dev() == Path::new("/proc").metadata().unwrap().dev() {
+            return;
+        }
+        write(dir.join("a.txt"), "").unwrap();
+        symlink("/proc", dir.join("proc")).unwrap();
+
+        let call = |same_filesystem: bool| {
+            find(serde_json::json!({
+                "follow_symlinks": true,
+                "in_directory": dir,
+                "max_depth": 2,
+                "same_filesystem": same_filesystem,
+            }))
+            .unwrap()
+            .len()
+        };
+
+        assert_eq!(call(true), 2);
+        assert!(call(false) > 2);
+    }
+
+    #[test]
+    fn test_sort_by() {
+        let temp = tempdir().unwrap();
+        let dir = temp.path();
+        create_dir_all(dir.join("a/b")).unwrap();
+        write(dir.join("small.txt"), "1").unwrap();
+        write(dir.join("a/b/big.txt"), "12345").unwrap();
+        write(dir.join("a/medium.txt"), "123").unwrap();
+
+        let call = |sort_by: &str| {
+            let outputs = find(serde_json::json!({
+                "in_directory": dir,
+                "is_directory": false,
+                "max_results": 2,
+                "sort_by": sort_by,
+                "sort_desc": true,
+            }))
+            .unwrap();
+            assert!(outputs.iter().all(|o| o.details.is_none()));
+            file_names(outputs)
+        };
+
+        assert_eq!(call("size"), ["big.txt", "medium.txt"]);
+        assert_eq!(call("depth"), ["big.txt", "medium.txt"]);
+        assert_eq!(call("path"), ["small.txt", "medium.txt"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_permissions() {
+        use std::{
+            fs::{set_permissions, Permissions},
+            os::unix::fs::PermissionsExt,
+        };
+
+        let temp = tempdir().unwrap();
+        let dir = temp.path();
+        write(dir.join("private.txt"), "").unwrap();
+        set_permissions(dir.join("private.txt"), Permissions::from_mode(0o600)).unwrap();
+        write(dir.join("shared.txt"), "").unwrap();
+        set_permissions(dir.join("shared.txt"), Permissions::from_mode(0o666)).unwrap();
+
+        let call = |mut params: serde_json::Value| {
+            params["in_directory"] = serde_json::json!(dir);
+            find(params)
+        };
+
+        let outputs = call(serde_json::json!({"mode_mask": "002"})).unwrap();
+        assert_eq!(outputs.len(), 1);
+        assert!(outputs[0].path.ends_with("shared.txt"));
+
+        let outputs = call(serde_json::json!({"mode_equals": "600", "detailed_output": true}));
+        let outputs = outputs.unwrap();
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(
+            outputs[0].details.as_ref().unwrap().mode.as_deref(),
+            Some("600")
+        );
+
+        let err = call(serde_json::json!({"mode_mask": "9"})).unwrap_err();
+        assert!(matches!(err, Error::InconsistentParams));
+        let err = call(serde_json::json!({"owner": "no-such-user-korah"})).unwrap_err();
+        assert!(matches!(err, Error::UnknownUser(_)));
+    }
 }
@@ -1,41 +1,85 @@
-use crate::tool::{Error, Tool};
-use netstat2::{get_sockets_info, AddressFamilyFlags, ProtocolFlags};
+use crate::{
+    tool::{build_regex, CallReport, Error, Tool},
+    util::{fmt::format_size, size::deserialize_size_opt},
+};
+use chrono::{DateTime, Utc};
+use netstat2::{get_sockets_info, AddressFamilyFlags, ProtocolFlags, SocketInfo};
 use regex::Regex;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
     path::PathBuf,
     sync::{atomic::AtomicBool, Arc},
     thread::sleep,
 };
-use sysinfo::{Process, ProcessRefreshKind, ProcessesToUpdate, System};
+use sysinfo::{Process, ProcessRefreshKind, ProcessesToUpdate, System, UpdateKind, Users};
 
 /// Parameters specific to the FindProcesses tool.
 #[derive(Deserialize, JsonSchema)]
 pub struct FindProcessesParams {
+    #[schemars(description = "Matched against the command line arguments joined with spaces.")]
+    cmd_regex: Option<String>,
+    #[schemars(description = "Ignored if no cmd_regex is given.")]
+    cmd_regex_case_insensitive: Option<bool>,
+    #[schemars(description = "Include the whole process subtree of a given PID.")]
+    descendants_of: Option<u32>,
     detailed_output: Option<bool>,
     #[schemars(description = "Percentage")]
     max_cpu_usage: Option<f32>,
     #[schemars(description = "In bytes")]
+    #[serde(default, deserialize_with = "deserialize_size_opt")]
     max_memory: Option<u64>,
     #[schemars(description = "In Bytes")]
+    #[serde(default, deserialize_with = "deserialize_size_opt")]
     max_read_from_disk: Option<u64>,
     #[schemars(description = "In Bytes")]
+    #[serde(default, deserialize_with = "deserialize_size_opt")]
     max_written_to_disk: Option<u64>,
+    #[schemars(description = "In seconds")]
+    max_run_seconds: Option<u64>,
     #[schemars(description = "Percentage")]
     min_cpu_usage: Option<f32>,
     #[schemars(description = "In bytes")]
+    #[serde(default, deserialize_with = "deserialize_size_opt")]
     min_memory: Option<u64>,
     #[schemars(description = "In Bytes")]
+    #[serde(default, deserialize_with = "deserialize_size_opt")]
     min_read_from_disk: Option<u64>,
     #[schemars(description = "In Bytes")]
+    #[serde(default, deserialize_with = "deserialize_size_opt")]
     min_written_to_disk: Option<u64>,
+    #[schemars(description = "In seconds")]
+    min_run_seconds: Option<u64>,
     name_regex: Option<String>,
+    #[schemars(description = "Ignored if no name_regex is given.")]
+    name_regex_case_insensitive: Option<bool>,
+    #[schemars(description = "Maximum number of processes to return after sorting.")]
+    limit: Option<usize>,
+    #[schemars(description = "Parent PID.")]
+    ppid: Option<u32>,
+    sort_by: Option<SortField>,
+    #[schemars(description = "Sort in descending order.")]
+    sort_desc: Option<bool>,
     #[schemars(description = "Zero means any.")]
     tcp_port: Option<u16>,
     #[schemars(description = "Zero means any.")]
     udp_port: Option<u16>,
+    #[schemars(description = "Exact owner username.")]
+    user: Option<String>,
+}
+
+/// A field to sort processes by.
+#[derive(Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SortField {
+    CpuUsage,
+    Memory,
+    Name,
+    Pid,
+    ReadFromDisk,
+    WrittenToDisk,
 }
 
 /// An output specific to the FindProcesses tool.
@@ -48,6 +92,14 @@ pub struct FindProcessesOutput {
 }
 
 impl FindProcessesOutput {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
     pub fn details(&self) -> &FindProcessesOutputDetails {
         self.details.as_ref().unwrap()
     }
@@ -63,9 +115,13 @@ pub struct FindProcessesOutputDetails {
     cpu_usage: f32,
     exe: Option<PathBuf>,
     memory: u64,
+    parent_pid: Option<u32>,
     read_from_disk: u64,
+    run_seconds: u64,
+    start_time: Option<DateTime<Utc>>,
     tcp_ports: Vec<u16>,
     udp_ports: Vec<u16>,
+    user: Option<String>,
     written_to_disk: u64,
 }
 
@@ -82,9 +138,16 @@ impl From<&Process> for FindProcessesOutput {
                 cpu_usage: process.cpu_usage(),
                 exe: process.exe().map(ToOwned::to_owned),
                 memory: process.memory(),
+                parent_pid: process.parent().map(|pid| pid.as_u32()),
                 read_from_disk: disk_usage.total_read_bytes,
+                run_seconds: process.run_time(),
+                // Zero means that the start time is unknown.
+                start_time: Some(process.start_time())
+                    .filter(|&secs| secs != 0)
+                    .and_then(|secs| DateTime::from_timestamp(secs as i64, 0)),
                 tcp_ports: Vec::new(),
                 udp_ports: Vec::new(),
+                user: None,
                 written_to_disk: disk_usage.total_written_bytes,
             }),
             name: process.name().to_string_lossy().to_string(),
@@ -102,34 +165,89 @@ impl FindProcesses {
         FindProcesses
     }
 
+    /// Samples processes twice with the same refresh kinds.
+    ///
+    /// CPU usage is computed from the difference between two consecutive refreshes, so the first
+    /// pass only establishes a baseline. The other kinds are refreshed in both passes to make
+    /// every detail field reflect the same post-sleep sample, and processes spawned in between
+    /// are still picked up by the second pass.
     fn get_processes() -> HashMap<u32, FindProcessesOutput> {
-        let mut system = System::new_all();
+        let mut system = System::new();
+        let refresh_kind = ProcessRefreshKind::nothing()
+            .with_cpu()
+            .with_memory()
+            .with_disk_usage()
+            .with_cmd(UpdateKind::OnlyIfNotSet)
+            .with_exe(UpdateKind::OnlyIfNotSet)
+            .with_user(UpdateKind::OnlyIfNotSet);
 
-        system.refresh_processes_specifics(
-            ProcessesToUpdate::All,
-            true,
-            ProcessRefreshKind::nothing().with_cpu(),
-        );
+        system.refresh_processes_specifics(ProcessesToUpdate::All, true, refresh_kind);
 
         sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL);
 
-        system.refresh_processes_specifics(
-            ProcessesToUpdate::All,
-            true,
-            ProcessRefreshKind::nothing().with_cpu(),
-        );
+        system.refresh_processes_specifics(ProcessesToUpdate::All, true, refresh_kind);
+
+        let users = Users::new_with_refreshed_list();
 
         system
             .processes()
             .iter()
-            .map(|(pid, proc)| (pid.as_u32(), proc.into()))
+            .map(|(pid, proc)| {
+                let mut output: FindProcessesOutput = proc.into();
+                output.details_mut().user = proc
+                    .user_id()
+                    .and_then(|uid| users.get_user_by_id(uid))
+                    .map(|user| user.name().to_owned());
+                (pid.as_u32(), output)
+            })
             .collect()
     }
 
-    fn add_net_ports(processes: &mut HashMap<u32, FindProcessesOutput>) -> Result<(), Error> {
+    /// Collects PIDs of all descendants of a given process.
+    fn get_descendants(processes: &HashMap<u32, FindProcessesOutput>, pid: u32) -> HashSet<u32> {
+        let mut descendants = HashSet::new();
+        for &child in processes.keys() {
+            // Walk up the parent pointers guarding against cycles.
+            let mut chain = Vec::new();
+            let mut current = child;
+            while let Some(parent) = processes.get(&current).and_then(|p| p.details().parent_pid) {
+                chain.push(current);
+                if parent == pid || descendants.contains(&parent) {
+                    descendants.extend(chain.drain(..));
+                    break;
+                }
+                if chain.len() > processes.len() {
+                    break;
+                }
+                current = parent;
+            }
+        }
+        descendants
+    }
+
+    fn compare(a: &FindProcessesOutput, b: &FindProcessesOutput, sort_by: SortField) -> Ordering {
+        use SortField::*;
+        match sort_by {
+            CpuUsage => a.details().cpu_usage.total_cmp(&b.details().cpu_usage),
+            Memory => a.details().memory.cmp(&b.details().memory),
+            Name => a.name.cmp(&b.name),
+            Pid => a.pid.cmp(&b.pid),
+            ReadFromDisk => a.details().read_from_disk.cmp(&b.details().read_from_disk),
+            WrittenToDisk => a
+                .details()
+                .written_to_disk
+                .cmp(&b.details().written_to_disk),
+        }
+    }
+
+    /// Lists IPv4 and IPv6 sockets of given protocols along with their associated PIDs.
+    pub(super) fn get_sockets(proto_flags: ProtocolFlags) -> Result<Vec<SocketInfo>, Error> {
         let af_flags = AddressFamilyFlags::IPV4 | AddressFamilyFlags::IPV6;
-        let proto_flags = ProtocolFlags::TCP | ProtocolFlags::UDP;
-        let sockets_info = get_sockets_info(af_flags, proto_flags)?;
+        Ok(get_sockets_info(af_flags, proto_flags)?)
+    }
+
+    fn add_net_ports(processes: &mut HashMap<u32, FindProcessesOutput>) -> Result<(), Error> {
+        let sockets_info = Self::get_sockets(ProtocolFlags::TCP | ProtocolFlags::UDP)?;
 
         use netstat2::ProtocolSocketInfo::*;
         for si in sockets_info {
@@ -164,39 +282,156 @@ impl Tool for FindProcesses {
         &self,
         params: FindProcessesParams,
         _cancel: Arc<AtomicBool>,
+        _report: Arc<CallReport>,
     ) -> Result<impl Iterator<Item = FindProcessesOutput> + 'static, Error> {
+        let descendants_of = params.descendants_of;
         let detailed_output = params.detailed_output.unwrap_or_default();
+        let limit = params.limit;
+        let sort_by = params.sort_by;
+        let sort_desc = params.sort_desc.unwrap_or_default();
         let filter: Filter = params.try_into()?;
 
         let mut processes = Self::get_processes();
         Self::add_net_ports(&mut processes)?;
 
+        let descendants = descendants_of.map(|pid| Self::get_descendants(&processes, pid));
+
         let mut processes: Vec<_> = processes
             .into_values()
-            .map(FindProcessesOutput::from)
+            .filter(|p| descendants.as_ref().is_none_or(|d| d.contains(&p.pid)))
             .filter(|p| filter.is_matching(p))
             .collect();
 
+        // Details are needed for filtering and sorting, so they are stripped afterwards.
+        if let Some(sort_by) = sort_by {
+            processes.sort_by(|a, b| Self::compare(a, b, sort_by));
+            if sort_desc {
+                processes.reverse();
+            }
+        }
+
+        if let Some(limit) = limit {
+            processes.truncate(limit);
+        }
+
         if !detailed_output {
             processes.iter_mut().for_each(|p| p.details = None);
         }
 
         Ok(processes.into_iter())
     }
+
+    fn explained_params(&self) -> &'static [&'static str] {
+        &[
+            "cmd_regex",
+            "descendants_of",
+            "limit",
+            "max_cpu_usage",
+            "max_memory",
+            "max_run_seconds",
+            "min_cpu_usage",
+            "min_memory",
+            "min_run_seconds",
+            "name_regex",
+            "ppid",
+            "sort_by",
+            "sort_desc",
+            "tcp_port",
+            "udp_port",
+            "user",
+        ]
+    }
+
+    fn explain(&self, params: &FindProcessesParams) -> Option<String> {
+        let mut conditions = Vec::new();
+        if let Some(regex) = &params.name_regex {
+            conditions.push(format!("with names matching /{regex}/"));
+        }
+        if let Some(regex) = &params.cmd_regex {
+            conditions.push(format!("with command lines matching /{regex}/"));
+        }
+        if let Some(user) = &params.user {
+            conditions.push(format!("owned by {user}"));
+        }
+        if let Some(ppid) = params.ppid {
+            conditions.push(format!("children of {ppid}"));
+        }
+        if let Some(pid) = params.descendants_of {
+            conditions.push(format!("in the subtree of {pid}"));
+        }
+        if let Some(cpu_usage) = params.min_cpu_usage {
+            conditions.push(format!("using over {cpu_usage}% CPU"));
+        }
+        if let Some(cpu_usage) = params.max_cpu_usage {
+            conditions.push(format!("using under {cpu_usage}% CPU"));
+        }
+        if let Some(memory) = params.min_memory {
+            conditions.push(format!("using over {} of memory", format_size(memory)));
+        }
+        if let Some(memory) = params.max_memory {
+            conditions.push(format!("using under {} of memory", format_size(memory)));
+        }
+        if let Some(seconds) = params.min_run_seconds {
+            conditions.push(format!("running over {seconds}s"));
+        }
+        if let Some(seconds) = params.max_run_seconds {
+            conditions.push(format!("running under {seconds}s"));
+        }
+        match params.tcp_port {
+            Some(0) => conditions.push("using any TCP port".to_owned()),
+            Some(port) => conditions.push(format!("using TCP port {port}")),
+            None => {}
+        }
+        match params.udp_port {
+            Some(0) => conditions.push("using any UDP port".to_owned()),
+            Some(port) => conditions.push(format!("using UDP port {port}")),
+            None => {}
+        }
+
+        let mut explanation = "Find processes".to_owned();
+        if !conditions.is_empty() {
+            explanation += &format!(" {}", conditions.join(", "));
+        }
+        if let Some(sort_by) = params.sort_by {
+            let field = match sort_by {
+                SortField::CpuUsage => "CPU usage",
+                SortField::Memory => "memory",
+                SortField::Name => "name",
+                SortField::Pid => "PID",
+                SortField::ReadFromDisk => "bytes read from disk",
+                SortField::WrittenToDisk => "bytes written to disk",
+            };
+            let order = if params.sort_desc.unwrap_or_default() {
+                "descending"
+            } else {
+                "ascending"
+            };
+            explanation += &format!(", sorted by {field} ({order})");
+        }
+        if let Some(limit) = params.limit {
+            explanation += &format!(", returning at most {limit}");
+        }
+        Some(explanation)
+    }
 }
 
 struct Filter {
+    cmd_regex: Option<Regex>,
     max_cpu_usage: Option<f32>,
     max_memory: Option<u64>,
     max_read_from_disk: Option<u64>,
     max_written_to_disk: Option<u64>,
+    max_run_seconds: Option<u64>,
     min_cpu_usage: Option<f32>,
     min_memory: Option<u64>,
     min_read_from_disk: Option<u64>,
     min_written_to_disk: Option<u64>,
+    min_run_seconds: Option<u64>,
     name_regex: Option<Regex>,
+    ppid: Option<u32>,
     tcp_port: Option<u16>,
     udp_port: Option<u16>,
+    user: Option<String>,
 }
 
 impl Filter {
@@ -255,6 +490,30 @@ impl Filter {
             }
         }
 
+        if let Some(min_run_seconds) = self.min_run_seconds {
+            if process.details().run_seconds < min_run_seconds {
+                return false;
+            }
+        }
+
+        if let Some(max_run_seconds) = self.max_run_seconds {
+            if process.details().run_seconds > max_run_seconds {
+                return false;
+            }
+        }
+
+        if let Some(cmd_regex) = &self.cmd_regex {
+            if !cmd_regex.is_match(&process.details().cmd.join(" ")) {
+                return false;
+            }
+        }
+
+        if let Some(ppid) = self.ppid {
+            if process.details().parent_pid != Some(ppid) {
+                return false;
+            }
+        }
+
         if let Some(tcp_port) = &self.tcp_port {
             if *tcp_port != 0 {
                 if !process.details().tcp_ports.iter().any(|p| p == tcp_port) {
@@ -275,6 +534,13 @@ impl Filter {
             }
         }
 
+        // Processes with an unknown owner never match.
+        if let Some(user) = &self.user {
+            if process.details().user.as_ref() != Some(user) {
+                return false;
+            }
+        }
+
         true
     }
 }
@@ -283,19 +549,31 @@ impl TryFrom<FindProcessesParams> for Filter {
     type Error = Error;
 
     fn try_from(params: FindProcessesParams) -> Result<Self, Error> {
-        let name_regex = params.name_regex.as_deref().map(Regex::new).transpose()?;
+        let cmd_regex = build_regex(
+            params.cmd_regex.as_deref(),
+            params.cmd_regex_case_insensitive,
+        )?;
+        let name_regex = build_regex(
+            params.name_regex.as_deref(),
+            params.name_regex_case_insensitive,
+        )?;
         Ok(Self {
+            cmd_regex,
             max_cpu_usage: params.max_cpu_usage,
             max_memory: params.max_memory,
             max_read_from_disk: params.max_read_from_disk,
             max_written_to_disk: params.max_written_to_disk,
+            max_run_seconds: params.max_run_seconds,
             min_cpu_usage: params.min_cpu_usage,
             min_memory: params.min_memory,
             min_read_from_disk: params.min_read_from_disk,
             min_written_to_disk: params.min_written_to_disk,
+            min_run_seconds: params.min_run_seconds,
             name_regex,
+            ppid: params.ppid,
             tcp_port: params.tcp_port,
             udp_port: params.udp_port,
+            user: params.user,
         })
     }
 }
@@ -0,0 +1,74 @@
+use crate::tool::{CallReport, Error, Tool};
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::sync::{atomic::AtomicBool, Arc, LazyLock};
+
+static SECRET_NAME_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new("(?i)(key|passw(or)?d|secret|token|credential|auth)").unwrap());
+
+/// Parameters specific to the GetEnv tool.
+#[derive(Deserialize, JsonSchema)]
+pub struct GetEnvParams {
+    #[schemars(description = "Redact values of secret-like variables.")]
+    mask_secrets: Option<bool>,
+    #[schemars(description = "RE2-compatible.")]
+    name_regex: Option<String>,
+}
+
+/// An output specific to the GetEnv tool.
+#[derive(Debug, JsonSchema, Serialize)]
+pub struct GetEnvOutput {
+    name: String,
+    value: String,
+}
+
+/// A tool for reading environment variables.
+pub struct GetEnv;
+
+impl GetEnv {
+    /// Creates a GetEnv instance.
+    pub fn new() -> Self {
+        GetEnv
+    }
+}
+
+impl Tool for GetEnv {
+    type Params = GetEnvParams;
+    type Output = GetEnvOutput;
+
+    fn name(&self) -> &'static str {
+        "get_env"
+    }
+
+    fn call(
+        &self,
+        params: GetEnvParams,
+        _cancel: Arc<AtomicBool>,
+        _report: Arc<CallReport>,
+    ) -> Result<impl Iterator<Item = GetEnvOutput> + 'static, Error> {
+        let mask_secrets = params.mask_secrets.unwrap_or_default();
+        let name_regex = params.name_regex.as_deref().map(Regex::new).transpose()?;
+
+        let mut vars: Vec<_> = std::env::vars_os()
+            .filter_map(|(name, value)| {
+                let name = name.into_string().ok()?;
+                if let Some(name_regex) = &name_regex {
+                    if !name_regex.is_match(&name) {
+                        return None;
+                    }
+                }
+
+                let value = if mask_secrets && SECRET_NAME_REGEX.is_match(&name) {
+                    "***".to_owned()
+                } else {
+                    value.to_string_lossy().to_string()
+                };
+                Some(GetEnvOutput { name, value })
+            })
+            .collect();
+        vars.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(vars.into_iter())
+    }
+}
@@ -0,0 +1,160 @@
+use crate::tool::{CallReport, Error, Tool};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Read,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_MAX_BODY_BYTES: u64 = 1024 * 1024;
+
+/// A deployment-specific configuration of the HttpRequest tool.
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+pub struct HttpRequestConfig {
+    /// The maximum size of a returned response body (defaults to 1 MiB).
+    pub max_body_bytes: Option<u64>,
+    /// The maximum duration of a whole request (defaults to 30 seconds).
+    pub timeout_secs: Option<u64>,
+}
+
+/// Parameters specific to the HttpRequest tool.
+#[derive(Deserialize, JsonSchema)]
+pub struct HttpRequestParams {
+    body: Option<String>,
+    headers: Option<HashMap<String, String>>,
+    #[schemars(description = "Defaults to GET.")]
+    method: Option<String>,
+    url: String,
+}
+
+/// An output specific to the HttpRequest tool.
+#[derive(Debug, JsonSchema, Serialize)]
+pub struct HttpRequestOutput {
+    body: String,
+    headers: BTreeMap<String, String>,
+    status: u16,
+    #[schemars(description = "The body was cut at the maximum size.")]
+    truncated: bool,
+}
+
+/// A tool for making HTTP requests.
+pub struct HttpRequest {
+    config: HttpRequestConfig,
+}
+
+impl HttpRequest {
+    /// Creates a HttpRequest instance.
+    pub fn new(config: HttpRequestConfig) -> Self {
+        HttpRequest { config }
+    }
+}
+
+impl Tool for HttpRequest {
+    type Params = HttpRequestParams;
+    type Output = HttpRequestOutput;
+
+    fn name(&self) -> &'static str {
+        "http_request"
+    }
+
+    // Any method may have side effects on the remote server.
+    fn is_destructive(&self) -> bool {
+        true
+    }
+
+    fn call(
+        &self,
+        params: HttpRequestParams,
+        _cancel: Arc<AtomicBool>,
+        _report: Arc<CallReport>,
+    ) -> Result<impl Iterator<Item = HttpRequestOutput> + 'static, Error> {
+        let timeout = self.config.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS);
+        let max_body_bytes = self.config.max_body_bytes.unwrap_or(DEFAULT_MAX_BODY_BYTES);
+
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(timeout))
+            .build();
+        let method = params.method.as_deref().unwrap_or("GET").to_uppercase();
+        let mut request = agent.request(&method, &params.url);
+        for (name, value) in params.headers.iter().flatten() {
+            request = request.set(name, value);
+        }
+
+        let result = match &params.body {
+            Some(body) => request.send_string(body),
+            None => request.call(),
+        };
+        // Error statuses are still meaningful responses.
+        let response = match result {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+            Err(err) => return Err(Box::new(err).into()),
+        };
+
+        let status = response.status();
+        let headers = response
+            .headers_names()
+            .into_iter()
+            .filter_map(|name| {
+                let value = response.header(&name)?.to_owned();
+                Some((name, value))
+            })
+            .collect();
+
+        let mut body = Vec::new();
+        response
+            .into_reader()
+            .take(max_body_bytes + 1)
+            .read_to_end(&mut body)?;
+        let truncated = body.len() as u64 > max_body_bytes;
+        body.truncate(max_body_bytes as usize);
+
+        Ok(std::iter::once(HttpRequestOutput {
+            body: String::from_utf8_lossy(&body).into_owned(),
+            headers,
+            status,
+            truncated,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_request() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/rates", server.server_addr());
+        std::thread::spawn(move || {
+            let request = server.recv().unwrap();
+            assert_eq!(request.method(), &tiny_http::Method::Post);
+            let response = tiny_http::Response::from_string("0123456789").with_status_code(404);
+            request.respond(response).unwrap();
+        });
+
+        let params = serde_json::from_value(serde_json::json!({
+            "url": url,
+            "method": "post",
+            "body": "{}",
+        }))
+        .unwrap();
+        let config = HttpRequestConfig {
+            max_body_bytes: Some(4),
+            timeout_secs: None,
+        };
+        let cancel = Arc::new(AtomicBool::new(false));
+        let outputs: Vec<_> = HttpRequest::new(config)
+            .call(params, cancel, Default::default())
+            .unwrap()
+            .collect();
+
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].status, 404);
+        assert_eq!(outputs[0].body, "0123");
+        assert!(outputs[0].truncated);
+        assert!(outputs[0].headers.contains_key("content-length"));
+    }
+}
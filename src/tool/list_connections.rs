@@ -0,0 +1,150 @@
+use crate::tool::{find_processes::FindProcesses, CallReport, Error, Tool};
+use netstat2::{ProtocolFlags, ProtocolSocketInfo};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{atomic::AtomicBool, Arc},
+};
+use sysinfo::{ProcessRefreshKind, ProcessesToUpdate, System};
+
+/// Parameters specific to the ListConnections tool.
+#[derive(Deserialize, JsonSchema)]
+pub struct ListConnectionsParams {
+    #[schemars(description = "Matched against both local and remote ports.")]
+    port: Option<u16>,
+    #[schemars(description = "Both TCP and UDP if omitted.")]
+    protocol: Option<Protocol>,
+    #[schemars(
+        description = "TCP state, e.g. LISTEN, ESTABLISHED, TIME_WAIT, CLOSE_WAIT. \
+        Excludes UDP sockets."
+    )]
+    state: Option<String>,
+}
+
+/// A network protocol.
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+/// An output specific to the ListConnections tool.
+#[derive(Debug, JsonSchema, Serialize)]
+pub struct ListConnectionsOutput {
+    local_addr: IpAddr,
+    local_port: u16,
+    processes: Vec<ConnectionProcess>,
+    protocol: Protocol,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_addr: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<String>,
+}
+
+/// A process associated with a socket.
+#[derive(Debug, JsonSchema, Serialize)]
+pub struct ConnectionProcess {
+    name: Option<String>,
+    pid: u32,
+}
+
+/// A tool for listing open network sockets.
+pub struct ListConnections;
+
+impl ListConnections {
+    /// Creates a ListConnections instance.
+    pub fn new() -> Self {
+        ListConnections
+    }
+
+    fn get_process_names() -> HashMap<u32, String> {
+        let mut system = System::new();
+        system.refresh_processes_specifics(
+            ProcessesToUpdate::All,
+            true,
+            ProcessRefreshKind::nothing(),
+        );
+        system
+            .processes()
+            .iter()
+            .map(|(pid, proc)| (pid.as_u32(), proc.name().to_string_lossy().to_string()))
+            .collect()
+    }
+}
+
+impl Tool for ListConnections {
+    type Params = ListConnectionsParams;
+    type Output = ListConnectionsOutput;
+
+    fn name(&self) -> &'static str {
+        "list_connections"
+    }
+
+    fn call(
+        &self,
+        params: ListConnectionsParams,
+        _cancel: Arc<AtomicBool>,
+        _report: Arc<CallReport>,
+    ) -> Result<impl Iterator<Item = ListConnectionsOutput> + 'static, Error> {
+        let proto_flags = match params.protocol {
+            Some(Protocol::Tcp) => ProtocolFlags::TCP,
+            Some(Protocol::Udp) => ProtocolFlags::UDP,
+            None => ProtocolFlags::TCP | ProtocolFlags::UDP,
+        };
+        let sockets_info = FindProcesses::get_sockets(proto_flags)?;
+        let process_names = Self::get_process_names();
+
+        let outputs: Vec<_> = sockets_info
+            .into_iter()
+            .map(|si| {
+                let processes = si
+                    .associated_pids
+                    .iter()
+                    .map(|&pid| ConnectionProcess {
+                        name: process_names.get(&pid).cloned(),
+                        pid,
+                    })
+                    .collect();
+                match si.protocol_socket_info {
+                    ProtocolSocketInfo::Tcp(tcp_si) => ListConnectionsOutput {
+                        local_addr: tcp_si.local_addr,
+                        local_port: tcp_si.local_port,
+                        processes,
+                        protocol: Protocol::Tcp,
+                        remote_addr: Some(tcp_si.remote_addr),
+                        remote_port: Some(tcp_si.remote_port),
+                        state: Some(tcp_si.state.to_string()),
+                    },
+                    ProtocolSocketInfo::Udp(udp_si) => ListConnectionsOutput {
+                        local_addr: udp_si.local_addr,
+                        local_port: udp_si.local_port,
+                        processes,
+                        protocol: Protocol::Udp,
+                        remote_addr: None,
+                        remote_port: None,
+                        state: None,
+                    },
+                }
+            })
+            .filter(|o| {
+                params
+                    .port
+                    .is_none_or(|port| o.local_port == port || o.remote_port == Some(port))
+            })
+            .filter(|o| {
+                params.state.as_ref().is_none_or(|state| {
+                    o.state
+                        .as_ref()
+                        .is_some_and(|s| s.eq_ignore_ascii_case(state))
+                })
+            })
+            .collect();
+
+        Ok(outputs.into_iter())
+    }
+}
@@ -1,37 +1,86 @@
+mod disk_usage;
 mod find_files;
 mod find_processes;
+mod get_env;
+mod http_request;
+mod list_connections;
+mod read_file;
+mod run_command;
+mod system_info;
+mod terminate_processes;
+
+pub use crate::tool::{
+    find_files::FindFilesConfig, http_request::HttpRequestConfig, run_command::RunCommandConfig,
+};
 
 use crate::{
-    tool::{find_files::FindFiles, find_processes::FindProcesses},
+    config::Config,
+    tool::{
+        disk_usage::DiskUsage, find_files::FindFiles, find_processes::FindProcesses,
+        get_env::GetEnv, http_request::HttpRequest, list_connections::ListConnections,
+        read_file::ReadFile, run_command::RunCommand, system_info::SystemInfo,
+        terminate_processes::TerminateProcesses,
+    },
     util::fmt::ErrorChainDisplay,
 };
+use jsonschema::JSONSchema;
 use log::warn;
-use schemars::{schema::RootSchema, schema_for, JsonSchema};
-use serde::{de::DeserializeOwned, Serialize};
+use regex::{Regex, RegexBuilder};
+use schemars::{
+    schema::{InstanceType, RootSchema, SchemaObject},
+    schema_for, JsonSchema,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::value::RawValue;
 use std::{
     collections::HashMap,
     fmt::Debug,
-    sync::{atomic::AtomicBool, Arc},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread::{sleep, spawn},
+    time::{Duration, Instant},
 };
 
 /// A tool error.
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
+    #[error("failed to parse glob")]
+    Globset(
+        #[from]
+        #[source]
+        globset::Error,
+    ),
+    #[error("http request failed")]
+    Http(
+        #[from]
+        #[source]
+        Box<ureq::Error>,
+    ),
     #[error("inconsistent params")]
     InconsistentParams,
+    #[error("invalid param '{0}'")]
+    InvalidParams(String, #[source] serde_json::Error),
     #[error("io error")]
     Io(
         #[from]
         #[source]
         std::io::Error,
     ),
+    #[error("missing param '{0}'")]
+    MissingParam(&'static str),
     #[error("netstat2 error")]
     Netstat2(
         #[from]
         #[source]
         netstat2::error::Error,
     ),
+    #[error("file is not valid utf-8")]
+    NonUtf8,
+    #[error("path '{}' is outside the allowed roots", .0.display())]
+    OutsideRoots(PathBuf),
     #[error("failed to parse regex")]
     Regex(
         #[from]
@@ -50,6 +99,12 @@ pub enum Error {
         #[source]
         shellexpand::path::LookupError<std::env::VarError>,
     ),
+    #[error("unknown param '{0}'")]
+    UnknownParam(String),
+    #[error("unknown signal '{0}'")]
+    UnknownSignal(String),
+    #[error("unknown user '{0}'")]
+    UnknownUser(String),
 }
 
 /// A tool for query processing.
@@ -69,11 +124,34 @@ pub trait Tool {
     }
 
     /// Calls the tool with given parameters getting an output iterator.
+    ///
+    /// Facts about the call which aren't results are recorded to a given report.
     fn call(
         &self,
         params: Self::Params,
         cancel: Arc<AtomicBool>,
+        report: Arc<CallReport>,
     ) -> Result<impl Iterator<Item = Self::Output> + 'static, Error>;
+
+    /// Describes in human language what a call with given parameters would do.
+    fn explain(&self, _params: &Self::Params) -> Option<String> {
+        None
+    }
+
+    /// Names of params rendered by `explain`, the other set ones are listed verbatim.
+    fn explained_params(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Whether the tool may change the system state.
+    fn is_destructive(&self) -> bool {
+        false
+    }
+
+    /// Names of optional params which are nevertheless required in the advertised schema.
+    fn required_params(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
 }
 
 /// A tool metadata.
@@ -81,16 +159,27 @@ pub trait Tool {
 pub struct ToolMeta {
     pub name: String,
     pub description: Option<String>,
+    pub destructive: bool,
     pub params_schema: RootSchema,
-    pub _output_schema: RootSchema,
+    pub output_schema: RootSchema,
 }
 
 impl ToolMeta {
-    /// Strip JSON-schema of the tool parameters.
+    /// Strip JSON-schema of the tool parameters down to an empty object.
+    ///
+    /// The title and description of the schema are kept.
     pub fn strip_params(mut self) -> Self {
-        #[derive(JsonSchema)]
-        struct Empty {}
-        self.params_schema = schema_for!(Empty);
+        let schema = &mut self.params_schema;
+        *schema = RootSchema {
+            meta_schema: schema.meta_schema.take(),
+            schema: SchemaObject {
+                metadata: schema.schema.metadata.take(),
+                instance_type: Some(InstanceType::Object.into()),
+                object: Some(Box::default()),
+                ..Default::default()
+            },
+            definitions: Default::default(),
+        };
         self
     }
 }
@@ -98,10 +187,90 @@ impl ToolMeta {
 /// A boxed iterator for tool output JSONs.
 pub type BoxOutputIter = Box<dyn Iterator<Item = Box<RawValue>> + 'static>;
 
+/// Facts about tool calls which aren't their results, final once the outputs are consumed.
+#[derive(Debug, Default)]
+pub struct CallReport {
+    /// The number of directories which couldn't be read, so the results may be incomplete.
+    pub unreadable_dirs: AtomicU64,
+}
+
+/// Truncates tool outputs after a given number of items logging if anything was dropped.
+fn limit_outputs(outputs: BoxOutputIter, max_outputs: usize) -> BoxOutputIter {
+    let mut outputs = outputs.peekable();
+    let mut num_outputs = 0;
+    Box::new(std::iter::from_fn(move || {
+        if num_outputs < max_outputs {
+            num_outputs += 1;
+            return outputs.next();
+        }
+        if num_outputs == max_outputs && outputs.peek().is_some() {
+            warn!("truncated tool outputs to {max_outputs} items");
+        }
+        // Prevents repeated logging if polled again.
+        num_outputs = max_outputs + 1;
+        None
+    }))
+}
+
+/// Creates a cancel flag for a single tool call set once a timeout elapses.
+///
+/// The timeout is counted from setting the `started` flag. The returned flag also follows
+/// a parent one. The `timed_out` flag is set on timeout. The watchdog thread exits as soon
+/// as the returned flag is dropped by the tool.
+pub fn deadline_cancel(
+    parent: Arc<AtomicBool>,
+    timeout: Duration,
+    started: Arc<AtomicBool>,
+    timed_out: Arc<AtomicBool>,
+) -> Arc<AtomicBool> {
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    let cancel = Arc::new(AtomicBool::new(false));
+    let weak_cancel = Arc::downgrade(&cancel);
+    let mut deadline = None;
+
+    spawn(move || loop {
+        let Some(cancel) = weak_cancel.upgrade() else {
+            return;
+        };
+        if parent.load(Ordering::SeqCst) {
+            cancel.store(true, Ordering::SeqCst);
+            return;
+        }
+        if deadline.is_none() && started.load(Ordering::SeqCst) {
+            deadline = Some(Instant::now() + timeout);
+        }
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            warn!("tool call timed out after {}s", timeout.as_secs_f32());
+            timed_out.store(true, Ordering::SeqCst);
+            cancel.store(true, Ordering::SeqCst);
+            return;
+        }
+        drop(cancel);
+        sleep(POLL_INTERVAL);
+    });
+
+    cancel
+}
+
 /// A tool wrapper for dynamic dispatch.
 pub trait DynTool {
     /// Calls the tool with given parameters getting an output iterator.
-    fn call(&self, params: Box<RawValue>, cancel: Arc<AtomicBool>) -> Result<BoxOutputIter, Error>;
+    fn call(
+        &self,
+        params: Box<RawValue>,
+        cancel: Arc<AtomicBool>,
+        report: Arc<CallReport>,
+    ) -> Result<BoxOutputIter, Error>;
+
+    /// Describes a call with given parameters without performing it.
+    ///
+    /// Set parameters the explanation doesn't render (or all of them if the tool can't
+    /// explain itself) are listed verbatim.
+    fn explain(&self, params: &RawValue) -> Result<String, Error>;
+
+    /// Checks given parameters against the tool schema rejecting unknown ones.
+    fn validate(&self, params: &RawValue) -> Result<(), Error>;
 
     /// Tool metadata.
     fn meta(&self) -> ToolMeta;
@@ -117,9 +286,10 @@ where
         &self,
         params: Box<RawValue>,
         cancel: Arc<AtomicBool>,
+        report: Arc<CallReport>,
     ) -> Result<Box<dyn Iterator<Item = Box<RawValue>>>, Error> {
-        let params = serde_json::from_str(params.get())?;
-        let iter = Tool::call(self, params, cancel)?;
+        let params = parse_params(&params)?;
+        let iter = Tool::call(self, params, cancel, report)?;
         Ok(Box::new(iter.filter_map(|o| {
             match serde_json::to_string(&o).and_then(RawValue::from_string) {
                 Ok(output) => Some(output),
@@ -134,14 +304,132 @@ where
         })))
     }
 
+    fn explain(&self, params: &RawValue) -> Result<String, Error> {
+        let object: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(params.get())?;
+        let typed_params = serde_json::from_str(params.get())?;
+
+        let (mut explanation, explained) = match Tool::explain(self, &typed_params) {
+            Some(explanation) => (explanation, Tool::explained_params(self)),
+            None => (format!("Call {}", Tool::name(self)), &[][..]),
+        };
+        let listed: Vec<_> = object
+            .iter()
+            .filter(|(k, v)| !v.is_null() && !explained.contains(&k.as_str()))
+            .collect();
+
+        if !listed.is_empty() {
+            let listed: Vec<_> = listed.iter().map(|(k, v)| format!("{k}={v}")).collect();
+            explanation += &format!(" with {}", listed.join(", "));
+        }
+        Ok(explanation)
+    }
+
+    fn validate(&self, params: &RawValue) -> Result<(), Error> {
+        let object: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(params.get())?;
+        let known = param_schemas(schema_for!(T::Params));
+        if let Some(name) = object.keys().find(|k| !known.contains_key(*k)) {
+            return Err(Error::UnknownParam(name.clone()));
+        }
+        parse_params::<T::Params>(params)?;
+        Ok(())
+    }
+
     fn meta(&self) -> ToolMeta {
+        let mut params_schema = schema_for!(T::Params);
+        if let Some(object) = &mut params_schema.schema.object {
+            object.required.extend(
+                Tool::required_params(self)
+                    .into_iter()
+                    .map(ToOwned::to_owned),
+            );
+        }
         ToolMeta {
             name: Tool::name(self).to_owned(),
             description: Tool::description(self).map(ToOwned::to_owned),
-            params_schema: schema_for!(T::Params),
-            _output_schema: schema_for!(T::Output),
+            destructive: Tool::is_destructive(self),
+            params_schema,
+            output_schema: schema_for!(T::Output),
+        }
+    }
+}
+
+/// Deserializes tool params reporting a path of an invalid field.
+fn parse_params<T: DeserializeOwned>(params: &RawValue) -> Result<T, Error> {
+    let deserializer = &mut serde_json::Deserializer::from_str(params.get());
+    serde_path_to_error::deserialize(deserializer)
+        .map_err(|err| Error::InvalidParams(err.path().to_string(), err.into_inner()))
+}
+
+/// Canonicalizes given root directories skipping nonexistent ones.
+pub(crate) fn canonical_roots(roots: &[PathBuf]) -> Result<Vec<PathBuf>, Error> {
+    let mut canonical_roots = Vec::with_capacity(roots.len());
+    for root in roots {
+        let root = shellexpand::path::full(root)?;
+        match root.canonicalize() {
+            Ok(root) => canonical_roots.push(root),
+            Err(err) => warn!(
+                "failed to canonicalize root {}: {}",
+                root.display(),
+                ErrorChainDisplay(&err)
+            ),
         }
     }
+    Ok(canonical_roots)
+}
+
+/// Checks that a given path with symlinks resolved is within one of given filesystem roots.
+///
+/// Any path is allowed if there are no roots.
+pub(crate) fn check_filesystem_roots(path: &Path, roots: &[PathBuf]) -> Result<(), Error> {
+    if roots.is_empty() {
+        return Ok(());
+    }
+
+    let canonical = path.canonicalize()?;
+    if canonical_roots(roots)?
+        .iter()
+        .any(|r| canonical.starts_with(r))
+    {
+        return Ok(());
+    }
+    Err(Error::OutsideRoots(path.to_owned()))
+}
+
+/// Gets schemas of top-level params by their names.
+fn param_schemas(schema: RootSchema) -> schemars::Map<String, schemars::schema::Schema> {
+    schema
+        .schema
+        .object
+        .map(|o| o.properties)
+        .unwrap_or_default()
+}
+
+/// Compiles an optional regex, case-insensitive if requested.
+fn build_regex(
+    pattern: Option<&str>,
+    case_insensitive: Option<bool>,
+) -> Result<Option<Regex>, Error> {
+    pattern
+        .map(|p| {
+            RegexBuilder::new(p)
+                .case_insensitive(case_insensitive.unwrap_or_default())
+                .build()
+        })
+        .transpose()
+        .map_err(Into::into)
+}
+
+/// Per-tool configurations.
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+pub struct ToolsConfig {
+    #[serde(default)]
+    pub find_files: FindFilesConfig,
+    #[serde(default)]
+    pub http_request: HttpRequestConfig,
+    #[serde(default)]
+    pub run_command: RunCommandConfig,
 }
 
 /// A mapping from tool names to their corresponding tool instances.
@@ -154,10 +442,352 @@ macro_rules! add_tool {
     };
 }
 
-/// Creates API tools.
-pub fn create_tools() -> DynTools {
+/// Tools which are only created if listed in `enabled_tools`.
+///
+/// The http_request tool can reach internal networks.
+const EXPLICIT_TOOLS: &[&str] = &["http_request"];
+
+/// Creates API tools as configured (all tools with default settings if no config is given).
+pub fn create_tools(config: Option<&Config>) -> DynTools {
+    let tools_config = config.map(|c| c.tools.clone()).unwrap_or_default();
+    // Running programs is opt-in.
+    let has_run_command = !tools_config.run_command.allowed_programs.is_empty();
+
+    let filesystem_roots = config
+        .map(|c| c.filesystem_roots.clone())
+        .unwrap_or_default();
+    let mut tools = all_tools(tools_config, filesystem_roots);
+    if !has_run_command {
+        tools.remove("run_command");
+    }
+    match config.and_then(|c| c.enabled_tools.as_ref()) {
+        Some(enabled_tools) => tools.retain(|name, _| enabled_tools.iter().any(|t| t == name)),
+        None => tools.retain(|name, _| !EXPLICIT_TOOLS.contains(name)),
+    }
+    if config.is_some_and(|c| c.validate_tool_outputs) {
+        tools = tools
+            .into_iter()
+            .map(|(name, tool)| {
+                let tool: Box<dyn DynTool> = Box::new(OutputValidatingTool::new(tool));
+                (name, tool)
+            })
+            .collect();
+    }
+    if let Some(max_outputs) = config.and_then(|c| c.max_outputs) {
+        tools = tools
+            .into_iter()
+            .map(|(name, tool)| {
+                let tool: Box<dyn DynTool> = Box::new(OutputLimitingTool { tool, max_outputs });
+                (name, tool)
+            })
+            .collect();
+    }
+    tools
+}
+
+/// A tool wrapper truncating outputs of each call to a maximum number.
+struct OutputLimitingTool {
+    tool: Box<dyn DynTool>,
+    max_outputs: usize,
+}
+
+impl DynTool for OutputLimitingTool {
+    fn call(
+        &self,
+        params: Box<RawValue>,
+        cancel: Arc<AtomicBool>,
+        report: Arc<CallReport>,
+    ) -> Result<BoxOutputIter, Error> {
+        let outputs = self.tool.call(params, cancel, report)?;
+        Ok(limit_outputs(outputs, self.max_outputs))
+    }
+
+    fn explain(&self, params: &RawValue) -> Result<String, Error> {
+        self.tool.explain(params)
+    }
+
+    fn validate(&self, params: &RawValue) -> Result<(), Error> {
+        self.tool.validate(params)
+    }
+
+    fn meta(&self) -> ToolMeta {
+        self.tool.meta()
+    }
+}
+
+/// A tool wrapper warning about outputs not matching the declared output schema.
+struct OutputValidatingTool {
+    tool: Box<dyn DynTool>,
+    schema: Arc<JSONSchema>,
+}
+
+impl OutputValidatingTool {
+    fn new(tool: Box<dyn DynTool>) -> Self {
+        let schema = serde_json::to_value(tool.meta().output_schema).unwrap();
+        let schema = JSONSchema::compile(&schema).expect("output schema is valid");
+        Self {
+            tool,
+            schema: Arc::new(schema),
+        }
+    }
+}
+
+impl DynTool for OutputValidatingTool {
+    fn call(
+        &self,
+        params: Box<RawValue>,
+        cancel: Arc<AtomicBool>,
+        report: Arc<CallReport>,
+    ) -> Result<BoxOutputIter, Error> {
+        let name = self.tool.meta().name;
+        let schema = self.schema.clone();
+        Ok(Box::new(self.tool.call(params, cancel, report)?.inspect(
+            move |output| {
+                let errors = output_schema_errors(&schema, output);
+                if !errors.is_empty() {
+                    warn!(
+                        "output of {name} doesn't match its schema: {}",
+                        errors.join(", ")
+                    );
+                }
+            },
+        )))
+    }
+
+    fn explain(&self, params: &RawValue) -> Result<String, Error> {
+        self.tool.explain(params)
+    }
+
+    fn validate(&self, params: &RawValue) -> Result<(), Error> {
+        self.tool.validate(params)
+    }
+
+    fn meta(&self) -> ToolMeta {
+        self.tool.meta()
+    }
+}
+
+/// Validates a given output against a schema describing the mismatches.
+fn output_schema_errors(schema: &JSONSchema, output: &RawValue) -> Vec<String> {
+    let output: serde_json::Value = match serde_json::from_str(output.get()) {
+        Ok(output) => output,
+        Err(err) => return vec![err.to_string()],
+    };
+    let errors = match schema.validate(&output) {
+        Ok(()) => Vec::new(),
+        Err(errors) => errors
+            .map(|e| format!("{} at '{}'", e, e.instance_path))
+            .collect(),
+    };
+    errors
+}
+
+/// Lists names of all the tools including ones disabled by default.
+pub fn tool_names() -> Vec<&'static str> {
+    compiled_tools().into_keys().collect()
+}
+
+/// Creates all the compiled-in tools with default settings including ones disabled by default.
+pub fn compiled_tools() -> DynTools {
+    all_tools(ToolsConfig::default(), Vec::new())
+}
+
+fn all_tools(config: ToolsConfig, filesystem_roots: Vec<PathBuf>) -> DynTools {
     let mut tools = DynTools::new();
-    add_tool!(tools, FindFiles::new());
+    add_tool!(tools, DiskUsage::new(filesystem_roots.clone()));
+    add_tool!(
+        tools,
+        FindFiles::new(config.find_files, filesystem_roots.clone())
+    );
     add_tool!(tools, FindProcesses::new());
+    add_tool!(tools, GetEnv::new());
+    add_tool!(tools, HttpRequest::new(config.http_request));
+    add_tool!(tools, ListConnections::new());
+    add_tool!(tools, ReadFile::new(filesystem_roots));
+    add_tool!(tools, RunCommand::new(config.run_command));
+    add_tool!(tools, SystemInfo::new());
+    add_tool!(tools, TerminateProcesses::new());
     tools
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_tools() {
+        assert!(create_tools(None).contains_key("terminate_processes"));
+        assert!(!create_tools(None).contains_key("http_request"));
+
+        let config: Config = toml::from_str(
+            "enabled_tools = [\"find_files\", \"get_env\"]\n\
+            [llm]\napi = \"ollama\"\n",
+        )
+        .unwrap();
+        let mut names: Vec<_> = create_tools(Some(&config)).into_keys().collect();
+        names.sort();
+        assert_eq!(names, ["find_files", "get_env"]);
+
+        let config: Config =
+            toml::from_str("enabled_tools = []\n[llm]\napi = \"ollama\"\n").unwrap();
+        assert!(create_tools(Some(&config)).is_empty());
+
+        // Outputs are limited by the tools themselves.
+        let config: Config = toml::from_str("max_outputs = 1\n[llm]\napi = \"ollama\"\n").unwrap();
+        let params = RawValue::from_string("{}".to_owned()).unwrap();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let outputs =
+            create_tools(Some(&config))["get_env"].call(params, cancel, Default::default());
+        assert_eq!(outputs.unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_compiled_tools() {
+        let tools = compiled_tools();
+        assert!(tools.contains_key("http_request"));
+        assert!(tools["terminate_processes"].meta().destructive);
+        assert!(tools["http_request"].meta().destructive);
+        assert!(!tools["find_files"].meta().destructive);
+    }
+
+    #[test]
+    fn test_output_validating_tool() {
+        let config: Config =
+            toml::from_str("validate_tool_outputs = true\n[llm]\napi = \"ollama\"\n").unwrap();
+        let tool = OutputValidatingTool::new(compiled_tools().remove("get_env").unwrap());
+
+        let params = RawValue::from_string(r#"{"name_regex":"^PATH$"}"#.to_owned()).unwrap();
+        let cancel = Arc::new(AtomicBool::new(false));
+        for output in create_tools(Some(&config))["get_env"]
+            .call(params, cancel, Default::default())
+            .unwrap()
+        {
+            assert!(output_schema_errors(&tool.schema, &output).is_empty());
+        }
+
+        let output = RawValue::from_string(r#"{"name":1}"#.to_owned()).unwrap();
+        assert!(!output_schema_errors(&tool.schema, &output).is_empty());
+    }
+
+    #[test]
+    fn test_strip_params() {
+        let meta = compiled_tools()["find_files"].meta().strip_params();
+        let schema = serde_json::to_value(&meta.params_schema).unwrap();
+        assert_eq!(schema["title"], "FindFilesParams");
+        assert_eq!(schema["type"], "object");
+        for key in ["definitions", "properties", "required"] {
+            assert!(schema.get(key).is_none(), "{key} is left");
+        }
+    }
+
+    #[test]
+    fn test_explain() {
+        let tools = create_tools(None);
+        let explain = |tool: &str, params: &str| {
+            let params = RawValue::from_string(params.to_owned()).unwrap();
+            tools[tool].explain(&params).unwrap()
+        };
+
+        assert_eq!(
+            explain(
+                "find_files",
+                r#"{"in_directory":"/home/me","min_size":104857600,"min_time_modified":"2024-01-01T00:00:00Z","is_directory":false,"colour":"red"}"#
+            ),
+            "Search /home/me (recursively) for files larger than 100 MiB, \
+            modified after 2024-01-01 00:00:00 UTC with colour=\"red\""
+        );
+        assert_eq!(
+            explain(
+                "find_files",
+                r#"{"in_directory":"/tmp","follow_symlinks":true,"name_glob":null}"#
+            ),
+            "Search /tmp (recursively) for entries with follow_symlinks=true"
+        );
+        assert_eq!(
+            explain("get_env", r#"{"name_regex":"^PATH$"}"#),
+            "Call get_env with name_regex=\"^PATH$\""
+        );
+    }
+
+    #[test]
+    fn test_invalid_params() {
+        let tools = create_tools(None);
+        let call = |tool: &str, params: &str| {
+            let params = RawValue::from_string(params.to_owned()).unwrap();
+            let cancel = Arc::new(AtomicBool::new(false));
+            tools[tool]
+                .call(params, cancel, Default::default())
+                .err()
+                .unwrap()
+        };
+
+        let err = call("find_files", r#"{"in_directory": "/", "min_size": "big"}"#);
+        assert!(matches!(&err, Error::InvalidParams(path, _) if path == "min_size"));
+        let err = call("read_file", r#"{"tail": 1}"#);
+        let Error::InvalidParams(_, source) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert!(source.to_string().contains("path"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_filesystem_roots() {
+        use std::fs::{create_dir_all, write};
+
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        create_dir_all(dir.join("allowed")).unwrap();
+        write(dir.join("allowed/a.txt"), "a").unwrap();
+        write(dir.join("secret.txt"), "s").unwrap();
+        std::os::unix::fs::symlink(dir.join("secret.txt"), dir.join("allowed/escape.txt")).unwrap();
+
+        let mut config: Config = toml::from_str("[llm]\napi = \"ollama\"\n").unwrap();
+        config.filesystem_roots = vec![dir.join("allowed")];
+        let tools = create_tools(Some(&config));
+        let call = |tool: &str, params: serde_json::Value| {
+            let params = RawValue::from_string(params.to_string()).unwrap();
+            let cancel = Arc::new(AtomicBool::new(false));
+            tools[tool]
+                .call(params, cancel, Default::default())
+                .map(|outputs| outputs.count())
+        };
+
+        let path = dir.join("allowed/a.txt");
+        assert_eq!(
+            call("read_file", serde_json::json!({"path": path})).unwrap(),
+            1
+        );
+        for path in [dir.join("secret.txt"), dir.join("allowed/escape.txt")] {
+            let err = call("read_file", serde_json::json!({"path": path})).unwrap_err();
+            assert!(matches!(err, Error::OutsideRoots(p) if p == path));
+        }
+
+        let params = serde_json::json!({"in_directory": dir});
+        let err = call("find_files", params.clone()).unwrap_err();
+        assert!(matches!(err, Error::OutsideRoots(ref p) if *p == dir));
+        let err = call("disk_usage", params).unwrap_err();
+        assert!(matches!(err, Error::OutsideRoots(ref p) if *p == dir));
+        let params = serde_json::json!({"in_directory": dir.join("allowed")});
+        assert_eq!(call("find_files", params.clone()).unwrap(), 2);
+        assert_eq!(call("disk_usage", params).unwrap(), 2);
+
+        // Symlink targets outside the roots aren't read to match the content.
+        let params = serde_json::json!({"in_directory": dir.join("allowed"), "content_regex": "."});
+        assert_eq!(call("find_files", params).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_limit_outputs() {
+        let outputs = || -> BoxOutputIter {
+            Box::new((0..5).map(|i| RawValue::from_string(i.to_string()).unwrap()))
+        };
+
+        let limited: Vec<_> = limit_outputs(outputs(), 3)
+            .map(|o| o.get().to_owned())
+            .collect();
+        assert_eq!(limited, ["0", "1", "2"]);
+
+        assert_eq!(limit_outputs(outputs(), 10).count(), 5);
+    }
+}
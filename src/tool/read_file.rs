@@ -0,0 +1,325 @@
+use crate::{
+    tool::{check_filesystem_roots, CallReport, Error, Tool},
+    util::{fmt::ErrorChainDisplay, size::deserialize_size_opt},
+};
+use log::warn;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read, Result as IoResult, Seek, SeekFrom},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// A size of chunks files are scanned in for line boundaries.
+const SCAN_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Parameters specific to the ReadFile tool.
+#[derive(Deserialize, JsonSchema)]
+pub struct ReadFileParams {
+    #[schemars(description = "The last line to read (1-based, inclusive).")]
+    end_line: Option<usize>,
+    #[schemars(description = "Replace invalid UTF-8 sequences instead of refusing to read.")]
+    lossy: Option<bool>,
+    #[schemars(description = "Maximum total size of the returned lines in bytes.")]
+    #[serde(default, deserialize_with = "deserialize_size_opt")]
+    max_bytes: Option<u64>,
+    path: PathBuf,
+    #[schemars(description = "The first line to read (1-based, inclusive).")]
+    start_line: Option<usize>,
+    #[schemars(description = "Return only a given number of the last lines (of the range).")]
+    tail: Option<usize>,
+}
+
+/// An output specific to the ReadFile tool.
+#[derive(Debug, JsonSchema, Serialize)]
+pub struct ReadFileOutput {
+    line: usize,
+    text: String,
+}
+
+/// A tool for reading text file contents line by line.
+pub struct ReadFile {
+    filesystem_roots: Vec<PathBuf>,
+}
+
+impl ReadFile {
+    /// Creates a ReadFile instance restricted to given filesystem roots (if any).
+    pub fn new(filesystem_roots: Vec<PathBuf>) -> Self {
+        ReadFile { filesystem_roots }
+    }
+}
+
+impl Tool for ReadFile {
+    type Params = ReadFileParams;
+    type Output = ReadFileOutput;
+
+    fn name(&self) -> &'static str {
+        "read_file"
+    }
+
+    fn call(
+        &self,
+        params: ReadFileParams,
+        cancel: Arc<AtomicBool>,
+        _report: Arc<CallReport>,
+    ) -> Result<impl Iterator<Item = ReadFileOutput> + 'static, Error> {
+        let start_line = params.start_line.unwrap_or(1);
+        if start_line == 0 || params.end_line.is_some_and(|end| end < start_line) {
+            return Err(Error::InconsistentParams);
+        }
+
+        let path = shellexpand::path::full(&params.path)?;
+        check_filesystem_roots(&path, &self.filesystem_roots)?;
+        let mut reader = BufReader::new(File::open(&path)?);
+        let lossy = params.lossy.unwrap_or_default();
+
+        // Binary files are refused early, invalid lines further on just stop the reading.
+        if !lossy {
+            if let Err(err) = std::str::from_utf8(reader.fill_buf()?) {
+                // An incomplete trailing sequence is continued beyond the buffer.
+                if err.error_len().is_some() {
+                    return Err(Error::NonUtf8);
+                }
+            }
+        }
+
+        // The tail is found by scanning backwards, preceding lines are only counted to number it.
+        let mut line = 0;
+        let mut end_line = params.end_line;
+        if let Some(tail) = params.tail {
+            let file = reader.get_mut();
+            let end = match params.end_line {
+                Some(end_line) => find_lines_end(file, end_line)?,
+                None => file.metadata()?.len(),
+            };
+            let offset = find_tail_offset(file, end, tail)?;
+            line = count_newlines(file, offset)?;
+            reader.seek(SeekFrom::Start(offset))?;
+            if tail == 0 {
+                end_line = Some(0);
+            }
+        }
+
+        Ok(LinesIterator {
+            bytes_left: params.max_bytes.unwrap_or(u64::MAX),
+            cancel,
+            end_line,
+            line,
+            lossy,
+            reader,
+            start_line,
+        })
+    }
+}
+
+/// Finds an offset right after a given number of lines (the file end if there are fewer).
+fn find_lines_end(file: &mut File, num: usize) -> IoResult<u64> {
+    let mut buf = vec![0; SCAN_CHUNK_BYTES];
+    let mut pos = 0;
+    let mut num_newlines = 0;
+    file.seek(SeekFrom::Start(0))?;
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            return Ok(pos);
+        }
+        for (i, _) in buf[..len].iter().enumerate().filter(|(_, b)| **b == b'\n') {
+            num_newlines += 1;
+            if num_newlines == num {
+                return Ok(pos + i as u64 + 1);
+            }
+        }
+        pos += len as u64;
+    }
+}
+
+/// Finds an offset of the first of the last `num` lines ending at a given offset.
+fn find_tail_offset(file: &mut File, end: u64, num: usize) -> IoResult<u64> {
+    if num == 0 {
+        return Ok(end);
+    }
+
+    let mut buf = vec![0; SCAN_CHUNK_BYTES];
+    let mut pos = end;
+    let mut num_newlines = 0;
+    // A newline terminating the last line doesn't start another one.
+    let mut is_last_byte = true;
+    while pos > 0 {
+        let len = pos.min(SCAN_CHUNK_BYTES as u64) as usize;
+        pos -= len as u64;
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut buf[..len])?;
+        for (i, byte) in buf[..len].iter().enumerate().rev() {
+            if *byte == b'\n' && !is_last_byte {
+                num_newlines += 1;
+                if num_newlines == num {
+                    return Ok(pos + i as u64 + 1);
+                }
+            }
+            is_last_byte = false;
+        }
+    }
+    Ok(0)
+}
+
+/// Counts newlines before a given offset.
+fn count_newlines(file: &mut File, end: u64) -> IoResult<usize> {
+    let mut buf = vec![0; SCAN_CHUNK_BYTES];
+    file.seek(SeekFrom::Start(0))?;
+    let mut reader = file.take(end);
+    let mut count = 0;
+    loop {
+        let len = reader.read(&mut buf)?;
+        if len == 0 {
+            return Ok(count);
+        }
+        count += buf[..len].iter().filter(|b| **b == b'\n').count();
+    }
+}
+
+struct LinesIterator {
+    /// The remaining total size of the returned lines.
+    bytes_left: u64,
+    cancel: Arc<AtomicBool>,
+    end_line: Option<usize>,
+    line: usize,
+    lossy: bool,
+    reader: BufReader<File>,
+    start_line: usize,
+}
+
+impl Iterator for LinesIterator {
+    type Item = ReadFileOutput;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut buf = Vec::new();
+        loop {
+            if self.cancel.load(Ordering::SeqCst) {
+                return None;
+            }
+            if self.end_line.is_some_and(|end| self.line >= end) {
+                return None;
+            }
+
+            // Skipped lines aren't buffered, and the others are read up to the byte limit
+            // (allowing for a line terminator).
+            buf.clear();
+            let result = if self.line + 1 < self.start_line {
+                self.reader.skip_until(b'\n')
+            } else {
+                let limit = self.bytes_left.saturating_add(2);
+                (&mut self.reader).take(limit).read_until(b'\n', &mut buf)
+            };
+            match result {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(err) => {
+                    warn!("failed to read line: {}", ErrorChainDisplay(&err));
+                    return None;
+                }
+            }
+            self.line += 1;
+            if self.line < self.start_line {
+                continue;
+            }
+
+            if buf.last() == Some(&b'\n') {
+                buf.pop();
+                if buf.last() == Some(&b'\r') {
+                    buf.pop();
+                }
+            }
+            if buf.len() as u64 > self.bytes_left {
+                self.end_line = Some(self.line);
+                return None;
+            }
+            self.bytes_left -= buf.len() as u64;
+
+            let text = if self.lossy {
+                String::from_utf8_lossy(&buf).into_owned()
+            } else {
+                match String::from_utf8(buf) {
+                    Ok(text) => text,
+                    Err(_) => {
+                        warn!("stopped reading at non-UTF-8 line {}", self.line);
+                        self.end_line = Some(self.line);
+                        return None;
+                    }
+                }
+            };
+
+            return Some(ReadFileOutput {
+                line: self.line,
+                text,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+
+    fn read(path: &std::path::Path, params: serde_json::Value) -> Result<Vec<String>, Error> {
+        let mut params = params;
+        params["path"] = serde_json::json!(path);
+        let params = serde_json::from_value(params).unwrap();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let outputs = ReadFile::new(Vec::new()).call(params, cancel, Default::default())?;
+        Ok(outputs.map(|o| format!("{}:{}", o.line, o.text)).collect())
+    }
+
+    #[test]
+    fn test_read_file() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path();
+        write(path, "one\ntwo\r\nthree\nfour\nfive").unwrap();
+
+        let all = read(path, serde_json::json!({})).unwrap();
+        assert_eq!(all, ["1:one", "2:two", "3:three", "4:four", "5:five"]);
+
+        let range = read(path, serde_json::json!({"start_line": 2, "end_line": 3})).unwrap();
+        assert_eq!(range, ["2:two", "3:three"]);
+
+        let tail = read(path, serde_json::json!({"tail": 2})).unwrap();
+        assert_eq!(tail, ["4:four", "5:five"]);
+        let tail = read(path, serde_json::json!({"tail": 2, "end_line": 3})).unwrap();
+        assert_eq!(tail, ["2:two", "3:three"]);
+        let tail = read(path, serde_json::json!({"tail": 3, "start_line": 4})).unwrap();
+        assert_eq!(tail, ["4:four", "5:five"]);
+        assert!(read(path, serde_json::json!({"tail": 0}))
+            .unwrap()
+            .is_empty());
+
+        let limited = read(path, serde_json::json!({"max_bytes": 7})).unwrap();
+        assert_eq!(limited, ["1:one", "2:two"]);
+
+        write(path, "first\nlast\n").unwrap();
+        let tail = read(path, serde_json::json!({"tail": 1})).unwrap();
+        assert_eq!(tail, ["2:last"]);
+        let tail = read(path, serde_json::json!({"tail": 5})).unwrap();
+        assert_eq!(tail, ["1:first", "2:last"]);
+
+        // A line exceeding the limit stops the reading without being buffered whole.
+        write(path, format!("short\n{}\nnext\n", "x".repeat(100_000))).unwrap();
+        let limited = read(path, serde_json::json!({"max_bytes": 10})).unwrap();
+        assert_eq!(limited, ["1:short"]);
+        let limited = read(path, serde_json::json!({"start_line": 3, "max_bytes": 10})).unwrap();
+        assert_eq!(limited, ["3:next"]);
+
+        let err = read(path, serde_json::json!({"start_line": 3, "end_line": 2})).unwrap_err();
+        assert!(matches!(err, Error::InconsistentParams));
+
+        write(path, b"\xff\xfe binary").unwrap();
+        let err = read(path, serde_json::json!({})).unwrap_err();
+        assert!(matches!(err, Error::NonUtf8));
+        let lossy = read(path, serde_json::json!({"lossy": true})).unwrap();
+        assert_eq!(lossy, ["1:\u{fffd}\u{fffd} binary"]);
+    }
+}
@@ -0,0 +1,220 @@
+use crate::{
+    tool::{CallReport, Error, Tool},
+    util::fmt::ErrorChainDisplay,
+};
+use log::warn;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{BufRead, BufReader},
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver, RecvTimeoutError},
+        Arc,
+    },
+    thread::spawn,
+    time::Duration,
+};
+
+/// A deployment-specific configuration of the RunCommand tool.
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+pub struct RunCommandConfig {
+    /// Names of programs allowed to run (the tool is disabled if empty).
+    #[serde(default)]
+    pub allowed_programs: Vec<String>,
+}
+
+/// Parameters specific to the RunCommand tool.
+#[derive(Deserialize, JsonSchema)]
+pub struct RunCommandParams {
+    #[serde(default)]
+    args: Vec<String>,
+    #[schemars(description = "A program name without a shell, e.g. git or df.")]
+    program: String,
+}
+
+/// An output specific to the RunCommand tool.
+///
+/// Every stdout line is a separate output. A failed command ends with an exit code output
+/// (-1 if the command was terminated by a signal).
+#[derive(Debug, JsonSchema, Serialize)]
+pub struct RunCommandOutput {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exit_code: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<String>,
+}
+
+/// A tool for running allowed programs.
+pub struct RunCommand {
+    config: RunCommandConfig,
+}
+
+impl RunCommand {
+    /// Creates a RunCommand instance.
+    pub fn new(config: RunCommandConfig) -> Self {
+        RunCommand { config }
+    }
+}
+
+impl Tool for RunCommand {
+    type Params = RunCommandParams;
+    type Output = RunCommandOutput;
+
+    fn name(&self) -> &'static str {
+        "run_command"
+    }
+
+    fn description(&self) -> Option<&'static str> {
+        Some("Runs a program with arguments getting its stdout lines.")
+    }
+
+    fn is_destructive(&self) -> bool {
+        true
+    }
+
+    fn call(
+        &self,
+        params: RunCommandParams,
+        cancel: Arc<AtomicBool>,
+        _report: Arc<CallReport>,
+    ) -> Result<impl Iterator<Item = RunCommandOutput> + 'static, Error> {
+        if !self.config.allowed_programs.contains(&params.program) {
+            return Err(Error::InconsistentParams);
+        }
+
+        let mut child = Command::new(&params.program)
+            .args(&params.args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        // Lines are read in a separate thread so that a silent child can still be cancelled.
+        let stdout = child.stdout.take().unwrap();
+        let (sender, receiver) = channel();
+        spawn(move || {
+            let mut reader = BufReader::new(stdout);
+            let mut buf = Vec::new();
+            loop {
+                buf.clear();
+                match reader.read_until(b'\n', &mut buf) {
+                    Ok(0) => return,
+                    Ok(_) => {}
+                    Err(err) => {
+                        warn!("failed to read command output: {}", ErrorChainDisplay(&err));
+                        return;
+                    }
+                }
+                let line = String::from_utf8_lossy(&buf);
+                let line = line.trim_end_matches(['\n', '\r']).to_owned();
+                if sender.send(line).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(RunCommandIterator {
+            cancel,
+            child: Some(child),
+            receiver,
+        })
+    }
+}
+
+struct RunCommandIterator {
+    cancel: Arc<AtomicBool>,
+    child: Option<Child>,
+    receiver: Receiver<String>,
+}
+
+impl RunCommandIterator {
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    fn kill(&mut self) {
+        let Some(mut child) = self.child.take() else {
+            return;
+        };
+        if let Err(err) = child.kill() {
+            warn!("failed to kill command: {}", ErrorChainDisplay(&err));
+        }
+        let _ = child.wait();
+    }
+}
+
+impl Iterator for RunCommandIterator {
+    type Item = RunCommandOutput;
+
+    fn next(&mut self) -> Option<RunCommandOutput> {
+        loop {
+            if self.cancel.load(Ordering::SeqCst) {
+                self.kill();
+                return None;
+            }
+
+            match self.receiver.recv_timeout(Self::POLL_INTERVAL) {
+                Ok(line) => {
+                    return Some(RunCommandOutput {
+                        exit_code: None,
+                        line: Some(line),
+                    })
+                }
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => {}
+            }
+
+            let mut child = self.child.take()?;
+            return match child.wait() {
+                Ok(status) if status.success() => None,
+                Ok(status) => Some(RunCommandOutput {
+                    exit_code: Some(status.code().unwrap_or(-1)),
+                    line: None,
+                }),
+                Err(err) => {
+                    warn!("failed to wait for command: {}", ErrorChainDisplay(&err));
+                    None
+                }
+            };
+        }
+    }
+}
+
+impl Drop for RunCommandIterator {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn call(program: &str, args: &[&str]) -> Result<Vec<String>, Error> {
+        let config = RunCommandConfig {
+            allowed_programs: vec!["sh".to_owned()],
+        };
+        let params = serde_json::from_value(serde_json::json!({
+            "program": program,
+            "args": args,
+        }))
+        .unwrap();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let outputs = RunCommand::new(config).call(params, cancel, Default::default())?;
+        Ok(outputs
+            .map(|o| serde_json::to_string(&o).unwrap())
+            .collect())
+    }
+
+    #[test]
+    fn test_run_command() {
+        let outputs = call("sh", &["-c", "echo one; echo two"]).unwrap();
+        assert_eq!(outputs, [r#"{"line":"one"}"#, r#"{"line":"two"}"#]);
+
+        let outputs = call("sh", &["-c", "echo one; exit 3"]).unwrap();
+        assert_eq!(outputs, [r#"{"line":"one"}"#, r#"{"exit_code":3}"#]);
+
+        let err = call("rm", &["-rf", "/tmp/nothing"]).unwrap_err();
+        assert!(matches!(err, Error::InconsistentParams));
+    }
+}
@@ -0,0 +1,104 @@
+use crate::tool::{CallReport, Error, Tool};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{atomic::AtomicBool, Arc},
+    thread::sleep,
+};
+use sysinfo::System;
+
+/// Parameters specific to the SystemInfo tool.
+#[derive(Deserialize, JsonSchema)]
+pub struct SystemInfoParams {
+    #[schemars(description = "Include per-core CPU usage.")]
+    detailed: Option<bool>,
+}
+
+/// An output specific to the SystemInfo tool.
+#[derive(Debug, JsonSchema, Serialize)]
+pub struct SystemInfoOutput {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    core_cpu_usages: Option<Vec<f32>>,
+    #[schemars(description = "Percentage")]
+    cpu_usage: f32,
+    host_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    load_average: Option<LoadAverage>,
+    os_name: Option<String>,
+    os_version: Option<String>,
+    total_memory: u64,
+    uptime_seconds: u64,
+    used_memory: u64,
+}
+
+/// Load averages over 1, 5 and 15 minutes.
+#[derive(Debug, JsonSchema, Serialize)]
+pub struct LoadAverage {
+    one: f64,
+    five: f64,
+    fifteen: f64,
+}
+
+/// A tool for reporting host-level system metrics.
+pub struct SystemInfo;
+
+impl SystemInfo {
+    /// Creates a SystemInfo instance.
+    pub fn new() -> Self {
+        SystemInfo
+    }
+
+    /// Windows has no load averages (sysinfo reports zeros there).
+    fn get_load_average() -> Option<LoadAverage> {
+        if cfg!(windows) {
+            return None;
+        }
+        let load_avg = System::load_average();
+        Some(LoadAverage {
+            one: load_avg.one,
+            five: load_avg.five,
+            fifteen: load_avg.fifteen,
+        })
+    }
+}
+
+impl Tool for SystemInfo {
+    type Params = SystemInfoParams;
+    type Output = SystemInfoOutput;
+
+    fn name(&self) -> &'static str {
+        "system_info"
+    }
+
+    fn call(
+        &self,
+        params: SystemInfoParams,
+        _cancel: Arc<AtomicBool>,
+        _report: Arc<CallReport>,
+    ) -> Result<impl Iterator<Item = SystemInfoOutput> + 'static, Error> {
+        let mut system = System::new();
+
+        // CPU usage is computed from the difference between two consecutive refreshes.
+        system.refresh_cpu_usage();
+        sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL);
+        system.refresh_cpu_usage();
+        system.refresh_memory();
+
+        let core_cpu_usages = params
+            .detailed
+            .unwrap_or_default()
+            .then(|| system.cpus().iter().map(|cpu| cpu.cpu_usage()).collect());
+
+        Ok(std::iter::once(SystemInfoOutput {
+            core_cpu_usages,
+            cpu_usage: system.global_cpu_usage(),
+            host_name: System::host_name(),
+            load_average: Self::get_load_average(),
+            os_name: System::name(),
+            os_version: System::os_version(),
+            total_memory: system.total_memory(),
+            uptime_seconds: System::uptime(),
+            used_memory: system.used_memory(),
+        }))
+    }
+}
@@ -0,0 +1,129 @@
+use crate::tool::{
+    find_processes::{FindProcesses, FindProcessesParams},
+    CallReport, Error, Tool,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::sync::{atomic::AtomicBool, Arc};
+use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, Signal, System};
+
+/// Parameters specific to the TerminateProcesses tool.
+#[derive(Deserialize, JsonSchema)]
+pub struct TerminateProcessesParams {
+    #[schemars(description = "Only list matching processes. Defaults to true.")]
+    dry_run: Option<bool>,
+    #[serde(flatten)]
+    filter: FindProcessesParams,
+    #[schemars(description = "E.g. SIGTERM (default), SIGKILL, SIGINT, SIGHUP.")]
+    signal: Option<String>,
+}
+
+/// An output specific to the TerminateProcesses tool.
+#[derive(Debug, JsonSchema, Serialize)]
+pub struct TerminateProcessesOutput {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    name: String,
+    pid: u32,
+    signaled: bool,
+}
+
+/// A tool for sending signals to processes running in the system.
+pub struct TerminateProcesses;
+
+impl TerminateProcesses {
+    /// Creates a TerminateProcesses instance.
+    pub fn new() -> Self {
+        TerminateProcesses
+    }
+
+    fn parse_signal(signal: &str) -> Result<Signal, Error> {
+        let name = signal.to_uppercase();
+        let name = name.strip_prefix("SIG").unwrap_or(&name);
+        use Signal::*;
+        Ok(match name {
+            "HUP" => Hangup,
+            "INT" => Interrupt,
+            "QUIT" => Quit,
+            "KILL" => Kill,
+            "USR1" => User1,
+            "USR2" => User2,
+            "TERM" => Term,
+            "CONT" => Continue,
+            "STOP" => Stop,
+            _ => return Err(Error::UnknownSignal(signal.to_owned())),
+        })
+    }
+}
+
+impl Tool for TerminateProcesses {
+    type Params = TerminateProcessesParams;
+    type Output = TerminateProcessesOutput;
+
+    fn name(&self) -> &'static str {
+        "terminate_processes"
+    }
+
+    fn description(&self) -> Option<&'static str> {
+        Some("Sends a signal to matching processes. Nothing is signaled unless dry_run is false.")
+    }
+
+    fn is_destructive(&self) -> bool {
+        true
+    }
+
+    fn call(
+        &self,
+        params: TerminateProcessesParams,
+        cancel: Arc<AtomicBool>,
+        report: Arc<CallReport>,
+    ) -> Result<impl Iterator<Item = TerminateProcessesOutput> + 'static, Error> {
+        let dry_run = params.dry_run.unwrap_or(true);
+        let signal = match &params.signal {
+            Some(signal) => Self::parse_signal(signal)?,
+            None => Signal::Term,
+        };
+
+        let own_pid = std::process::id();
+        let matched: Vec<_> = FindProcesses::new()
+            .call(params.filter, cancel, report)?
+            .filter(|p| p.pid() != own_pid)
+            .collect();
+
+        let pids: Vec<_> = matched.iter().map(|p| Pid::from_u32(p.pid())).collect();
+        let mut system = System::new();
+        system.refresh_processes_specifics(
+            ProcessesToUpdate::Some(&pids),
+            true,
+            ProcessRefreshKind::nothing(),
+        );
+
+        let outputs: Vec<_> = matched
+            .into_iter()
+            .map(|p| {
+                let mut output = TerminateProcessesOutput {
+                    error: None,
+                    name: p.name().to_owned(),
+                    pid: p.pid(),
+                    signaled: false,
+                };
+                if dry_run {
+                    return output;
+                }
+
+                match system
+                    .process(Pid::from_u32(p.pid()))
+                    .map(|p| p.kill_with(signal))
+                {
+                    Some(Some(true)) => output.signaled = true,
+                    Some(Some(false)) => output.error = Some("failed to send signal".to_owned()),
+                    Some(None) => output.error = Some("unsupported signal".to_owned()),
+                    None => output.error = Some("process not found".to_owned()),
+                }
+                output
+            })
+            .collect();
+
+        Ok(outputs.into_iter())
+    }
+}
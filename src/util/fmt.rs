@@ -1,6 +1,6 @@
 use std::{
     error::Error,
-    fmt::{Display, Formatter, Result},
+    fmt::{Debug, Display, Formatter, Result},
 };
 
 /// A wrapper for writing error chains.
@@ -18,10 +18,103 @@ impl<E: Error> Display for ErrorChainDisplay<'_, E> {
     }
 }
 
+/// Replaces all occurrences of given secrets in a string.
+pub fn redact(s: &str, secrets: &[&str]) -> String {
+    let mut s = s.to_owned();
+    for secret in secrets.iter().filter(|s| !s.is_empty()) {
+        s = s.replace(secret, "***");
+    }
+    s
+}
+
+/// An error with secrets redacted from its message chain keeping the original error.
+pub struct RedactedError<E> {
+    inner: E,
+    redacted: String,
+}
+
+impl<E: Error> RedactedError<E> {
+    /// Wraps a given error redacting secrets from its message chain.
+    pub fn new(inner: E, secrets: &[&str]) -> Self {
+        let redacted = redact(&ErrorChainDisplay(&inner).to_string(), secrets);
+        Self { inner, redacted }
+    }
+
+    /// Gets the original error which may contain secrets.
+    pub fn get_ref(&self) -> &E {
+        &self.inner
+    }
+
+    /// Unwraps the original error which may contain secrets.
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+}
+
+impl<E> Debug for RedactedError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        f.debug_tuple("RedactedError")
+            .field(&self.redacted)
+            .finish()
+    }
+}
+
+impl<E> Display for RedactedError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}", self.redacted)
+    }
+}
+
+// The sources are already in the redacted message, exposing them would leak the secrets.
+impl<E> Error for RedactedError<E> {}
+
+/// Formats a byte size using binary units, e.g. 100 MiB or 1.5 KiB.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} bytes");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    let size = format!("{size:.1}");
+    format!("{} {}", size.trim_end_matches(".0"), UNITS[unit])
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(10), "10 bytes");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(100 * 1024 * 1024), "100 MiB");
+    }
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(redact("key=abc&b=abc", &["abc", ""]), "key=***&b=***");
+        assert_eq!(redact("nothing", &[]), "nothing");
+    }
+
+    #[test]
+    fn test_redacted_error() {
+        let err = std::io::Error::other("token abc rejected");
+        let redacted = RedactedError::new(err, &["abc"]);
+        assert_eq!(redacted.to_string(), "token *** rejected");
+        assert_eq!(
+            format!("{redacted:?}"),
+            r#"RedactedError("token *** rejected")"#
+        );
+        assert!(redacted.source().is_none());
+        assert_eq!(redacted.get_ref().to_string(), "token abc rejected");
+    }
+
     #[test]
     fn test_error_chain_display() {
         #[derive(Debug, thiserror::Error)]
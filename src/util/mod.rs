@@ -1 +1,2 @@
 pub mod fmt;
+pub mod size;
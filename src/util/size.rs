@@ -0,0 +1,148 @@
+use serde::{
+    de::{Error, Visitor},
+    Deserializer,
+};
+use std::fmt::{Formatter, Result as FmtResult};
+
+/// Units with their multipliers, decimal ones are powers of 1000 and binary ones of 1024.
+const UNITS: [(&str, u64); 11] = [
+    ("B", 1),
+    ("KB", 1000),
+    ("MB", 1000_u64.pow(2)),
+    ("GB", 1000_u64.pow(3)),
+    ("TB", 1000_u64.pow(4)),
+    ("PB", 1000_u64.pow(5)),
+    ("KIB", 1 << 10),
+    ("MIB", 1 << 20),
+    ("GIB", 1 << 30),
+    ("TIB", 1 << 40),
+    ("PIB", 1 << 50),
+];
+
+/// Parses a human-readable byte size, e.g. "100MB", "1.5 GiB" or "512".
+///
+/// KB, MB, GB, TB and PB are decimal, KiB, MiB, GiB, TiB and PiB are binary. Unit prefixes
+/// are case-insensitive, but the trailing "B" must be uppercase since "b" usually stands for
+/// bits. Bare prefixes like "K" or "M" are rejected as ambiguous.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let unit_start = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(unit_start);
+    let unit = unit.trim_start();
+
+    let multiplier = if unit.is_empty() {
+        1
+    } else {
+        if !unit.ends_with('B') {
+            return None;
+        }
+        let unit = unit.to_ascii_uppercase();
+        UNITS.iter().find(|(u, _)| *u == unit)?.1
+    };
+
+    if let Ok(number) = number.parse::<u64>() {
+        return number.checked_mul(multiplier);
+    }
+    let number: f64 = number.parse().ok()?;
+    let size = (number * multiplier as f64).round();
+    (size.is_finite() && size <= u64::MAX as f64).then_some(size as u64)
+}
+
+/// Deserializes an optional byte size given either as an integer or a human-readable string.
+///
+/// Fields using it also need `#[serde(default)]` to stay optional.
+pub fn deserialize_size_opt<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    deserializer.deserialize_option(OptionSizeVisitor)
+}
+
+struct OptionSizeVisitor;
+
+impl<'de> Visitor<'de> for OptionSizeVisitor {
+    type Value = Option<u64>;
+
+    fn expecting(&self, f: &mut Formatter) -> FmtResult {
+        f.write_str("a byte size")
+    }
+
+    fn visit_none<E: Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(SizeVisitor).map(Some)
+    }
+}
+
+struct SizeVisitor;
+
+impl Visitor<'_> for SizeVisitor {
+    type Value = u64;
+
+    fn expecting(&self, f: &mut Formatter) -> FmtResult {
+        f.write_str("a number of bytes or a size like \"100MB\" or \"2GiB\"")
+    }
+
+    fn visit_u64<E: Error>(self, v: u64) -> Result<u64, E> {
+        Ok(v)
+    }
+
+    fn visit_i64<E: Error>(self, v: i64) -> Result<u64, E> {
+        u64::try_from(v).map_err(|_| E::custom("negative byte size"))
+    }
+
+    fn visit_f64<E: Error>(self, v: f64) -> Result<u64, E> {
+        if v.fract() != 0.0 || v < 0.0 || v > u64::MAX as f64 {
+            return Err(E::custom("byte size must be a non-negative integer"));
+        }
+        Ok(v as u64)
+    }
+
+    fn visit_str<E: Error>(self, v: &str) -> Result<u64, E> {
+        parse_size(v).ok_or_else(|| E::custom(format!("malformed byte size '{v}'")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("100MB"), Some(100_000_000));
+        assert_eq!(parse_size("100 mB"), Some(100_000_000));
+        assert_eq!(parse_size("2GiB"), Some(2 << 30));
+        assert_eq!(parse_size("1.5 KiB"), Some(1536));
+        assert_eq!(parse_size("10 B"), Some(10));
+        assert_eq!(parse_size("100M"), None);
+        assert_eq!(parse_size("100mb"), None);
+        assert_eq!(parse_size("-1KB"), None);
+        assert_eq!(parse_size("MB"), None);
+        assert_eq!(parse_size("99999999999PiB"), None);
+    }
+
+    #[test]
+    fn test_deserialize_size_opt() {
+        #[derive(serde::Deserialize)]
+        struct Params {
+            #[serde(default, deserialize_with = "deserialize_size_opt")]
+            size: Option<u64>,
+        }
+
+        let size = |json: &str| serde_json::from_str::<Params>(json).map(|p| p.size);
+        assert_eq!(size(r#"{"size":1000}"#).unwrap(), Some(1000));
+        assert_eq!(size(r#"{"size":"1KB"}"#).unwrap(), Some(1000));
+        assert_eq!(size(r#"{"size":null}"#).unwrap(), None);
+        assert_eq!(size("{}").unwrap(), None);
+        assert!(size(r#"{"size":-1}"#).is_err());
+        assert!(size(r#"{"size":"1K"}"#).is_err());
+    }
+}
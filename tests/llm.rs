@@ -0,0 +1,448 @@
+use korah::{
+    create_llm_client, create_tools,
+    llm::{Error, LlmConfig, ToolCall},
+    tool::ToolMeta,
+    util::fmt::ErrorChainDisplay,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    sync::mpsc::{channel, Receiver},
+    thread::spawn,
+};
+use tiny_http::{Header, Response, Server};
+
+/// A request received by the fake server.
+struct ReceivedRequest {
+    url: String,
+    headers: HashMap<String, String>,
+    body: Value,
+}
+
+/// Starts a fake HTTP server responding to a single request with a given body.
+fn serve_once(response_body: Value) -> (String, Receiver<ReceivedRequest>) {
+    let server = Server::http("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", server.server_addr().to_ip().unwrap());
+    let (tx, rx) = channel();
+
+    spawn(move || {
+        let mut request = server.recv().unwrap();
+
+        let mut body = String::new();
+        request.as_reader().read_to_string(&mut body).unwrap();
+        let headers = request
+            .headers()
+            .iter()
+            .map(|h| (h.field.to_string().to_lowercase(), h.value.to_string()))
+            .collect();
+        tx.send(ReceivedRequest {
+            url: request.url().to_owned(),
+            headers,
+            body: serde_json::from_str(&body).unwrap(),
+        })
+        .unwrap();
+
+        let header = Header::from_bytes("Content-Type", "application/json").unwrap();
+        let response = Response::from_string(response_body.to_string()).with_header(header);
+        request.respond(response).unwrap();
+    });
+
+    (base_url, rx)
+}
+
+fn tools_meta() -> Vec<ToolMeta> {
+    let mut tools_meta: Vec<_> = create_tools(None).values().map(|t| t.meta()).collect();
+    tools_meta.sort_by(|a, b| a.name.cmp(&b.name));
+    tools_meta
+}
+
+fn derive_tool_call(config: &str, query: &str) -> Option<ToolCall> {
+    let config: LlmConfig = toml::from_str(config).unwrap();
+    let llm = create_llm_client(&config).unwrap();
+    llm.derive_tool_call(tools_meta(), query.to_owned())
+        .unwrap()
+}
+
+fn anthropic_config(base_url: &str) -> String {
+    format!(
+        r#"
+        api = "anthropic"
+        [anthropic]
+        base_url = "{base_url}/v1"
+        key = "secret"
+        model = "claude-3-5-haiku-latest"
+        "#
+    )
+}
+
+fn gemini_config(base_url: &str) -> String {
+    format!(
+        r#"
+        api = "gemini"
+        [gemini]
+        api_key = "secret"
+        base_url = "{base_url}/v1beta"
+        model = "gemini-2.0-flash"
+        "#
+    )
+}
+
+fn ollama_config(base_url: &str) -> String {
+    format!(
+        r#"
+        api = "ollama"
+        [ollama]
+        base_url = "{base_url}/"
+        model = "qwen2.5"
+        headers = {{ "x-org-id" = "42" }}
+        "#
+    )
+}
+
+fn open_ai_config(base_url: &str) -> String {
+    format!(
+        r#"
+        api = "open_ai"
+        [open_ai]
+        base_url = "{base_url}/v1"
+        key = "secret"
+        model = "gpt-4o-mini"
+        "#
+    )
+}
+
+#[test]
+fn test_anthropic_tool_call() {
+    let (base_url, rx) = serve_once(json!({
+        "content": [
+            {"type": "text", "text": "Looking for files."},
+            {"type": "tool_use", "id": "toolu_1", "name": "find_files", "input": {"in_directory": "~"}},
+        ],
+    }));
+
+    let call = derive_tool_call(&anthropic_config(&base_url), "find files").unwrap();
+    assert_eq!(call.tool, "find_files");
+    assert_eq!(call.params.get(), r#"{"in_directory":"~"}"#);
+    assert_eq!(call.reason.as_deref(), Some("Looking for files."));
+
+    let request = rx.recv().unwrap();
+    assert_eq!(request.url, "/v1/messages");
+    assert_eq!(request.headers["x-api-key"], "secret");
+    assert_eq!(request.body["max_tokens"], 1024);
+    assert_eq!(request.body["messages"][0]["role"], "user");
+
+    // Nullable types must be reduced to single instance types.
+    let find_files = &request.body["tools"][1];
+    assert_eq!(find_files["name"], "find_files");
+    let min_size = &find_files["input_schema"]["properties"]["min_size"];
+    assert_eq!(min_size["type"], "integer");
+}
+
+#[test]
+fn test_anthropic_no_tool_use() {
+    let (base_url, _rx) = serve_once(json!({
+        "content": [{"type": "text", "text": "I can't help with that."}],
+    }));
+    assert!(derive_tool_call(&anthropic_config(&base_url), "find files").is_none());
+}
+
+#[test]
+fn test_gemini_tool_call() {
+    let (base_url, rx) = serve_once(json!({
+        "candidates": [{
+            "content": {
+                "role": "model",
+                "parts": [{"functionCall": {"name": "get_env", "args": {}}}],
+            },
+        }],
+    }));
+
+    let call = derive_tool_call(&gemini_config(&base_url), "show env").unwrap();
+    assert_eq!(call.tool, "get_env");
+    assert_eq!(call.params.get(), "{}");
+    assert_eq!(call.reason, None);
+
+    let request = rx.recv().unwrap();
+    assert_eq!(
+        request.url,
+        "/v1beta/models/gemini-2.0-flash:generateContent"
+    );
+    assert_eq!(request.headers["x-goog-api-key"], "secret");
+    assert_eq!(request.body["contents"][0]["parts"][0]["text"], "show env");
+    let declarations = &request.body["tools"][0]["functionDeclarations"];
+    assert_eq!(declarations[1]["name"], "find_files");
+}
+
+#[test]
+fn test_gemini_missing_config() {
+    let config: LlmConfig = toml::from_str(r#"api = "gemini""#).unwrap();
+    assert!(create_llm_client(&config).is_err());
+}
+
+#[test]
+fn test_ollama_tool_call() {
+    let (base_url, rx) = serve_once(json!({
+        "message": {
+            "role": "assistant",
+            "content": "",
+            "tool_calls": [{
+                "function": {"name": "find_files", "arguments": {"in_directory": "~"}},
+            }],
+        },
+    }));
+
+    let call = derive_tool_call(&ollama_config(&base_url), "find files").unwrap();
+    assert_eq!(call.tool, "find_files");
+    assert_eq!(call.params.get(), r#"{"in_directory":"~"}"#);
+    assert_eq!(call.reason, None);
+
+    let request = rx.recv().unwrap();
+    assert_eq!(request.url, "/api/chat");
+    assert_eq!(request.headers["x-org-id"], "42");
+    assert_eq!(request.body["model"], "qwen2.5");
+    assert_eq!(request.body["stream"], false);
+    assert_eq!(request.body["messages"][0]["content"], "find files");
+    assert!(request.body.get("keep_alive").is_none());
+}
+
+#[test]
+fn test_ollama_keep_alive() {
+    let (base_url, rx) = serve_once(json!({"message": {"role": "assistant", "content": ""}}));
+    let config = ollama_config(&base_url) + "keep_alive = -1\n";
+    derive_tool_call(&config, "find files");
+    assert_eq!(rx.recv().unwrap().body["keep_alive"], -1);
+
+    let (base_url, rx) = serve_once(json!({"message": {"role": "assistant", "content": ""}}));
+    let config = ollama_config(&base_url) + "keep_alive = \"5m\"\n";
+    derive_tool_call(&config, "find files");
+    assert_eq!(rx.recv().unwrap().body["keep_alive"], "5m");
+}
+
+#[test]
+fn test_ollama_multiple_tool_calls() {
+    let (base_url, _rx) = serve_once(json!({
+        "message": {
+            "role": "assistant",
+            "content": "",
+            "tool_calls": [
+                {"function": {"name": "find_files", "arguments": {"in_directory": "~"}}},
+                {"function": {"name": "get_env", "arguments": {}}},
+            ],
+        },
+    }));
+
+    assert!(derive_tool_call(&ollama_config(&base_url), "find files").is_none());
+}
+
+#[test]
+fn test_ollama_derive_tool_calls() {
+    let (base_url, _rx) = serve_once(json!({
+        "message": {
+            "role": "assistant",
+            "content": "",
+            "tool_calls": [
+                {"function": {"name": "find_files", "arguments": {"in_directory": "~"}}},
+                {"function": {"name": "find_processes", "arguments": {}}},
+            ],
+        },
+    }));
+
+    let config: LlmConfig = toml::from_str(&ollama_config(&base_url)).unwrap();
+    let llm = create_llm_client(&config).unwrap();
+    let calls = llm
+        .derive_tool_calls(tools_meta(), "find files and processes".to_owned())
+        .unwrap();
+    let names: Vec<_> = calls.iter().map(|c| c.tool.as_str()).collect();
+    assert_eq!(names, ["find_files", "find_processes"]);
+}
+
+#[test]
+fn test_open_ai_tool_call() {
+    let (base_url, rx) = serve_once(json!({
+        "choices": [{
+            "message": {
+                "role": "assistant",
+                "content": "Listing processes.",
+                "tool_calls": [{
+                    "function": {
+                        "name": "find_processes",
+                        "arguments": "{\"name_regex\":\"gram\"}",
+                    },
+                }],
+            },
+        }],
+    }));
+
+    let call = derive_tool_call(&open_ai_config(&base_url), "find processes").unwrap();
+    assert_eq!(call.tool, "find_processes");
+    assert_eq!(call.params.get(), r#"{"name_regex":"gram"}"#);
+    assert_eq!(call.reason.as_deref(), Some("Listing processes."));
+
+    let request = rx.recv().unwrap();
+    assert_eq!(request.url, "/v1/chat/completions");
+    assert_eq!(request.headers["authorization"], "Bearer secret");
+}
+
+#[test]
+fn test_open_ai_malformed_arguments() {
+    let (base_url, _rx) = serve_once(json!({
+        "choices": [{
+            "message": {
+                "role": "assistant",
+                "tool_calls": [{
+                    "function": {"name": "find_processes", "arguments": "{\"name_regex\":"},
+                }],
+            },
+        }],
+    }));
+
+    let config: LlmConfig = toml::from_str(&open_ai_config(&base_url)).unwrap();
+    let llm = create_llm_client(&config).unwrap();
+    let result = llm.derive_tool_calls(tools_meta(), "find processes".to_owned());
+    assert!(matches!(result, Err(Error::MalformedToolCall(_))));
+}
+
+#[test]
+fn test_open_ai_options() {
+    let (base_url, rx) = serve_once(json!({"choices": []}));
+    let config = open_ai_config(&base_url) + "seed = 0\ntemperature = 0.5\n";
+    derive_tool_call(&config, "find files");
+
+    let request = rx.recv().unwrap();
+    assert_eq!(request.body["seed"], 0);
+    assert_eq!(request.body["temperature"], 0.5);
+    assert!(request.body.get("top_p").is_none());
+    assert!(request.body.get("max_tokens").is_none());
+}
+
+#[test]
+fn test_model_override() {
+    for config in [ollama_config, open_ai_config] {
+        let (base_url, rx) = serve_once(json!({"choices": [], "message": {"content": ""}}));
+        let mut config: LlmConfig = toml::from_str(&config(&base_url)).unwrap();
+        config.set_model("llama3.2".to_owned());
+        assert_eq!(config.model(), Some("llama3.2"));
+
+        let llm = create_llm_client(&config).unwrap();
+        let _ = llm.derive_tool_calls(tools_meta(), "find files".to_owned());
+        assert_eq!(rx.recv().unwrap().body["model"], "llama3.2");
+    }
+}
+
+#[test]
+fn test_open_ai_compatible_without_key() {
+    let (base_url, rx) = serve_once(json!({"choices": []}));
+    let config = open_ai_config(&base_url).replace("key = \"secret\"", "")
+        + "headers = { \"x-gateway\" = \"local\" }\n";
+    derive_tool_call(&config, "find files");
+
+    let request = rx.recv().unwrap();
+    assert_eq!(request.headers["x-gateway"], "local");
+    assert!(!request.headers.contains_key("authorization"));
+}
+
+#[test]
+fn test_open_ai_error_redacts_key() {
+    // Some gateways take the key as a query parameter, which ends up in transport errors.
+    let config: LlmConfig = toml::from_str(
+        r#"
+        api = "open_ai"
+        [open_ai]
+        base_url = "http://127.0.0.1:1/v1?api-key=topsecret"
+        key = "topsecret"
+        model = "gpt-4o-mini"
+        max_retries = 0
+        "#,
+    )
+    .unwrap();
+    let llm = create_llm_client(&config).unwrap();
+    let err = llm
+        .derive_tool_calls(tools_meta(), "find files".to_owned())
+        .unwrap_err();
+
+    let message = format!("{} {err:?}", ErrorChainDisplay(&err));
+    assert!(message.contains("127.0.0.1"));
+    assert!(!message.contains("topsecret"));
+}
+
+#[test]
+fn test_ollama_error_redacts_headers() {
+    std::env::set_var("KORAH_TEST_OLLAMA_TOKEN", "topsecret");
+    let config: LlmConfig = toml::from_str(
+        r#"
+        api = "ollama"
+        [ollama]
+        base_url = "http://127.0.0.1:1/?token=topsecret&version=2023-06-01"
+        model = "qwen2.5"
+        headers = { "x-token" = "$KORAH_TEST_OLLAMA_TOKEN", "x-version" = "2023-06-01" }
+        max_retries = 0
+        "#,
+    )
+    .unwrap();
+    let llm = create_llm_client(&config).unwrap();
+    let err = llm
+        .derive_tool_calls(tools_meta(), "find files".to_owned())
+        .unwrap_err();
+
+    let message = format!("{} {err:?}", ErrorChainDisplay(&err));
+    assert!(message.contains("127.0.0.1"));
+    // Only the values expanded from the environment are secrets.
+    assert!(message.contains("2023-06-01"));
+    assert!(!message.contains("topsecret"));
+}
+
+#[test]
+fn test_open_ai_no_choices() {
+    let (base_url, _rx) = serve_once(json!({"choices": []}));
+    assert!(derive_tool_call(&open_ai_config(&base_url), "find processes").is_none());
+}
+
+#[test]
+fn test_request_tools() {
+    let (base_url, rx) = serve_once(json!({"choices": []}));
+    derive_tool_call(&open_ai_config(&base_url), "find files");
+
+    let request = rx.recv().unwrap();
+    let tools = request.body["tools"].as_array().unwrap();
+    let names: Vec<_> = tools.iter().map(|t| &t["function"]["name"]).collect();
+    assert_eq!(
+        names,
+        [
+            "disk_usage",
+            "find_files",
+            "find_processes",
+            "get_env",
+            "list_connections",
+            "read_file",
+            "system_info",
+            "terminate_processes"
+        ]
+    );
+
+    let tool = |name: &str| {
+        tools
+            .iter()
+            .find(|t| t["function"]["name"] == name)
+            .unwrap()
+    };
+
+    let find_files = tool("find_files");
+    assert_eq!(find_files["type"], "function");
+    let params = &find_files["function"]["parameters"];
+    assert_eq!(params["type"], "object");
+    assert_eq!(params["required"], json!(["in_directory"]));
+
+    // Nullable types must be reduced to single instance types.
+    let min_size = &params["properties"]["min_size"];
+    assert_eq!(min_size["type"], "integer");
+    assert_eq!(min_size["description"], "In bytes");
+
+    // References to definitions must be inlined.
+    let sort_by = &tool("find_processes")["function"]["parameters"]["properties"]["sort_by"];
+    assert_eq!(sort_by["type"], "string");
+    assert!(sort_by["enum"]
+        .as_array()
+        .unwrap()
+        .contains(&json!("cpu_usage")));
+    assert!(!request.body.to_string().contains("$ref"));
+}
@@ -0,0 +1,186 @@
+use std::{
+    fs::write,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    process::{Command, Stdio},
+};
+use tempfile::tempdir;
+
+/// Creates a command running korah with the sample config.
+fn korah() -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_korah"));
+    command.args([
+        "--config-path",
+        concat!(env!("CARGO_MANIFEST_DIR"), "/korah.toml"),
+    ]);
+    command
+}
+
+#[test]
+fn test_ndjson_output() {
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        write(dir.join(name), name).unwrap();
+    }
+
+    let call = serde_json::json!({
+        "tool": "find_files",
+        "params": {"in_directory": dir},
+    });
+    let mut child = korah()
+        .args(["--output", "ndjson"])
+        .arg(call.to_string())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let mut names = Vec::new();
+    for line in BufReader::new(child.stdout.take().unwrap()).lines() {
+        let output: serde_json::Value = serde_json::from_str(&line.unwrap()).unwrap();
+        let path = Path::new(output["path"].as_str().unwrap());
+        names.push(path.file_name().unwrap().to_str().unwrap().to_owned());
+    }
+    names.sort();
+
+    assert!(child.wait().unwrap().success());
+    assert_eq!(names, ["a.txt", "b.txt", "c.txt"]);
+}
+
+#[test]
+fn test_json_output() {
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    for name in ["a.txt", "b.txt"] {
+        write(dir.join(name), name).unwrap();
+    }
+
+    let call = serde_json::json!({
+        "tool": "find_files",
+        "params": {"in_directory": dir},
+    });
+    let output = korah()
+        .args(["--format", "json"])
+        .arg(call.to_string())
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let outputs: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(outputs.len(), 2);
+}
+
+#[test]
+fn test_timeout() {
+    let call = serde_json::json!({
+        "tool": "find_files",
+        "params": {"in_directory": "/"},
+    });
+    let output = korah()
+        .args(["--timeout", "0"])
+        .arg(call.to_string())
+        .stdout(Stdio::null())
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(124));
+}
+
+#[test]
+fn test_query_from_stdin() {
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    write(dir.join("a.txt"), "a").unwrap();
+
+    let call = serde_json::json!({
+        "tool": "find_files",
+        "params": {"in_directory": dir},
+    });
+    let mut child = korah()
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(call.to_string().as_bytes())
+        .unwrap();
+
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let output: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(output["path"].as_str().unwrap().ends_with("a.txt"));
+}
+
+#[test]
+fn test_list_tools() {
+    let output = Command::new(env!("CARGO_BIN_EXE_korah"))
+        .args(["--list-tools", "--format", "json"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let tools: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout).unwrap();
+    let names: Vec<_> = tools.iter().map(|t| t["name"].as_str().unwrap()).collect();
+    assert!(names.contains(&"find_files"));
+    assert!(tools.iter().all(|t| t.get("description").is_some()));
+}
+
+#[test]
+fn test_dump_schema() {
+    let output = Command::new(env!("CARGO_BIN_EXE_korah"))
+        .args(["--dump-schema", "find_files"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let schema: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(schema["properties"]["in_directory"].is_object());
+
+    let output = Command::new(env!("CARGO_BIN_EXE_korah"))
+        .args(["--dump-schema", "foo"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_print0_output() {
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    write(dir.join("a\nb.txt"), "a").unwrap();
+
+    let call = serde_json::json!({
+        "tool": "find_files",
+        "params": {"in_directory": dir},
+    });
+    let output = korah()
+        .arg("--print0")
+        .arg(call.to_string())
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let expected = format!("{}\0", dir.join("a\nb.txt").display());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+}
+
+#[test]
+fn test_tool_timeout() {
+    let call = serde_json::json!({
+        "tool": "find_files",
+        "params": {"in_directory": "/"},
+    });
+    let output = korah()
+        .arg(call.to_string())
+        .env("KORAH_TOOL_TIMEOUT_SECS", "0")
+        .stdout(Stdio::null())
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("tool call timed out"));
+}